@@ -0,0 +1,63 @@
+// Text forms of a coordinate for copying and sharing a point
+
+// Adding 0.0 turns -0.0 into 0.0, so a point on the equator or prime meridian has no minus sign
+fn unsigned_zero(value: f64) -> f64 {
+    value + 0.0
+}
+
+// "51.507400, -0.127800", latitude first
+pub fn to_decimal(lat: f64, lng: f64) -> String {
+    format!("{:.6}, {:.6}", unsigned_zero(lat), unsigned_zero(lng))
+}
+
+// Degrees, minutes and seconds to a tenth of a second: 51°30'26.6"N 0°07'40.1"W.
+// Zero counts as north and east.
+pub fn to_dms(lat: f64, lng: f64) -> String {
+    format!(
+        "{} {}",
+        dms_component(lat, if lat < 0.0 { 'S' } else { 'N' }),
+        dms_component(lng, if lng < 0.0 { 'W' } else { 'E' })
+    )
+}
+
+fn dms_component(value: f64, hemisphere: char) -> String {
+    // Rounding whole tenths of a second carries 59.96" over into the next minute
+    let tenths = (value.abs() * 36_000.0).round() as u64;
+    let (degrees, minutes, tenths_of_seconds) = (tenths / 36_000, tenths % 36_000 / 600, tenths % 600);
+    format!(
+        "{}°{:02}'{:02}.{}\"{}",
+        degrees,
+        minutes,
+        tenths_of_seconds / 10,
+        tenths_of_seconds % 10,
+        hemisphere
+    )
+}
+
+// RFC 5870 geo URI, which maps and phone apps open: geo:51.507400,-0.127800
+pub fn to_geo_uri(lat: f64, lng: f64) -> String {
+    format!("geo:{:.6},{:.6}", unsigned_zero(lat), unsigned_zero(lng))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_dms() {
+        assert_eq!(to_dms(51.5074, -0.1278), "51°30'26.6\"N 0°07'40.1\"W");
+        assert_eq!(to_dms(-33.8688, 151.2093), "33°52'07.7\"S 151°12'33.5\"E");
+        assert_eq!(to_dms(0.0, 0.0), "0°00'00.0\"N 0°00'00.0\"E");
+        assert_eq!(to_dms(-0.0, -0.0), "0°00'00.0\"N 0°00'00.0\"E");
+
+        // 59.99" rounds up into the next minute and degree
+        assert_eq!(to_dms(10.999_997, -20.0), "11°00'00.0\"N 20°00'00.0\"W");
+    }
+
+    #[test]
+    fn test_decimal_and_geo_uri() {
+        assert_eq!(to_decimal(51.5074, -0.1278), "51.507400, -0.127800");
+        assert_eq!(to_geo_uri(51.5074, -0.1278), "geo:51.507400,-0.127800");
+        assert_eq!(to_geo_uri(-0.0, 0.0), "geo:0.000000,0.000000");
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::geolocation::Location;
+use crate::settings::config_file;
+
+const FAVORITES_FILE: &str = "favorites.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Favorite {
+    pub name: String,
+    pub location: Location,
+}
+
+pub struct FavoritesStore {
+    path: PathBuf,
+    favorites: Vec<Favorite>,
+}
+
+impl FavoritesStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            favorites: Vec::new(),
+        }
+    }
+
+    // Loads favorites from the given file; a missing file is an empty store
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let favorites = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self { path, favorites })
+    }
+
+    // Loads from the XDG config directory, starting empty if that fails
+    pub fn load_default() -> Self {
+        let path = config_file(FAVORITES_FILE);
+
+        match Self::load(path.clone()) {
+            Ok(store) => store,
+            Err(e) => {
+                eprintln!("⚠️ Could not load favorites from {}: {}", path.display(), e);
+                Self::new(path)
+            }
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let json = serde_json::to_string_pretty(&self.favorites)?;
+        fs::write(&self.path, json)
+    }
+
+    // Adds a favorite, replacing any existing one with the same name
+    pub fn add(&mut self, favorite: Favorite) {
+        self.favorites.retain(|f| f.name != favorite.name);
+        self.favorites.push(favorite);
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.favorites.len();
+        self.favorites.retain(|f| f.name != name);
+        self.favorites.len() != before
+    }
+
+    pub fn list(&self) -> &[Favorite] {
+        &self.favorites
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("map-rs-test-{}-{}", std::process::id(), name))
+            .join(FAVORITES_FILE)
+    }
+
+    #[test]
+    fn test_add_and_remove() {
+        let mut store = FavoritesStore::new(temp_path("add-remove"));
+        store.add(Favorite { name: "Home".to_string(), location: Location::new(51.5, -0.1) });
+        store.add(Favorite { name: "Work".to_string(), location: Location::new(51.6, -0.2) });
+        store.add(Favorite { name: "Home".to_string(), location: Location::new(51.7, -0.3) });
+
+        assert_eq!(store.list().len(), 2);
+        assert_eq!(store.list()[1].location.latitude, 51.7);
+
+        assert!(store.remove("Work"));
+        assert!(!store.remove("Work"));
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let path = temp_path("round-trip");
+
+        let mut store = FavoritesStore::new(path.clone());
+        store.add(Favorite { name: "Big Ben".to_string(), location: Location::new(51.4994, -0.1245) });
+        store.save().unwrap();
+
+        let loaded = FavoritesStore::load(path.clone()).unwrap();
+        assert_eq!(loaded.list().len(), 1);
+        assert_eq!(loaded.list()[0].name, "Big Ben");
+        assert_eq!(loaded.list()[0].location.longitude, -0.1245);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_and_corrupt_files() {
+        let path = temp_path("missing");
+        assert!(FavoritesStore::load(path.clone()).unwrap().list().is_empty());
+
+        let path = temp_path("corrupt");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "not json").unwrap();
+        assert!(FavoritesStore::load(path.clone()).is_err());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,4 +1,22 @@
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+use zbus::zvariant::OwnedObjectPath;
+use crate::routing::{DEFAULT_REQUEST_TIMEOUT, DEFAULT_USER_AGENT};
+
+// Desktop file id GeoClue uses to apply per-application location permissions
+const GEOCLUE_DESKTOP_ID: &str = "map-rs";
+const GEOCLUE_ACCURACY_EXACT: u32 = 8;
+const GEOCLUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const IP_LOOKUP_URL: &str = "https://ipapi.co/json/";
+// IP geolocation only resolves to roughly city level
+const IP_LOOKUP_ACCURACY_METERS: f64 = 5000.0;
+
+// Number of recent locations kept unless a capacity is given
+pub const DEFAULT_MAX_HISTORY: usize = 100;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Location {
@@ -6,9 +24,12 @@ pub struct Location {
     pub longitude: f64,
     pub accuracy: Option<f64>,
     pub timestamp: Option<u64>,
+    // Meters above sea level, when the source reports it (GPX <ele>)
+    #[serde(default)]
+    pub elevation: Option<f64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LocationUpdate {
     pub lat: f64,
     pub lng: f64,
@@ -31,6 +52,7 @@ impl Location {
             latitude,
             longitude,
             accuracy: None,
+            elevation: None,
             timestamp: Some(std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
@@ -43,6 +65,9 @@ impl Location {
         self
     }
 
+    // Great-circle (haversine) distance in meters. The longitude difference only enters
+    // through sin², which repeats every 360°, so points either side of the antimeridian
+    // (179.9 and -179.9) come out ~22 km apart at the equator, not ~40,000 km.
     pub fn distance_to(&self, other: &Location) -> f64 {
         let lat1 = self.latitude.to_radians();
         let lat2 = other.latitude.to_radians();
@@ -55,18 +80,115 @@ impl Location {
         
         6371000.0 * c // Earth radius in meters
     }
+
+    // Initial great-circle bearing towards `other`, in degrees clockwise from north (0–360)
+    pub fn bearing_to(&self, other: &Location) -> f64 {
+        let lat1 = self.latitude.to_radians();
+        let lat2 = other.latitude.to_radians();
+        let delta_lon = (other.longitude - self.longitude).to_radians();
+
+        let y = delta_lon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * delta_lon.cos();
+        y.atan2(x).to_degrees().rem_euclid(360.0)
+    }
+}
+
+// Brings a longitude into [-180, 180), so 190 becomes -170. Values already in range are
+// returned as they are, without rounding error.
+pub fn wrap_longitude(longitude: f64) -> f64 {
+    if (-180.0..180.0).contains(&longitude) {
+        longitude
+    } else {
+        (longitude + 180.0).rem_euclid(360.0) - 180.0
+    }
+}
+
+// Returns the (southwest, northeast) corners of the smallest box containing all locations.
+// When the box crosses the antimeridian the southwest longitude is greater than the
+// northeast one, e.g. (-18, 177) to (-13, -171) around Fiji and Samoa.
+pub fn bounding_box(locations: &[Location]) -> Option<(Location, Location)> {
+    let first = locations.first()?;
+    
+    let mut south = first.latitude;
+    let mut north = first.latitude;
+    for location in locations {
+        south = south.min(location.latitude);
+        north = north.max(location.latitude);
+    }
+    
+    // The longitude span is the circle minus its largest gap between neighbouring points
+    let mut longitudes: Vec<f64> = locations.iter().map(|l| l.longitude).collect();
+    longitudes.sort_by(|a, b| a.total_cmp(b));
+    
+    let mut west = longitudes[0];
+    let mut east = longitudes[longitudes.len() - 1];
+    let mut largest_gap = west + 360.0 - east; // gap wrapping across the antimeridian
+    
+    for pair in longitudes.windows(2) {
+        let gap = pair[1] - pair[0];
+        if gap > largest_gap {
+            largest_gap = gap;
+            west = pair[1];
+            east = pair[0];
+        }
+    }
+    
+    Some((Location::new(south, west), Location::new(north, east)))
 }
 
 pub struct GeolocationService {
     current_location: Option<Location>,
     location_history: Vec<Location>,
+    max_history: usize, // older locations are dropped beyond this many
+    replay: VecDeque<Location>, // preloaded fixes that step() feeds in, oldest first
 }
 
 impl GeolocationService {
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_MAX_HISTORY)
+    }
+
+    pub fn with_capacity(max_history: usize) -> Self {
         Self {
             current_location: None,
             location_history: Vec::new(),
+            max_history,
+            replay: VecDeque::new(),
+        }
+    }
+
+    // A service that replays a fixed sequence of fixes, one per step() call. Mainly for
+    // driving follow mode and the distance and speed figures in tests, but also handy
+    // for demos without a GPS.
+    pub fn from_locations(locations: Vec<Location>) -> Self {
+        Self {
+            replay: locations.into(),
+            ..Self::new()
+        }
+    }
+
+    // Feeds the next preloaded fix in as if it had come from update_location and returns
+    // it, or None once the sequence is used up
+    pub fn step(&mut self) -> Option<&Location> {
+        let location = self.replay.pop_front()?;
+        self.update_location(location);
+        self.get_current_location()
+    }
+
+    pub fn max_history(&self) -> usize {
+        self.max_history
+    }
+
+    // Changes the capacity, dropping the oldest locations if the history is now too long
+    pub fn set_max_history(&mut self, max_history: usize) {
+        self.max_history = max_history;
+        self.trim_history();
+    }
+
+    fn trim_history(&mut self) {
+        if self.location_history.len() > self.max_history {
+            let excess = self.location_history.len() - self.max_history;
+            self.location_history.drain(..excess);
         }
     }
 
@@ -74,10 +196,15 @@ impl GeolocationService {
         self.location_history.push(location.clone());
         self.current_location = Some(location);
         
-        // Keep only last 100 locations to manage memory
-        if self.location_history.len() > 100 {
-            self.location_history.remove(0);
-        }
+        self.trim_history();
+    }
+
+    // Records the outcome of a location detection. A failed detection records nothing, so the
+    // history, speed and heading only ever follow real fixes; what to show instead is the caller's choice.
+    pub fn record_detection<E>(&mut self, detected: Result<Location, E>) -> Result<Location, E> {
+        let location = detected?;
+        self.update_location(location.clone());
+        Ok(location)
     }
 
     pub fn get_current_location(&self) -> Option<&Location> {
@@ -87,6 +214,441 @@ impl GeolocationService {
     pub fn get_location_history(&self) -> &[Location] {
         &self.location_history
     }
+
+    // Sum of the distances between consecutive history points, in meters
+    pub fn total_distance(&self) -> f64 {
+        self.location_history
+            .windows(2)
+            .map(|pair| pair[0].distance_to(&pair[1]))
+            .sum()
+    }
+
+    // Average speed in m/s over the timestamped part of the history
+    pub fn average_speed(&self) -> Option<f64> {
+        let timed: Vec<&Location> = self.location_history
+            .iter()
+            .filter(|location| location.timestamp.is_some())
+            .collect();
+        
+        if timed.len() < 2 {
+            return None;
+        }
+        
+        let start = timed[0].timestamp?;
+        let end = timed[timed.len() - 1].timestamp?;
+        let elapsed = end.checked_sub(start).filter(|secs| *secs > 0)?;
+        
+        let distance: f64 = timed
+            .windows(2)
+            .map(|pair| pair[0].distance_to(pair[1]))
+            .sum();
+        
+        Some(distance / elapsed as f64)
+    }
+
+    // Speed in m/s between successive timestamped points, exponentially smoothed so one
+    // jumpy fix doesn't swing the result. `alpha` (0–1) is the weight of the newest speed:
+    // near 1 follows changes quickly, near 0 stays steady. Pairs with no time between
+    // them are skipped; None until two timestamped points are at least a second apart.
+    pub fn smoothed_speed(&self, alpha: f64) -> Option<f64> {
+        let alpha = alpha.clamp(0.0, 1.0);
+        let timed: Vec<(&Location, u64)> = self.location_history
+            .iter()
+            .filter_map(|location| Some((location, location.timestamp?)))
+            .collect();
+        
+        let mut smoothed: Option<f64> = None;
+        for pair in timed.windows(2) {
+            let ((from, start), (to, end)) = (pair[0], pair[1]);
+            let Some(elapsed) = end.checked_sub(start).filter(|secs| *secs > 0) else {
+                continue;
+            };
+            let speed = from.distance_to(to) / elapsed as f64;
+            smoothed = Some(match smoothed {
+                Some(previous) => alpha * speed + (1.0 - alpha) * previous,
+                None => speed,
+            });
+        }
+        smoothed
+    }
+
+    // Direction of travel in degrees from north, from the bearings between successive
+    // history points at least `min_distance` meters apart (closer ones are GPS jitter while
+    // standing still). Bearings are smoothed like smoothed_speed, as unit vectors so 350°
+    // and 10° average to 0° rather than 180°. None until the position has moved.
+    pub fn heading(&self, alpha: f64, min_distance: f64) -> Option<f64> {
+        let alpha = alpha.clamp(0.0, 1.0);
+        let mut from = self.location_history.first()?;
+        let mut smoothed: Option<(f64, f64)> = None;
+        for to in &self.location_history[1..] {
+            if from.distance_to(to) < min_distance {
+                continue;
+            }
+            let bearing = from.bearing_to(to).to_radians();
+            let (x, y) = (bearing.sin(), bearing.cos());
+            smoothed = Some(match smoothed {
+                Some((sx, sy)) => (alpha * x + (1.0 - alpha) * sx, alpha * y + (1.0 - alpha) * sy),
+                None => (x, y),
+            });
+            from = to;
+        }
+        smoothed.map(|(x, y)| x.atan2(y).to_degrees().rem_euclid(360.0))
+    }
+
+    // Drops history points closer than `min_distance` meters to the last point kept, which
+    // removes duplicate fixes taken while standing still. The first and last points always stay.
+    pub fn smooth_history(&mut self, min_distance: f64) {
+        if self.location_history.len() <= 2 {
+            return;
+        }
+        
+        let mut points = std::mem::take(&mut self.location_history).into_iter();
+        let first = points.next().unwrap();
+        let last = points.next_back().unwrap();
+        let mut kept = vec![first];
+        for point in points {
+            if kept[kept.len() - 1].distance_to(&point) >= min_distance {
+                kept.push(point);
+            }
+        }
+        // The last point replaces a kept point it is too close to, unless that is the first
+        if kept.len() > 1 && kept[kept.len() - 1].distance_to(&last) < min_distance {
+            kept.pop();
+        }
+        kept.push(last);
+        self.location_history = kept;
+    }
+
+    // Replaces each history point's position with the mean of the `window` points centered
+    // on it, evening out GPS jitter. The first and last points keep their positions, and
+    // windows are narrowed near the ends. A window of 1 or less changes nothing.
+    pub fn average_history(&mut self, window: usize) {
+        let half = window / 2;
+        if half == 0 || self.location_history.len() <= 2 {
+            return;
+        }
+        
+        let original = self.location_history.clone();
+        let last = original.len() - 1;
+        for (i, point) in self.location_history.iter_mut().enumerate().take(last).skip(1) {
+            let reach = half.min(i).min(last - i);
+            let neighbors = &original[i - reach..=i + reach];
+            point.latitude = neighbors.iter().map(|p| p.latitude).sum::<f64>() / neighbors.len() as f64;
+            // Averaged as offsets from this point, so a track crossing the antimeridian
+            // doesn't average 179.9 and -179.9 to 0
+            let center = original[i].longitude;
+            let offset = neighbors.iter().map(|p| wrap_longitude(p.longitude - center)).sum::<f64>() / neighbors.len() as f64;
+            point.longitude = wrap_longitude(center + offset);
+        }
+    }
+
+    // Serializes the location history as a JSON array, keeping accuracy and timestamps
+    pub fn export_history(&self) -> String {
+        serde_json::to_string(&self.location_history).unwrap_or_else(|_| "[]".to_string())
+    }
+
+    // Replaces the history with one saved by export_history. Only the most recent
+    // entries up to the history cap are kept; on error the history is left untouched.
+    pub fn import_history(&mut self, json: &str) -> Result<(), serde_json::Error> {
+        let history: Vec<Location> = serde_json::from_str(json)?;
+        
+        self.current_location = history.last().cloned();
+        self.location_history = history;
+        self.trim_history();
+        Ok(())
+    }
+
+    // Replays the <trkpt> elements of a GPX document into the location history
+    pub fn import_gpx(&mut self, gpx: &str) -> Result<usize, GpxError> {
+        let locations = parse_gpx_track(gpx)?;
+        let count = locations.len();
+        
+        for location in locations {
+            self.update_location(location);
+        }
+        
+        Ok(count)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoError {
+    GeoClue(String),
+    IpLookup(String),
+}
+
+impl fmt::Display for GeoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoError::GeoClue(reason) => write!(f, "GeoClue location unavailable: {}", reason),
+            GeoError::IpLookup(reason) => write!(f, "IP geolocation failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for GeoError {}
+
+impl From<zbus::Error> for GeoError {
+    fn from(error: zbus::Error) -> Self {
+        GeoError::GeoClue(error.to_string())
+    }
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.GeoClue2.Manager",
+    default_service = "org.freedesktop.GeoClue2",
+    default_path = "/org/freedesktop/GeoClue2/Manager"
+)]
+trait GeoClueManager {
+    fn get_client(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(interface = "org.freedesktop.GeoClue2.Client", default_service = "org.freedesktop.GeoClue2")]
+trait GeoClueClient {
+    fn start(&self) -> zbus::Result<()>;
+
+    fn stop(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn set_desktop_id(&self, id: &str) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn set_requested_accuracy_level(&self, level: u32) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn location_updated(&self, old: OwnedObjectPath, new: OwnedObjectPath) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(interface = "org.freedesktop.GeoClue2.Location", default_service = "org.freedesktop.GeoClue2")]
+trait GeoClueLocation {
+    #[zbus(property)]
+    fn latitude(&self) -> zbus::Result<f64>;
+
+    #[zbus(property)]
+    fn longitude(&self) -> zbus::Result<f64>;
+
+    #[zbus(property)]
+    fn accuracy(&self) -> zbus::Result<f64>;
+}
+
+// Detects the device location through GeoClue2, falling back to an IP-based lookup.
+// This is a free function so callers don't need to hold the service lock while it runs.
+pub async fn detect_current() -> Result<Location, GeoError> {
+    match detect_with_geoclue().await {
+        Ok(location) => Ok(location),
+        Err(e) => {
+            eprintln!("⚠️ {}, trying IP geolocation", e);
+            detect_with_ip_lookup().await
+        }
+    }
+}
+
+async fn detect_with_geoclue() -> Result<Location, GeoError> {
+    let connection = zbus::Connection::system().await?;
+    let manager = GeoClueManagerProxy::new(&connection).await?;
+    let client_path = manager.get_client().await?;
+    let client = GeoClueClientProxy::builder(&connection)
+        .path(client_path)?
+        .build()
+        .await?;
+    
+    client.set_desktop_id(GEOCLUE_DESKTOP_ID).await?;
+    client.set_requested_accuracy_level(GEOCLUE_ACCURACY_EXACT).await?;
+    
+    // Subscribe before starting so the first fix isn't missed
+    let mut updates = client.receive_location_updated().await?;
+    client.start().await?;
+    
+    let update = tokio::time::timeout(GEOCLUE_TIMEOUT, updates.next()).await;
+    let _ = client.stop().await;
+    
+    let update = update
+        .map_err(|_| GeoError::GeoClue("timed out waiting for a location fix".to_string()))?
+        .ok_or_else(|| GeoError::GeoClue("location updates ended unexpectedly".to_string()))?;
+    
+    let args = update.args()?;
+    let location = GeoClueLocationProxy::builder(&connection)
+        .path(args.new.clone())?
+        .build()
+        .await?;
+    
+    Ok(Location::new(location.latitude().await?, location.longitude().await?)
+        .with_accuracy(location.accuracy().await?))
+}
+
+async fn detect_with_ip_lookup() -> Result<Location, GeoError> {
+    #[derive(Deserialize)]
+    struct IpLookupResponse {
+        latitude: f64,
+        longitude: f64,
+    }
+    
+    // Same limit as routing requests, so a hung lookup can't stall detection
+    let client = reqwest::Client::builder()
+        .connect_timeout(DEFAULT_REQUEST_TIMEOUT)
+        .timeout(DEFAULT_REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| GeoError::IpLookup(e.to_string()))?;
+    let response = client
+        .get(IP_LOOKUP_URL)
+        .header("User-Agent", DEFAULT_USER_AGENT)
+        .send()
+        .await
+        .map_err(|e| GeoError::IpLookup(e.to_string()))?;
+    
+    if !response.status().is_success() {
+        return Err(GeoError::IpLookup(format!("lookup service returned {}", response.status())));
+    }
+    
+    let result: IpLookupResponse = response
+        .json()
+        .await
+        .map_err(|e| GeoError::IpLookup(e.to_string()))?;
+    
+    Ok(Location::new(result.latitude, result.longitude).with_accuracy(IP_LOOKUP_ACCURACY_METERS))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GpxError {
+    NoTrackPoints,
+    MissingAttribute(&'static str),
+    InvalidCoordinate(String),
+}
+
+impl fmt::Display for GpxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GpxError::NoTrackPoints => write!(f, "GPX file contains no track points"),
+            GpxError::MissingAttribute(name) => write!(f, "Track point is missing the '{}' attribute", name),
+            GpxError::InvalidCoordinate(value) => write!(f, "Invalid track point coordinate: {}", value),
+        }
+    }
+}
+
+impl std::error::Error for GpxError {}
+
+fn parse_gpx_track(gpx: &str) -> Result<Vec<Location>, GpxError> {
+    let mut locations = Vec::new();
+    let mut rest = gpx;
+    
+    while let Some(start) = rest.find("<trkpt") {
+        rest = &rest[start..];
+        let Some(tag_end) = rest.find('>') else {
+            break; // truncated file
+        };
+        let tag = &rest[..tag_end];
+        
+        let latitude = parse_coordinate(xml_attribute(tag, "lat").ok_or(GpxError::MissingAttribute("lat"))?)?;
+        let longitude = parse_coordinate(xml_attribute(tag, "lon").ok_or(GpxError::MissingAttribute("lon"))?)?;
+        let mut location = Location::new(latitude, longitude);
+        
+        // Self-closing points carry no child elements
+        if tag.ends_with('/') {
+            rest = &rest[tag_end + 1..];
+        } else {
+            let body_end = rest.find("</trkpt>").unwrap_or(rest.len());
+            let body = &rest[tag_end + 1..body_end];
+            
+            if let Some(timestamp) = xml_element_text(body, "time").and_then(parse_rfc3339) {
+                location.timestamp = Some(timestamp);
+            }
+            location.elevation = xml_element_text(body, "ele")
+                .and_then(|ele| ele.parse::<f64>().ok())
+                .filter(|ele| ele.is_finite());
+            
+            rest = &rest[body_end..];
+        }
+        
+        locations.push(location);
+    }
+    
+    if locations.is_empty() {
+        return Err(GpxError::NoTrackPoints);
+    }
+    
+    Ok(locations)
+}
+
+fn parse_coordinate(value: &str) -> Result<f64, GpxError> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .ok_or_else(|| GpxError::InvalidCoordinate(value.to_string()))
+}
+
+fn xml_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut search = tag;
+    
+    while let Some(pos) = search.find(name) {
+        let preceded_by_space = search[..pos].ends_with(char::is_whitespace);
+        let after = search[pos + name.len()..].trim_start();
+        
+        if preceded_by_space && let Some(value) = after.strip_prefix('=') {
+            let value = value.trim_start();
+            let quote = value.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let value = &value[1..];
+                return value.find(quote).map(|end| &value[..end]);
+            }
+        }
+        
+        search = &search[pos + name.len()..];
+    }
+    
+    None
+}
+
+fn xml_element_text<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&close)? + start;
+    Some(body[start..end].trim())
+}
+
+// Parses an RFC 3339 timestamp such as 2024-05-01T12:30:00Z into Unix seconds
+fn parse_rfc3339(value: &str) -> Option<u64> {
+    let (date, time) = value.split_once(['T', 't', ' '])?;
+    
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: i64 = date_parts.next()?.parse().ok()?;
+    let day: i64 = date_parts.next()?.parse().ok()?;
+    
+    // Split off the timezone designator
+    let (clock, offset_secs) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, 0)
+    } else if let Some(pos) = time.rfind(['+', '-']) {
+        let (clock, offset) = time.split_at(pos);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        (clock, sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60))
+    } else {
+        (time, 0)
+    };
+    
+    let mut clock_parts = clock.splitn(3, ':');
+    let hour: i64 = clock_parts.next()?.parse().ok()?;
+    let minute: i64 = clock_parts.next()?.parse().ok()?;
+    let second: f64 = clock_parts.next()?.parse().ok()?;
+    
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    
+    // Days since the Unix epoch (civil-from-days algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second as i64 - offset_secs;
+    u64::try_from(seconds).ok()
 }
 
 impl Default for GeolocationService {
@@ -117,6 +679,68 @@ mod tests {
         assert!(distance < 400000.0); // Should be < 400km
     }
 
+    #[test]
+    fn test_distance_across_antimeridian() {
+        // Suva, Fiji to Apia, Samoa is about 1,150 km the short way, over the 180th meridian
+        let suva = Location::new(-18.1248, 178.4501);
+        let apia = Location::new(-13.8333, -171.7500);
+        let distance = suva.distance_to(&apia);
+        assert!((distance - 1_150_794.0).abs() < 1.0, "{}", distance);
+        assert_eq!(distance, apia.distance_to(&suva));
+        
+        // Same as measuring with Apia's longitude written past 180
+        let apia_east = Location::new(-13.8333, 188.2500);
+        assert!((suva.distance_to(&apia_east) - distance).abs() < 1e-6);
+        
+        // 0.2° of longitude at the equator either side of the meridian, and ±180 as one line
+        let west = Location::new(0.0, 179.9);
+        let east = Location::new(0.0, -179.9);
+        assert!((west.distance_to(&east) - 22_239.0).abs() < 1.0);
+        assert!(Location::new(10.0, 180.0).distance_to(&Location::new(10.0, -180.0)) < 1e-6);
+    }
+
+    #[test]
+    fn test_wrap_longitude() {
+        assert_eq!(wrap_longitude(190.0), -170.0);
+        assert_eq!(wrap_longitude(-190.0), 170.0);
+        assert_eq!(wrap_longitude(180.0), -180.0);
+        assert_eq!(wrap_longitude(-0.1278), -0.1278);
+    }
+
+    #[test]
+    fn test_bounding_box() {
+        assert!(bounding_box(&[]).is_none());
+        
+        let single = [Location::new(51.505, -0.09)];
+        let (sw, ne) = bounding_box(&single).unwrap();
+        assert_eq!((sw.latitude, sw.longitude), (51.505, -0.09));
+        assert_eq!((ne.latitude, ne.longitude), (51.505, -0.09));
+        
+        let points = [
+            Location::new(51.5074, -0.1278),
+            Location::new(48.8566, 2.3522),
+            Location::new(52.5200, 13.4050),
+        ];
+        let (sw, ne) = bounding_box(&points).unwrap();
+        assert_eq!((sw.latitude, sw.longitude), (48.8566, -0.1278));
+        assert_eq!((ne.latitude, ne.longitude), (52.5200, 13.4050));
+    }
+
+    #[test]
+    fn test_bounding_box_across_antimeridian() {
+        // Fiji, Tonga and Samoa straddle the 180th meridian
+        let points = [
+            Location::new(-18.1248, 178.4501),
+            Location::new(-21.1789, -175.1982),
+            Location::new(-13.8333, -171.7500),
+        ];
+        
+        let (sw, ne) = bounding_box(&points).unwrap();
+        assert_eq!((sw.latitude, sw.longitude), (-21.1789, 178.4501));
+        assert_eq!((ne.latitude, ne.longitude), (-13.8333, -171.7500));
+        assert!(sw.longitude > ne.longitude);
+    }
+
     #[test]
     fn test_geolocation_service() {
         let mut service = GeolocationService::new();
@@ -128,4 +752,293 @@ mod tests {
         assert!(service.get_current_location().is_some());
         assert_eq!(service.get_location_history().len(), 1);
     }
-}
\ No newline at end of file
+
+    fn timed_location(latitude: f64, longitude: f64, timestamp: Option<u64>) -> Location {
+        Location {
+            latitude,
+            longitude,
+            accuracy: None,
+            timestamp,
+            elevation: None,
+        }
+    }
+
+    #[test]
+    fn test_total_distance_and_average_speed() {
+        let mut service = GeolocationService::new();
+        assert_eq!(service.total_distance(), 0.0);
+        assert!(service.average_speed().is_none());
+        
+        // A single point has no distance or speed
+        service.update_location(timed_location(51.5074, -0.1278, Some(1_000)));
+        assert_eq!(service.total_distance(), 0.0);
+        assert!(service.average_speed().is_none());
+        
+        service.update_location(timed_location(51.5164, -0.1278, Some(1_100)));
+        service.update_location(timed_location(51.5254, -0.1278, Some(1_200)));
+        
+        let distance = service.total_distance();
+        assert!((distance - 2001.5).abs() < 1.0, "distance was {}", distance);
+        
+        let speed = service.average_speed().unwrap();
+        assert!((speed - distance / 200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_smoothed_speed() {
+        let mut service = GeolocationService::new();
+        assert!(service.smoothed_speed(0.5).is_none());
+        service.update_location(timed_location(0.0, 0.0, Some(0)));
+        assert!(service.smoothed_speed(0.5).is_none());
+        
+        // One fast outlier, then a steady ~11.1 m/s (0.001° of latitude every 10 s)
+        service.update_location(timed_location(0.01, 0.0, Some(10)));
+        let step = service.get_location_history()[0].distance_to(&timed_location(0.001, 0.0, None)) / 10.0;
+        for i in 2..40 {
+            service.update_location(timed_location(0.01 + (i - 1) as f64 * 0.001, 0.0, Some(i * 10)));
+        }
+        
+        let responsive = service.smoothed_speed(0.5).unwrap();
+        let steady = service.smoothed_speed(0.1).unwrap();
+        assert!((responsive - step).abs() < 1e-6, "{} vs {}", responsive, step);
+        assert!(steady > responsive && (steady - step).abs() < step, "{} vs {}", steady, step);
+        
+        // alpha 1 is the latest raw speed, 0 keeps the first
+        assert!((service.smoothed_speed(1.0).unwrap() - step).abs() < 1e-9);
+        assert!((service.smoothed_speed(0.0).unwrap() - step * 10.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_failed_detection_is_not_recorded() {
+        let mut service = GeolocationService::new();
+        for i in 0..3 {
+            service.record_detection::<GeoError>(Ok(timed_location(51.5 + i as f64 * 0.001, -0.1, Some(i * 10)))).unwrap();
+        }
+        let speed = service.smoothed_speed(0.5).unwrap();
+        let heading = service.heading(0.5, 5.0).unwrap();
+        
+        // A failed poll must not add a point thousands of kilometers away
+        let failed = service.record_detection(Err(GeoError::IpLookup("timed out".to_string())));
+        assert!(failed.is_err());
+        assert_eq!(service.get_location_history().len(), 3);
+        assert_eq!(service.get_current_location().unwrap().latitude, 51.502);
+        assert_eq!(service.smoothed_speed(0.5), Some(speed));
+        assert_eq!(service.heading(0.5, 5.0), Some(heading));
+    }
+
+    #[test]
+    fn test_replayed_locations() {
+        // 0.001° of latitude every 10 s, about 11.1 m/s
+        let fixes: Vec<Location> = (0..4).map(|i| timed_location(51.5 + i as f64 * 0.001, -0.1, Some(i * 10))).collect();
+        let mut service = GeolocationService::from_locations(fixes.clone());
+        assert!(service.get_current_location().is_none());
+        assert!(service.average_speed().is_none());
+        
+        assert_eq!(service.step().unwrap().latitude, 51.5);
+        assert_eq!(service.get_location_history().len(), 1);
+        while service.step().is_some() {}
+        assert!(service.step().is_none());
+        
+        assert_eq!(service.get_current_location().unwrap().latitude, fixes[3].latitude);
+        let expected = fixes[0].distance_to(&fixes[3]);
+        assert!((service.total_distance() - expected).abs() < 1e-6);
+        assert!((service.average_speed().unwrap() - expected / 30.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_bearing_and_heading() {
+        let origin = Location::new(0.0, 0.0);
+        assert!((origin.bearing_to(&Location::new(1.0, 0.0)) - 0.0).abs() < 1e-9);
+        assert!((origin.bearing_to(&Location::new(0.0, 1.0)) - 90.0).abs() < 1e-9);
+        assert!((origin.bearing_to(&Location::new(-1.0, 0.0)) - 180.0).abs() < 1e-9);
+        assert!((origin.bearing_to(&Location::new(0.0, -1.0)) - 270.0).abs() < 1e-9);
+        // Eastwards across the antimeridian
+        assert!((Location::new(0.0, 179.9).bearing_to(&Location::new(0.0, -179.9)) - 90.0).abs() < 1e-9);
+        
+        let mut service = GeolocationService::new();
+        service.update_location(Location::new(51.5, -0.1));
+        assert!(service.heading(0.5, 5.0).is_none());
+        
+        // Jitter under the minimum distance is ignored
+        service.update_location(Location::new(51.50001, -0.1));
+        assert!(service.heading(0.5, 5.0).is_none());
+        
+        // Heading north-west then north-east averages to north, not south
+        service.update_location(Location::new(51.501, -0.1015));
+        service.update_location(Location::new(51.502, -0.1));
+        let heading = service.heading(0.5, 5.0).unwrap();
+        assert!(!(1.0..=359.0).contains(&heading), "{}", heading);
+        assert!((service.heading(1.0, 5.0).unwrap() - 44.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_average_speed_edge_cases() {
+        // Zero elapsed time must not divide by zero
+        let mut service = GeolocationService::new();
+        service.update_location(timed_location(51.5074, -0.1278, Some(1_000)));
+        service.update_location(timed_location(51.5164, -0.1278, Some(1_000)));
+        assert!(service.average_speed().is_none());
+        
+        // Untimestamped points are ignored for speed but count for distance
+        let mut service = GeolocationService::new();
+        service.update_location(timed_location(51.5074, -0.1278, Some(1_000)));
+        service.update_location(timed_location(51.5164, -0.1278, None));
+        assert!(service.total_distance() > 0.0);
+        assert!(service.average_speed().is_none());
+    }
+
+    #[test]
+    fn test_import_gpx() {
+        let gpx = r#"<?xml version="1.0"?>
+            <gpx version="1.1"><trk><trkseg>
+                <trkpt lat="51.5074" lon="-0.1278"><ele>11</ele><time>2024-05-01T12:30:00Z</time></trkpt>
+                <trkpt lon='-0.1245' lat='51.4994'><time>2024-05-01T13:30:05+01:00</time></trkpt>
+                <trkpt lat="51.5000" lon="-0.1200"/>
+            </trkseg></trk></gpx>"#;
+        
+        let mut service = GeolocationService::new();
+        assert_eq!(service.import_gpx(gpx), Ok(3));
+        
+        let history = service.get_location_history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].latitude, 51.5074);
+        assert_eq!(history[1].longitude, -0.1245);
+        assert_eq!(history[0].timestamp, Some(1714566600));
+        assert_eq!(history[1].timestamp, Some(1714566605));
+        assert_eq!(history[0].elevation, Some(11.0));
+        assert_eq!(history[2].elevation, None);
+        assert_eq!(service.get_current_location().unwrap().latitude, 51.5);
+    }
+
+    #[test]
+    fn test_import_gpx_errors() {
+        let mut service = GeolocationService::new();
+        assert_eq!(service.import_gpx("<gpx></gpx>"), Err(GpxError::NoTrackPoints));
+        assert_eq!(
+            service.import_gpx(r#"<trkpt lat="51.5"></trkpt>"#),
+            Err(GpxError::MissingAttribute("lon"))
+        );
+        assert!(matches!(
+            service.import_gpx(r#"<trkpt lat="north" lon="0"/>"#),
+            Err(GpxError::InvalidCoordinate(_))
+        ));
+        assert!(service.get_location_history().is_empty());
+    }
+
+    #[test]
+    fn test_import_gpx_respects_history_cap() {
+        let points: String = (0..150)
+            .map(|i| format!(r#"<trkpt lat="{}" lon="0"/>"#, i as f64 * 0.001))
+            .collect();
+        
+        let mut service = GeolocationService::new();
+        assert_eq!(service.import_gpx(&points), Ok(150));
+        assert_eq!(service.get_location_history().len(), 100);
+    }
+
+    #[test]
+    fn test_smooth_history() {
+        let mut service = GeolocationService::new();
+        service.update_location(Location::new(51.5000, -0.1000));
+        // Jitter while standing still, roughly a meter apart
+        for i in 0..5 {
+            service.update_location(Location::new(51.5000 + i as f64 * 0.00001, -0.1000));
+        }
+        service.update_location(Location::new(51.5010, -0.1000)); // ~110 m on
+        service.update_location(Location::new(51.5020, -0.1000));
+        service.update_location(Location::new(51.50201, -0.1000)); // end, ~1 m after the previous point
+        
+        service.smooth_history(10.0);
+        let latitudes: Vec<f64> = service.get_location_history().iter().map(|l| l.latitude).collect();
+        assert_eq!(latitudes, vec![51.5000, 51.5010, 51.50201]);
+        assert_eq!(service.get_current_location().unwrap().latitude, 51.50201);
+        
+        // Endpoints survive even when everything is one spot
+        let mut service = GeolocationService::new();
+        for _ in 0..4 {
+            service.update_location(Location::new(48.8566, 2.3522));
+        }
+        service.smooth_history(10.0);
+        assert_eq!(service.get_location_history().len(), 2);
+    }
+
+    #[test]
+    fn test_average_history() {
+        let mut service = GeolocationService::new();
+        for latitude in [0.0, 0.3, 0.0, 0.3, 0.0] {
+            service.update_location(Location::new(latitude, 0.0));
+        }
+        
+        service.average_history(3);
+        let latitudes: Vec<f64> = service.get_location_history().iter().map(|l| l.latitude).collect();
+        assert_eq!(latitudes.len(), 5);
+        assert_eq!((latitudes[0], latitudes[4]), (0.0, 0.0));
+        assert!((latitudes[1] - 0.1).abs() < 1e-12);
+        assert!((latitudes[2] - 0.2).abs() < 1e-12);
+        assert!((latitudes[3] - 0.1).abs() < 1e-12);
+        
+        // A track crossing the antimeridian stays there instead of averaging to 0°
+        let mut service = GeolocationService::new();
+        for longitude in [179.8, 179.9, -180.0, -179.9, -179.8] {
+            service.update_location(Location::new(-17.0, longitude));
+        }
+        service.average_history(3);
+        let longitudes: Vec<f64> = service.get_location_history().iter().map(|l| l.longitude).collect();
+        assert!((longitudes[1] - 179.9).abs() < 1e-9, "{:?}", longitudes);
+        assert!((longitudes[2] + 180.0).abs() < 1e-9, "{:?}", longitudes);
+        assert!((longitudes[3] + 179.9).abs() < 1e-9, "{:?}", longitudes);
+    }
+
+    #[test]
+    fn test_history_json_round_trip() {
+        let mut service = GeolocationService::new();
+        let mut location = Location::new(51.5074, -0.1278);
+        location.accuracy = Some(12.5);
+        location.timestamp = Some(1_700_000_000);
+        service.update_location(location);
+        service.update_location(Location::new(51.4994, -0.1245));
+        
+        let mut restored = GeolocationService::new();
+        restored.import_history(&service.export_history()).unwrap();
+        
+        let history = restored.get_location_history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].accuracy, Some(12.5));
+        assert_eq!(history[0].timestamp, Some(1_700_000_000));
+        assert_eq!(restored.get_current_location().unwrap().latitude, 51.4994);
+        
+        // Bad input leaves the history alone
+        assert!(restored.import_history("not json").is_err());
+        assert_eq!(restored.get_location_history().len(), 2);
+    }
+
+    #[test]
+    fn test_import_history_respects_history_cap() {
+        let history: Vec<Location> = (0..150).map(|i| Location::new(i as f64 * 0.001, 0.0)).collect();
+        
+        let mut service = GeolocationService::new();
+        service.import_history(&serde_json::to_string(&history).unwrap()).unwrap();
+        assert_eq!(service.get_location_history().len(), 100);
+        assert_eq!(service.get_location_history()[0].latitude, 0.05);
+        assert_eq!(service.get_current_location().unwrap().latitude, 0.149);
+    }
+
+    #[test]
+    fn test_history_capacity() {
+        let mut service = GeolocationService::with_capacity(3);
+        for i in 0..10 {
+            service.update_location(Location::new(i as f64, 0.0));
+        }
+        
+        let latitudes: Vec<f64> = service.get_location_history().iter().map(|l| l.latitude).collect();
+        assert_eq!(latitudes, vec![7.0, 8.0, 9.0]);
+        
+        // Shrinking the cap trims the oldest entries straight away
+        service.set_max_history(1);
+        assert_eq!(service.get_location_history().len(), 1);
+        assert_eq!(service.get_location_history()[0].latitude, 9.0);
+        assert_eq!(service.get_current_location().unwrap().latitude, 9.0);
+        
+        assert_eq!(GeolocationService::new().max_history(), DEFAULT_MAX_HISTORY);
+    }
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use crate::routing::{GeometryFormat, Language, RouteRequest, RoutingService, Waypoint};
+
+// One request per input line, e.g. {"op":"geocode","query":"Berlin"} or
+// {"op":"route","waypoints":[{"lat":52.5,"lng":13.4},{"lat":52.4,"lng":13.5}]}
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum HeadlessRequest {
+    Geocode {
+        query: String,
+        #[serde(default)]
+        countries: Vec<String>, // ISO 3166-1 alpha-2 codes to restrict results to
+    },
+    Route {
+        waypoints: Vec<Waypoint>,
+        #[serde(default = "default_profile")]
+        profile: String,
+        #[serde(default)]
+        exclude: Vec<String>,
+        #[serde(default)]
+        language: Language,
+        #[serde(default)]
+        depart_at: Option<u64>, // Unix seconds; ignored unless the server has traffic data
+    },
+}
+
+fn default_profile() -> String {
+    "driving".to_string()
+}
+
+// One response line per request. The request's "id", if any, is echoed back so
+// callers can match responses to requests.
+#[derive(Debug, Serialize)]
+struct HeadlessResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl HeadlessResponse {
+    fn success(id: Option<serde_json::Value>, result: serde_json::Value) -> Self {
+        Self { id, ok: true, result: Some(result), error: None }
+    }
+
+    fn failure(id: Option<serde_json::Value>, error: String) -> Self {
+        Self { id, ok: false, result: None, error: Some(error) }
+    }
+}
+
+// Answers a single request line; malformed input and unknown ops become error responses
+async fn handle_line(service: &RoutingService, line: &str) -> HeadlessResponse {
+    let value: serde_json::Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(e) => return HeadlessResponse::failure(None, format!("Invalid JSON: {}", e)),
+    };
+    let id = value.get("id").cloned();
+
+    let request: HeadlessRequest = match serde_json::from_value(value) {
+        Ok(request) => request,
+        Err(e) => return HeadlessResponse::failure(id, format!("Invalid request: {}", e)),
+    };
+
+    let result = match request {
+        HeadlessRequest::Geocode { query, countries } => {
+            let countries: Vec<&str> = countries.iter().map(String::as_str).collect();
+            service
+                .geocode(&query, None, &countries)
+                .await
+                .map(|results| serde_json::to_value(results).unwrap_or_default())
+        }
+        HeadlessRequest::Route { waypoints, profile, exclude, language, depart_at } => {
+            let depart_at = depart_at.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+            let request = RouteRequest { waypoints, profile, exclude, depart_at };
+            service
+                .calculate_route(&request, GeometryFormat::GeoJson, false, language, false)
+                .await
+                .map(|route| serde_json::to_value(route).unwrap_or_default())
+        }
+    };
+
+    match result {
+        Ok(result) => HeadlessResponse::success(id, result),
+        Err(e) => HeadlessResponse::failure(id, e.to_string()),
+    }
+}
+
+// Reads newline-delimited JSON requests until end of input, writing one JSON response line each
+pub async fn run<R, W>(service: &RoutingService, reader: R, mut writer: W) -> std::io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = reader.lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = handle_line(service, &line).await;
+        let mut output = serde_json::to_string(&response)?;
+        output.push('\n');
+        writer.write_all(output.as_bytes()).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::{HttpClient, HttpResponse, RoutingError};
+    use futures_util::future::{FutureExt, LocalBoxFuture};
+
+    // Answers every request with the same Nominatim search result
+    struct OneResultHttp;
+
+    impl HttpClient for OneResultHttp {
+        fn get_with_headers<'a>(&'a self, _url: &'a str, _headers: &'a [(&'a str, &'a str)], _max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            let body = r#"[{"lat":"52.52","lon":"13.40","display_name":"Berlin, Germany","importance":0.9}]"#.to_string();
+            async move { Ok(HttpResponse { status: reqwest::StatusCode::OK, body }) }.boxed_local()
+        }
+
+        fn post_json<'a>(&'a self, url: &'a str, _body: &'a serde_json::Value, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.get(url, max_retries)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_headless_geocode_responses() {
+        let service = RoutingService::new().with_http_client(Box::new(OneResultHttp));
+        let input = "{\"id\":1,\"op\":\"geocode\",\"query\":\"Berlin\"}\n{\"id\":2,\"op\":\"geocode\",\"query\":\"berlin\"}\n";
+        let mut output = Vec::new();
+        run(&service, input.as_bytes(), &mut output).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let responses: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(responses.len(), 2);
+        for (response, id) in responses.iter().zip([1, 2]) {
+            assert_eq!(response["id"], id);
+            assert_eq!(response["result"][0]["display_name"], "Berlin, Germany");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_headless_errors_are_responses() {
+        let service = RoutingService::new();
+        let input = concat!(
+            "not json\n",
+            "\n",
+            "{\"id\": 7, \"op\": \"teleport\"}\n",
+            "{\"id\": \"r1\", \"op\": \"route\", \"waypoints\": [{\"lat\": 51.5, \"lng\": -0.1}]}\n",
+        );
+        let mut output = Vec::new();
+        run(&service, input.as_bytes(), &mut output).await.unwrap();
+
+        let responses: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 3);
+        assert!(responses.iter().all(|response| response["ok"] == false));
+
+        assert!(responses[0].get("id").is_none());
+        assert!(responses[0]["error"].as_str().unwrap().starts_with("Invalid JSON"));
+        assert_eq!(responses[1]["id"], 7);
+        assert!(responses[1]["error"].as_str().unwrap().contains("unknown variant `teleport`"));
+        assert_eq!(responses[2]["id"], "r1");
+        assert_eq!(responses[2]["error"], "At least 2 waypoints are required");
+    }
+}
@@ -0,0 +1,47 @@
+// Building page scripts from Rust values
+
+// Quotes text as a JavaScript string literal that can be spliced into a script passed to
+// evaluate_javascript. Quotes, backslashes and line breaks are escaped so user text can't end
+// the literal early, and `<` is escaped so a `</script>` in it can't close an enclosing tag.
+pub fn js_string_literal(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    literal.push('\'');
+    for c in s.chars() {
+        match c {
+            '\\' => literal.push_str("\\\\"),
+            '\'' => literal.push_str("\\'"),
+            '"' => literal.push_str("\\\""),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            '<' => literal.push_str("\\x3C"),
+            // Other control characters, and the separators older engines treat as line breaks
+            c if c.is_control() || c == '\u{2028}' || c == '\u{2029}' => {
+                literal.push_str(&format!("\\u{:04X}", c as u32));
+            }
+            c => literal.push(c),
+        }
+    }
+    literal.push('\'');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_js_string_literal() {
+        assert_eq!(js_string_literal("Café de Flore"), "'Café de Flore'");
+        assert_eq!(js_string_literal(""), "''");
+
+        // Attempts to break out of the literal or the page
+        assert_eq!(js_string_literal("O'Brien's"), "'O\\'Brien\\'s'");
+        assert_eq!(js_string_literal("'); alert(1); ('"), "'\\'); alert(1); (\\''");
+        assert_eq!(js_string_literal("C:\\temp\\'"), "'C:\\\\temp\\\\\\''");
+        assert_eq!(js_string_literal("say \"hi\""), "'say \\\"hi\\\"'");
+        assert_eq!(js_string_literal("line one\nline two\r\n"), "'line one\\nline two\\r\\n'");
+        assert_eq!(js_string_literal("</script><script>alert(1)</script>"), "'\\x3C/script>\\x3Cscript>alert(1)\\x3C/script>'");
+        assert_eq!(js_string_literal("a\u{2028}b\u{0}c\u{7f}"), "'a\\u2028b\\u0000c\\u007F'");
+    }
+}
@@ -0,0 +1,15 @@
+// Map, routing and geolocation logic without any GTK dependency, shared by the
+// map-rs binary and usable from other projects
+pub mod coords;
+pub mod favorites;
+pub mod geolocation;
+pub mod headless;
+pub mod js;
+pub mod map_source;
+pub mod message;
+pub mod overlay;
+pub mod routing;
+pub mod settings;
+pub mod share;
+pub mod supersede;
+pub mod units;
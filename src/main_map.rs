@@ -1,28 +1,233 @@
+use clap::Parser;
 use gtk::prelude::*;
-use gtk::{glib, Application, ApplicationWindow, Box as GtkBox, HeaderBar, Orientation, Button, Entry, Image, MenuButton, Settings, Switch, Label, Popover};
-use webkit2gtk::{WebView, WebViewExt, UserContentManager, UserContentManagerExt, UserScript, UserScriptInjectionTime, UserContentInjectedFrames};
-use std::path::Path;
+use gtk::{glib, Application, ApplicationWindow, Box as GtkBox, ButtonsType, DialogFlags, HeaderBar, MessageDialog, MessageType, Orientation, Button, CheckButton, ComboBoxText, DrawingArea, Entry, FileChooserAction, FileChooserDialog, Image, MenuButton, RadioButton, ResponseType, Settings, Switch, Label, ListBox, Popover, Spinner, ToggleButton};
+use webkit2gtk::{SnapshotOptions, SnapshotRegion, WebView, WebViewExt, UserContentManager, UserContentManagerExt, UserScript, UserScriptInjectionTime, UserContentInjectedFrames};
+use std::path::{Path, PathBuf};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-mod geolocation;
-mod routing;
-
-use geolocation::{GeolocationService, Location};
-use routing::{RoutingService, Waypoint};
+use map_rs::{coords, geolocation, headless, message, routing, units};
+use map_rs::favorites::{Favorite, FavoritesStore};
+use map_rs::geolocation::{bounding_box, GeolocationService, Location};
+use map_rs::js::js_string_literal;
+use map_rs::map_source::{default_map_source, MapSource};
+use map_rs::message::{IncomingMessage, MarkerInfo};
+use map_rs::overlay::{validate_overlay, OverlayError, OverlayStyle};
+use map_rs::routing::{format_distance, format_distance_rounded, format_height, DistanceRounding, normalize_country_codes, format_duration, GeocodeResult, GeometryFormat, Language, OtpBackend, RouteRequest, RouteResponse, RoutingError, RoutingService, ValhallaBackend, Waypoint, WaypointKind, DEFAULT_DUPLICATE_THRESHOLD_M, TRANSIT_PROFILE};
+use map_rs::settings::AppSettings;
+use map_rs::share::{decode_route_url, decode_view_url, encode_route_url, encode_view_url, load_route_geojson, MapView, ParseError, MAX_VIEW_ZOOM};
+use map_rs::supersede::RequestGeneration;
 
 const APP_ID: &str = "org.example.map-rs";
+const MAX_SEARCH_RESULTS: usize = 5;
+
+// Fallback position (central London) when location detection fails
+const DEFAULT_LATITUDE: f64 = 51.5074;
+const DEFAULT_LONGITUDE: f64 = -0.1278;
+
+// How long the marker flashed for a clicked direction step stays on the map
+const STEP_HIGHLIGHT_DURATION: Duration = Duration::from_secs(3);
+
+// In follow mode a maneuver counts as done, and the arrow moves on, within this distance
+const STEP_REACHED_M: f64 = 25.0;
+
+// How often the position is re-detected while follow mode is on
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(5);
+
+// Weight of the newest fix in follow mode's speed readout; lower is steadier
+const FOLLOW_SPEED_SMOOTHING: f64 = 0.3;
+
+// Heading-up mode's weight of the newest bearing, and the movement needed to count as one
+const HEADING_SMOOTHING: f64 = 0.5;
+const HEADING_MIN_MOVE_M: f64 = 5.0;
+
+// Follow mode announces arrival once the position is this close to the route's last stop
+const DEFAULT_ARRIVAL_RADIUS_M: f64 = 30.0;
+
+// Longest the map scale bar may grow, in pixels
+const SCALE_BAR_MAX_PX: f64 = 120.0;
+
+// Imported GPX tracks drop points closer than this to the previous one, then average each
+// point with its neighbors, so stationary jitter doesn't draw as a blob
+const GPX_MIN_POINT_SPACING_M: f64 = 5.0;
+const GPX_AVERAGE_WINDOW: usize = 3;
+
+// Base map tile sources offered in the header bar; the first entry is the default
+struct TileLayer {
+    id: &'static str,
+    label: &'static str,
+    url: &'static str,
+    attribution: &'static str,
+    max_zoom: u8,
+}
+
+const TILE_LAYERS: &[TileLayer] = &[
+    TileLayer {
+        id: "osm",
+        label: "Standard",
+        url: "https://{s}.tile.openstreetmap.org/{z}/{x}/{y}.png",
+        attribution: "© <a href=\"https://www.openstreetmap.org/copyright\">OpenStreetMap</a> contributors",
+        max_zoom: 19,
+    },
+    TileLayer {
+        id: "satellite",
+        label: "Satellite",
+        url: "https://server.arcgisonline.com/ArcGIS/rest/services/World_Imagery/MapServer/tile/{z}/{y}/{x}",
+        attribution: "Tiles © Esri — Source: Esri, Maxar, Earthstar Geographics, and the GIS User Community",
+        max_zoom: 19,
+    },
+    TileLayer {
+        id: "terrain",
+        label: "Terrain",
+        url: "https://{s}.tile.opentopomap.org/{z}/{x}/{y}.png",
+        attribution: "© <a href=\"https://www.openstreetmap.org/copyright\">OpenStreetMap</a> contributors, © <a href=\"https://opentopomap.org\">OpenTopoMap</a>",
+        max_zoom: 17,
+    },
+];
+
+// How a planned route is drawn and framed for each travel mode; the first entry also
+// serves any profile not listed
+struct RouteStyle {
+    profile: &'static str,
+    color: &'static str, // CSS color of the route line
+    weight: u8, // line width in pixels
+    padding: u16, // pixels kept clear around the route when zooming to it
+    max_zoom: u8, // closest zoom the fit may use; walks and rides are framed tighter
+}
+
+const ROUTE_STYLES: &[RouteStyle] = &[
+    RouteStyle { profile: "driving", color: "#007cba", weight: 5, padding: 20, max_zoom: 16 },
+    RouteStyle { profile: "walking", color: "#2e8b57", weight: 4, padding: 8, max_zoom: 18 },
+    RouteStyle { profile: "cycling", color: "#d2691e", weight: 4, padding: 12, max_zoom: 17 },
+    RouteStyle { profile: TRANSIT_PROFILE, color: "#8e44ad", weight: 5, padding: 20, max_zoom: 17 },
+];
+
+fn route_style(profile: &str) -> &'static RouteStyle {
+    ROUTE_STYLES.iter().find(|style| style.profile == profile).unwrap_or(&ROUTE_STYLES[0])
+}
+
+// Upper end of the search pin setting
+const MAX_SEARCH_MARKERS_LIMIT: usize = 50;
+
+// Padding around imported tracks and other shapes the map is zoomed to
+const FIT_PADDING_PX: u16 = 20;
+
+// Command-line options, applied once the map page has loaded
+#[derive(Parser, Debug, Clone)]
+#[command(name = "map-rs", about = "OpenStreetMap viewer with routing")]
+struct Cli {
+    #[arg(value_name = "LINK", value_parser = parse_shared_link, help = "Shared route link (map-rs://route?wp=...) or map view (geo:LAT,LNG?z=ZOOM) to open")]
+    link: Option<SharedLink>,
+    
+    #[arg(long, value_name = "LAT,LNG", value_parser = parse_center, conflicts_with = "search", help = "Open the map centered on these coordinates")]
+    center: Option<Location>,
+    
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=19), help = "Initial zoom level (0-19)")]
+    zoom: Option<u8>,
+    
+    #[arg(long, value_name = "QUERY", help = "Search for a place at startup")]
+    search: Option<String>,
+    
+    #[arg(long, help = "Run without a window, answering JSON requests on stdin (one per line) on stdout")]
+    headless: bool,
+    
+    #[arg(long, value_name = "URL", help = "Plan routes with this Valhalla server instead of OSRM")]
+    valhalla: Option<String>,
+    
+    #[arg(long, value_name = "URL", help = "Offer transit directions from this OpenTripPlanner server")]
+    otp: Option<String>,
+    
+    #[arg(long, value_name = "METERS", default_value_t = DEFAULT_DUPLICATE_THRESHOLD_M, help = "Refuse routes with consecutive stops closer than this (0 allows them)")]
+    duplicate_threshold: f64,
+    
+    #[arg(long, value_name = "METERS", default_value_t = DEFAULT_ARRIVAL_RADIUS_M, help = "Follow mode notifies and stops this close to the destination")]
+    arrival_radius: f64,
+    
+    #[arg(long, value_name = "STRING", help = "User-Agent for geocoder requests; include a contact, e.g. \"map-rs (you@example.org)\"")]
+    user_agent: Option<String>,
+}
+
+impl Cli {
+    fn routing_service(&self) -> RoutingService {
+        let mut service = match &self.valhalla {
+            Some(url) => RoutingService::new().with_backend(Box::new(ValhallaBackend::new(url.clone()))),
+            None => RoutingService::new(),
+        };
+        if let Some(url) = &self.otp {
+            service = service.with_transit_backend(Box::new(OtpBackend::new(url.clone())));
+        }
+        service.duplicate_threshold_m = self.duplicate_threshold;
+        match &self.user_agent {
+            Some(user_agent) => service.with_user_agent(user_agent.clone()),
+            None => service,
+        }
+    }
+}
+
+// A link given on the command line: a shared route, or a map position to open at
+#[derive(Debug, Clone)]
+enum SharedLink {
+    Route(Vec<Waypoint>, String),
+    View(MapView),
+}
+
+fn parse_shared_link(s: &str) -> Result<SharedLink, ParseError> {
+    if s.trim_start().to_ascii_lowercase().starts_with("geo:") {
+        decode_view_url(s).map(SharedLink::View)
+    } else {
+        decode_route_url(s).map(|(waypoints, profile)| SharedLink::Route(waypoints, profile))
+    }
+}
+
+// Parses "lat,lng" for --center, rejecting coordinates outside the valid ranges
+fn parse_center(s: &str) -> Result<Location, String> {
+    let (lat, lng) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected LAT,LNG but got '{}'", s))?;
+    let coordinate = |value: &str| {
+        value
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("'{}' is not a number", value.trim()))
+    };
+    let (lat, lng) = (coordinate(lat)?, coordinate(lng)?);
+    Waypoint { lat, lng, name: None, kind: WaypointKind::Stop }.validate()?;
+    Ok(Location::new(lat, lng))
+}
 
 fn main() -> glib::ExitCode {
+    // Prints usage for --help and exits non-zero on invalid arguments
+    let cli = Cli::parse();
+    
     // Initialize Tokio runtime for async operations
     let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
     let _guard = rt.enter();
     
+    if cli.headless {
+        let reader = tokio::io::BufReader::new(tokio::io::stdin());
+        return match rt.block_on(headless::run(&cli.routing_service(), reader, tokio::io::stdout())) {
+            Ok(()) => glib::ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("❌ Headless mode failed: {}", e);
+                glib::ExitCode::FAILURE
+            }
+        };
+    }
+    
+    // GTK must not see our arguments, or it would try to open them as files
+    let program: Vec<String> = std::env::args().take(1).collect();
     let app = Application::builder().application_id(APP_ID).build();
-    app.connect_activate(build_ui);
-    app.run()
+    app.connect_activate(move |app| build_ui(app, cli.clone()));
+    app.run_with_args(&program)
 }
 
-fn build_ui(app: &Application) {
+fn build_ui(app: &Application, cli: Cli) {
+    // Restore preferences saved by the previous session
+    let app_settings = Arc::new(Mutex::new(AppSettings::load()));
+    let initial_settings = app_settings.lock().unwrap().clone();
+    
     let window = ApplicationWindow::builder()
         .application(app)
         .title("Map.rs")
@@ -51,21 +256,81 @@ fn build_ui(app: &Application) {
     
     // Theme setting
     let theme_row = GtkBox::new(Orientation::Horizontal, 10);
-    let theme_label = Label::new(Some("Light Mode"));
+    let theme_label = Label::new(Some(if initial_settings.dark_mode { "Dark Mode" } else { "Light Mode" }));
     let theme_toggle = Switch::new();
+    theme_toggle.set_active(initial_settings.dark_mode);
+    apply_theme(initial_settings.dark_mode);
     theme_row.pack_start(&theme_label, false, false, 0);
     theme_row.pack_end(&theme_toggle, false, false, 0);
     
     // Units setting
     let units_row = GtkBox::new(Orientation::Horizontal, 10);
-    let units_label = Label::new(Some("Miles"));
+    let units_label = Label::new(Some(if initial_settings.use_miles { "Miles" } else { "Kilometers" }));
     let units_toggle = Switch::new();
-    units_toggle.set_active(true); // Default to miles (true = miles, false = km)
+    units_toggle.set_active(initial_settings.use_miles); // true = miles, false = km
     units_row.pack_start(&units_label, false, false, 0);
     units_row.pack_end(&units_toggle, false, false, 0);
     
+    // Road snapping setting
+    let snap_row = GtkBox::new(Orientation::Horizontal, 10);
+    let snap_label = Label::new(Some("Snap points to roads"));
+    let snap_toggle = Switch::new();
+    snap_toggle.set_active(initial_settings.snap_to_road);
+    snap_row.pack_start(&snap_label, false, false, 0);
+    snap_row.pack_end(&snap_toggle, false, false, 0);
+    
+    // Search country restriction setting
+    let countries_row = GtkBox::new(Orientation::Horizontal, 10);
+    let countries_label = Label::new(Some("Search only in"));
+    let countries_entry = Entry::new();
+    countries_entry.set_placeholder_text(Some("all countries, or e.g. gb,ie"));
+    countries_entry.set_text(&initial_settings.search_countries.join(","));
+    countries_row.pack_start(&countries_label, false, false, 0);
+    countries_row.pack_end(&countries_entry, false, false, 0);
+    
+    // How many search result pins stay on the map
+    let search_pins_row = GtkBox::new(Orientation::Horizontal, 10);
+    let search_pins_label = Label::new(Some("Search pins kept"));
+    let search_pins_spin = gtk::SpinButton::with_range(1.0, MAX_SEARCH_MARKERS_LIMIT as f64, 1.0);
+    search_pins_spin.set_value(initial_settings.max_search_markers.clamp(1, MAX_SEARCH_MARKERS_LIMIT) as f64);
+    search_pins_row.pack_start(&search_pins_label, false, false, 0);
+    search_pins_row.pack_end(&search_pins_spin, false, false, 0);
+    
+    // Whether nearby search pins are grouped into clusters
+    let cluster_row = GtkBox::new(Orientation::Horizontal, 10);
+    let cluster_label = Label::new(Some("Cluster search pins"));
+    let cluster_toggle = Switch::new();
+    cluster_toggle.set_active(initial_settings.cluster_search_markers);
+    cluster_row.pack_start(&cluster_label, false, false, 0);
+    cluster_row.pack_end(&cluster_toggle, false, false, 0);
+    
+    // Server instruction text setting, mostly for checking our own wording
+    let raw_instructions_row = GtkBox::new(Orientation::Horizontal, 10);
+    let raw_instructions_label = Label::new(Some("Server's own directions text"));
+    let raw_instructions_toggle = Switch::new();
+    raw_instructions_toggle.set_active(initial_settings.raw_instructions);
+    raw_instructions_row.pack_start(&raw_instructions_label, false, false, 0);
+    raw_instructions_row.pack_end(&raw_instructions_toggle, false, false, 0);
+    
+    // Instruction language setting
+    let language_row = GtkBox::new(Orientation::Horizontal, 10);
+    let language_label = Label::new(Some("Directions language"));
+    let language_combo = ComboBoxText::new();
+    for language in Language::ALL {
+        language_combo.append(Some(language.code()), language.label());
+    }
+    language_combo.set_active_id(Some(initial_settings.language.code()));
+    language_row.pack_start(&language_label, false, false, 0);
+    language_row.pack_end(&language_combo, false, false, 0);
+    
     popover_box.pack_start(&theme_row, false, false, 0);
     popover_box.pack_start(&units_row, false, false, 0);
+    popover_box.pack_start(&snap_row, false, false, 0);
+    popover_box.pack_start(&language_row, false, false, 0);
+    popover_box.pack_start(&raw_instructions_row, false, false, 0);
+    popover_box.pack_start(&countries_row, false, false, 0);
+    popover_box.pack_start(&search_pins_row, false, false, 0);
+    popover_box.pack_start(&cluster_row, false, false, 0);
     
     settings_popover.add(&popover_box);
     popover_box.show_all();
@@ -83,24 +348,38 @@ fn build_ui(app: &Application) {
     // Add settings menu to the left of close button
     header_bar.pack_end(&settings_menu_button);
     
+    // Base map selector, left of the settings menu
+    let tile_menu_button = MenuButton::new();
+    tile_menu_button.set_image(Some(&Image::from_icon_name(Some("view-paged"), gtk::IconSize::Button)));
+    tile_menu_button.set_tooltip_text(Some("Map layer"));
+    let tile_popover = Popover::new(Some(&tile_menu_button));
+    let tile_box = GtkBox::new(Orientation::Vertical, 5);
+    tile_box.set_margin_start(15);
+    tile_box.set_margin_end(15);
+    tile_box.set_margin_top(10);
+    tile_box.set_margin_bottom(10);
+    tile_popover.add(&tile_box);
+    tile_menu_button.set_popover(Some(&tile_popover));
+    header_bar.pack_end(&tile_menu_button);
+    
     // Connect theme toggle functionality
     {
         let label_clone = theme_label.clone();
+        let app_settings = app_settings.clone();
         theme_toggle.connect_state_set(move |_, is_active| {
             if is_active {
                 println!("🌙 Switching to dark mode");
                 label_clone.set_text("Dark Mode");
-                if let Some(settings) = Settings::default() {
-                    settings.set_gtk_application_prefer_dark_theme(true);
-                    settings.set_gtk_theme_name(Some("Adwaita"));
-                }
             } else {
                 println!("🌞 Switching to light mode");
                 label_clone.set_text("Light Mode");
-                if let Some(settings) = Settings::default() {
-                    settings.set_gtk_application_prefer_dark_theme(false);
-                    settings.set_gtk_theme_name(Some("Adwaita"));
-                }
+            }
+            apply_theme(is_active);
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.dark_mode = is_active;
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
             }
             glib::Propagation::Proceed
         });
@@ -120,13 +399,20 @@ fn build_ui(app: &Application) {
 
     // Initialize services and shared state
     let geo_service = Arc::new(Mutex::new(GeolocationService::new()));
-    let routing_service = Arc::new(RoutingService::new());
-    let use_miles = Arc::new(Mutex::new(true)); // Default to miles
+    let routing_service = Arc::new(cli.routing_service());
+    let arrival_radius = cli.arrival_radius;
+    let use_miles = Arc::new(Mutex::new(initial_settings.use_miles));
+    let language = Arc::new(Mutex::new(initial_settings.language));
+    let raw_instructions = Arc::new(Mutex::new(initial_settings.raw_instructions));
+    let search_countries = Arc::new(Mutex::new(initial_settings.search_countries.clone()));
+    let scale_bar = ScaleBar::new();
     
     // Connect units toggle functionality
     {
         let label_clone = units_label.clone();
         let use_miles_clone = use_miles.clone();
+        let app_settings = app_settings.clone();
+        let scale_bar = scale_bar.clone();
         units_toggle.connect_state_set(move |_, is_active| {
             if is_active {
                 println!("📏 Switching to miles");
@@ -137,6 +423,80 @@ fn build_ui(app: &Application) {
                 label_clone.set_text("Kilometers");
                 *use_miles_clone.lock().unwrap() = false;
             }
+            scale_bar.refresh(is_active);
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.use_miles = is_active;
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
+            }
+            glib::Propagation::Proceed
+        });
+    }
+    
+    // Connect country restriction; invalid codes are flagged and not applied
+    {
+        let search_countries = search_countries.clone();
+        let app_settings = app_settings.clone();
+        countries_entry.connect_changed(move |entry| {
+            let text = entry.text();
+            let codes: Vec<&str> = text
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|code| !code.is_empty())
+                .collect();
+            let countries = match normalize_country_codes(&codes) {
+                Ok(countries) => countries,
+                Err(e) => {
+                    entry.set_secondary_icon_name(Some("dialog-warning-symbolic"));
+                    entry.set_secondary_icon_tooltip_text(Some(&e.to_string()));
+                    return;
+                }
+            };
+            entry.set_secondary_icon_name(None);
+            
+            println!("🌍 Limiting searches to: {}", if countries.is_empty() { "all countries".to_string() } else { countries.join(", ") });
+            *search_countries.lock().unwrap() = countries.clone();
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.search_countries = countries;
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
+            }
+        });
+    }
+    
+    // Connect language selector; applies to routes planned from now on
+    {
+        let language = language.clone();
+        let app_settings = app_settings.clone();
+        language_combo.connect_changed(move |combo| {
+            let Some(selected) = combo.active_id().and_then(|id| Language::from_code(&id)) else {
+                return;
+            };
+            println!("🌐 Switching directions language to {}", selected.label());
+            *language.lock().unwrap() = selected;
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.language = selected;
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
+            }
+        });
+    }
+    
+    // Connect server instruction text toggle; applies to routes planned from now on
+    {
+        let raw_instructions = raw_instructions.clone();
+        let app_settings = app_settings.clone();
+        raw_instructions_toggle.connect_state_set(move |_, is_active| {
+            println!("🗒️ {} the server's instruction text", if is_active { "Using" } else { "Not using" });
+            *raw_instructions.lock().unwrap() = is_active;
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.raw_instructions = is_active;
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
+            }
             glib::Propagation::Proceed
         });
     }
@@ -158,17 +518,136 @@ fn build_ui(app: &Application) {
         .build();
     
     let search_button = Button::with_label("Search");
+    
+    // Favorites: star the current search result, or pick a saved place
+    let favorite_button = Button::new();
+    favorite_button.set_image(Some(&Image::from_icon_name(Some("starred"), gtk::IconSize::Button)));
+    favorite_button.set_tooltip_text(Some("Save search result to favorites"));
+    let directions_button = Button::new();
+    directions_button.set_image(Some(&Image::from_icon_name(Some("mark-location"), gtk::IconSize::Button)));
+    directions_button.set_tooltip_text(Some("Directions from your location to the search result"));
+    let favorites_menu_button = MenuButton::new();
+    favorites_menu_button.set_image(Some(&Image::from_icon_name(Some("user-bookmarks"), gtk::IconSize::Button)));
+    favorites_menu_button.set_tooltip_text(Some("Favorites"));
+    
     let location_button = Button::with_label("My Location");
+    location_button.set_tooltip_text(Some("Center the map on your current location (Ctrl+Home)"));
+    location_button.set_sensitive(false); // enabled once there is a location fix
+    let follow_toggle = ToggleButton::with_label("Follow");
+    follow_toggle.set_tooltip_text(Some("Keep the map centered on your position as it updates"));
+    let heading_toggle = ToggleButton::with_label("Heading Up");
+    heading_toggle.set_tooltip_text(Some("Turn the map so your direction of travel points up; off keeps north up"));
+    let speed_label = Label::new(None);
+    speed_label.set_tooltip_text(Some("Speed over the last few position updates"));
+    speed_label.set_no_show_all(true);
     let route_button = Button::with_label("Plan Route");
+    let optimize_button = Button::with_label("Optimize Order");
+    optimize_button.set_tooltip_text(Some("Reorder the stops into the shortest trip, keeping the first one as the start"));
+    
+    // Other ways to start a route, in a menu next to Optimize Order
+    let route_menu_button = MenuButton::new();
+    route_menu_button.set_tooltip_text(Some("More ways to plan a route"));
+    let route_popover = Popover::new(Some(&route_menu_button));
+    let route_menu_box = GtkBox::new(Orientation::Vertical, 0);
+    route_menu_box.set_margin_top(5);
+    route_menu_box.set_margin_bottom(5);
+    let route_addresses_item = gtk::ModelButton::builder().text("Route from Addresses…").build();
+    route_addresses_item.set_tooltip_text(Some("Paste addresses, one per line, and route through them in that order"));
+    route_menu_box.pack_start(&route_addresses_item, false, false, 0);
+    route_popover.add(&route_menu_box);
+    route_menu_box.show_all();
+    route_menu_button.set_popover(Some(&route_popover));
+    
+    // Travel mode selector for route planning
+    let profile_combo = ComboBoxText::new();
+    profile_combo.append(Some("driving"), "Driving");
+    profile_combo.append(Some("walking"), "Walking");
+    profile_combo.append(Some("cycling"), "Cycling");
+    if routing_service.supports_transit() {
+        profile_combo.append(Some(TRANSIT_PROFILE), "Transit");
+    }
+    profile_combo.set_active_id(Some("driving"));
+    profile_combo.set_tooltip_text(Some("Travel mode"));
+    
+    // Road classes to avoid; the stock OSRM profiles only support these when driving
+    let avoid_motorways_check = CheckButton::with_label("Avoid highways");
+    let avoid_tolls_check = CheckButton::with_label("Avoid tolls");
+    {
+        let avoid_motorways_check = avoid_motorways_check.clone();
+        let avoid_tolls_check = avoid_tolls_check.clone();
+        profile_combo.connect_changed(move |combo| {
+            let driving = combo.active_id().as_deref() == Some("driving");
+            avoid_motorways_check.set_sensitive(driving);
+            avoid_tolls_check.set_sensitive(driving);
+        });
+    }
+    
     let clear_button = Button::with_label("Clear");
+    
+    // Less common ways to clear, in a menu next to Clear
+    let clear_menu_button = MenuButton::new();
+    clear_menu_button.set_tooltip_text(Some("More clearing options"));
+    let clear_popover = Popover::new(Some(&clear_menu_button));
+    let clear_menu_box = GtkBox::new(Orientation::Vertical, 0);
+    clear_menu_box.set_margin_top(5);
+    clear_menu_box.set_margin_bottom(5);
+    let clear_search_item = gtk::ModelButton::builder().text("Clear Search Markers").build();
+    clear_search_item.set_tooltip_text(Some("Remove the pins dropped by searches, keeping the route"));
+    clear_menu_box.pack_start(&clear_search_item, false, false, 0);
+    let clear_overlays_item = gtk::ModelButton::builder().text("Clear Overlays").build();
+    clear_overlays_item.set_tooltip_text(Some("Remove the GeoJSON overlays loaded with Load Overlay"));
+    clear_menu_box.pack_start(&clear_overlays_item, false, false, 0);
+    clear_popover.add(&clear_menu_box);
+    clear_menu_box.show_all();
+    clear_menu_button.set_popover(Some(&clear_popover));
+    let undo_button = Button::with_label("Undo Point");
+    undo_button.set_tooltip_text(Some("Remove the last point placed on the map"));
     let directions_toggle = Button::with_label("Directions");
+    let import_gpx_button = Button::with_label("Import GPX");
+    let open_route_button = Button::with_label("Open Route");
+    open_route_button.set_tooltip_text(Some("Load a route saved as GeoJSON and plan directions along its stops"));
+    let load_overlay_button = Button::with_label("Load Overlay");
+    load_overlay_button.set_tooltip_text(Some("Draw a GeoJSON file, such as a delivery zone, over the map"));
+    let export_image_button = Button::with_label("Export Image");
+    export_image_button.set_tooltip_text(Some("Save the visible map as a PNG image"));
+    
+    // Straight-line distance measurement, independent of routing
+    let measure_toggle = ToggleButton::with_label("Measure");
+    measure_toggle.set_tooltip_text(Some("Click points on the map to measure straight-line distance"));
+    let measure_label = Label::new(None);
+    measure_label.set_no_show_all(true);
+    
+    // Spins while searches and route requests are running
+    let spinner = Spinner::new();
+    spinner.set_tooltip_text(Some("Waiting for the server"));
+    let busy = BusyIndicator::new(spinner.clone());
     
     controls_box.pack_start(&location_entry, false, false, 0);
     controls_box.pack_start(&search_button, false, false, 0);
+    controls_box.pack_start(&directions_button, false, false, 0);
+    controls_box.pack_start(&favorite_button, false, false, 0);
+    controls_box.pack_start(&favorites_menu_button, false, false, 0);
     controls_box.pack_start(&location_button, false, false, 0);
+    controls_box.pack_start(&follow_toggle, false, false, 0);
+    controls_box.pack_start(&heading_toggle, false, false, 0);
+    controls_box.pack_start(&speed_label, false, false, 0);
     controls_box.pack_start(&route_button, false, false, 0);
+    controls_box.pack_start(&optimize_button, false, false, 0);
+    controls_box.pack_start(&route_menu_button, false, false, 0);
+    controls_box.pack_start(&profile_combo, false, false, 0);
+    controls_box.pack_start(&avoid_motorways_check, false, false, 0);
+    controls_box.pack_start(&avoid_tolls_check, false, false, 0);
     controls_box.pack_start(&directions_toggle, false, false, 0);
+    controls_box.pack_start(&import_gpx_button, false, false, 0);
+    controls_box.pack_start(&open_route_button, false, false, 0);
+    controls_box.pack_start(&load_overlay_button, false, false, 0);
+    controls_box.pack_start(&export_image_button, false, false, 0);
+    controls_box.pack_start(&measure_toggle, false, false, 0);
+    controls_box.pack_start(&measure_label, false, false, 0);
+    controls_box.pack_start(&undo_button, false, false, 0);
     controls_box.pack_start(&clear_button, false, false, 0);
+    controls_box.pack_start(&clear_menu_button, false, false, 0);
+    controls_box.pack_start(&spinner, false, false, 0);
     
     // WebView setup
     let user_content_manager = UserContentManager::new();
@@ -197,11 +676,7 @@ fn build_ui(app: &Application) {
     directions_box.set_margin_end(10);
     directions_box.set_margin_top(5);
     directions_box.set_margin_bottom(10);
-    
-    let directions_label = Label::new(Some("Click 'Plan Route' to see turn-by-turn directions"));
-    directions_label.set_line_wrap(true);
-    directions_label.set_xalign(0.0);
-    directions_box.pack_start(&directions_label, false, false, 0);
+    reset_directions(&directions_box);
     
     directions_scrolled.add(&directions_box);
     directions_container.pack_start(&directions_title, false, false, 0);
@@ -209,81 +684,711 @@ fn build_ui(app: &Application) {
     
     // Will hide directions pane after show_all()
     
+    // Waypoints sidebar, shown once points are dropped on the map
+    let waypoint_panel = WaypointPanel::new();
+    
+    // The route currently drawn; follow mode watches for arrival at its last stop
+    let last_route: Rc<RefCell<Option<Rc<PlannedRoute>>>> = Rc::new(RefCell::new(None));
+    
     // Set up WebView with message handlers
-    setup_webview(&webview, &user_content_manager, geo_service.clone(), routing_service.clone(), directions_box.clone(), directions_container.clone(), use_miles.clone());
+    setup_webview(&webview, &user_content_manager, geo_service.clone(), routing_service.clone(), directions_box.clone(), directions_container.clone(), measure_label.clone(), use_miles.clone(), language.clone(), raw_instructions.clone(), busy.clone(), vec![route_button.clone(), optimize_button.clone()], waypoint_panel.clone(), scale_bar.clone(), last_route.clone());
     
-    // Load the HTML map
-    load_map_html(&webview);
+    // Hand the saved preferences to the page before it loads so it starts on the saved layer
+    user_content_manager.add_script(&UserScript::new(
+        &page_settings_script(&initial_settings),
+        UserContentInjectedFrames::AllFrames,
+        UserScriptInjectionTime::Start,
+        &[],
+        &[],
+    ));
+    setup_tile_menu(&tile_box, webview.clone(), app_settings.clone());
     
-    // Content area with map and directions pane
-    let content_box = GtkBox::new(Orientation::Horizontal, 0);
+    // Connect road snapping toggle; the page decides per click whether to ask for a snap
+    {
+        let webview = webview.clone();
+        let app_settings = app_settings.clone();
+        snap_toggle.connect_state_set(move |_, is_active| {
+            println!("🧲 Road snapping {}", if is_active { "enabled" } else { "disabled" });
+            let js_code = format!("window.snapToRoads = {};", is_active);
+            webview.evaluate_javascript(
+                &js_code,
+                None,
+                None,
+                webkit2gtk::gio::Cancellable::NONE,
+                |_| {}
+            );
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.snap_to_road = is_active;
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
+            }
+            glib::Propagation::Proceed
+        });
+    }
     
-    content_box.pack_start(&directions_container, false, false, 0);
-    content_box.pack_start(&webview, true, true, 0);
+    // Connect the search pin limit; the page drops the oldest pins beyond it right away
+    {
+        let webview = webview.clone();
+        let app_settings = app_settings.clone();
+        search_pins_spin.connect_value_changed(move |spin| {
+            let limit = spin.value_as_int().max(1) as usize;
+            println!("📌 Keeping the {} newest search pins", limit);
+            let js_code = format!("if (window.setMaxSearchMarkers) {{ window.setMaxSearchMarkers({}); }}", limit);
+            webview.evaluate_javascript(
+                &js_code,
+                None,
+                None,
+                webkit2gtk::gio::Cancellable::NONE,
+                |_| {}
+            );
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.max_search_markers = limit;
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
+            }
+        });
+    }
     
-    main_box.pack_start(&controls_box, false, false, 0);
-    main_box.pack_start(&content_box, true, true, 0);
+    // Connect search pin clustering; the page regroups the pins already shown
+    {
+        let webview = webview.clone();
+        let app_settings = app_settings.clone();
+        cluster_toggle.connect_state_set(move |_, is_active| {
+            println!("📌 {} search pins", if is_active { "Clustering" } else { "Not clustering" });
+            set_clustering(&webview, is_active);
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.cluster_search_markers = is_active;
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
+            }
+            glib::Propagation::Proceed
+        });
+    }
     
-    window.add(&main_box);
+    // Size the scale bar for the initial view; the page reports later moves itself
+    {
+        let scale_bar = scale_bar.clone();
+        let use_miles = use_miles.clone();
+        webview.connect_load_changed(move |webview, event| {
+            if event != webkit2gtk::LoadEvent::Finished {
+                return;
+            }
+            let webview = webview.clone();
+            let scale_bar = scale_bar.clone();
+            let use_miles = use_miles.clone();
+            glib::spawn_future_local(async move {
+                if let Some((lat, zoom)) = map_view(&webview).await {
+                    scale_bar.set_view(lat, zoom, *use_miles.lock().unwrap());
+                }
+            });
+        });
+    }
     
-    // Set up event handlers
-    setup_event_handlers(
-        geo_service,
-        routing_service,
-        location_entry,
-        search_button,
-        location_button,
-        route_button,
-        clear_button,
-        directions_toggle,
-        webview.clone(),
-        directions_box.clone(),
-        directions_container.clone(),
-        use_miles.clone(),
-    );
+    // Remove search pins only; the route and its directions stay
+    {
+        let webview = webview.clone();
+        clear_search_item.connect_clicked(move |_| {
+            println!("🧹 Clearing search markers...");
+            webview.evaluate_javascript(
+                "if (window.clearSearchMarkers) { window.clearSearchMarkers(); }",
+                None,
+                None,
+                webkit2gtk::gio::Cancellable::NONE,
+                |_| {}
+            );
+        });
+    }
     
-    // Add Escape key handler to clear map
+    // Remove user overlays only; Clear leaves them on the map
     {
         let webview = webview.clone();
-        let directions_box = directions_box.clone();
-        let directions_container = directions_container.clone();
-        window.connect_key_press_event(move |_, event_key| {
-            if event_key.keyval() == gtk::gdk::keys::constants::Escape {
-                println!("🧹 Escape pressed - clearing map...");
-                let js_code = "if (window.clearMap) { window.clearMap(); }";
+        clear_overlays_item.connect_clicked(move |_| {
+            println!("🧹 Clearing overlays...");
+            clear_overlays(&webview);
+        });
+    }
+    
+    // Draw a GeoJSON file over the map; files that are not GeoJSON are reported, not drawn
+    {
+        let webview = webview.clone();
+        load_overlay_button.connect_clicked(move |button| {
+            let Some(path) = choose_file(button, "Load Overlay", FileChooserAction::Open, None) else {
+                return;
+            };
+            
+            let loaded = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| add_geojson_overlay(&webview, &json, OverlayStyle::default()).map_err(|e| e.to_string()));
+            match loaded {
+                Ok(()) => println!("🗂️ Loaded overlay from {}", path.display()),
+                Err(e) => {
+                    println!("❌ Failed to load overlay {}: {}", path.display(), e);
+                    show_error_dialog(button, "Could not load overlay", &e);
+                }
+            }
+        });
+    }
+    
+    // Open a GeoJSON route: draw its line, then place its stops and plan directions along them
+    {
+        let webview = webview.clone();
+        let profile_combo = profile_combo.clone();
+        open_route_button.connect_clicked(move |button| {
+            let Some(path) = choose_file(button, "Open Route", FileChooserAction::Open, None) else {
+                return;
+            };
+            
+            let loaded = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| load_route_geojson(&json).map_err(|e| e.to_string()));
+            let (waypoints, geometry) = match loaded {
+                Ok(route) => route,
+                Err(e) => {
+                    println!("❌ Failed to open {}: {}", path.display(), e);
+                    show_error_dialog(button, "Could not open route", &e);
+                    return;
+                }
+            };
+            println!("📂 Opened route with {} stops from {}", waypoints.len(), path.display());
+            
+            let profile = profile_combo
+                .active_id()
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| "driving".to_string());
+            let js_code = format!(
+                "if (window.mapInstance) {{ \
+                    if (window.clearMap) {{ window.clearMap(); }} \
+                    window.loadedRouteLayer = L.geoJSON({}, {{ style: {{ color: '#555555', weight: 3, dashArray: '6 6' }} }}) \
+                        .addTo(window.mapInstance); \
+                    window.mapInstance.fitBounds(window.loadedRouteLayer.getBounds(), {{ padding: [{}, {}] }}); \
+                }} {} {}",
+                geometry,
+                FIT_PADDING_PX,
+                FIT_PADDING_PX,
+                click_points_js(&waypoints),
+                route_request_js(&profile, &[], false)
+            );
+            webview.evaluate_javascript(
+                &js_code,
+                None,
+                None,
+                webkit2gtk::gio::Cancellable::NONE,
+                |_| {}
+            );
+        });
+    }
+    
+    // Route from pasted addresses: geocode every line in order, report the ones not found,
+    // then place the rest as stops and plan the route through them
+    {
+        let webview = webview.clone();
+        let routing_service = routing_service.clone();
+        let profile_combo = profile_combo.clone();
+        let route_button = route_button.clone();
+        let busy = busy.clone();
+        route_addresses_item.connect_clicked(move |_| {
+            let Some(addresses) = ask_for_addresses(&route_button) else {
+                return;
+            };
+            if addresses.len() < 2 {
+                show_error_dialog(&route_button, "Not enough addresses", "Enter at least two addresses, one per line.");
+                return;
+            }
+            
+            let profile = profile_combo
+                .active_id()
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| "driving".to_string());
+            println!("📋 Geocoding {} addresses for a {} route...", addresses.len(), profile);
+            
+            let webview = webview.clone();
+            let routing_service = routing_service.clone();
+            let route_button = route_button.clone();
+            let busy = busy.clone();
+            glib::spawn_future_local(async move {
+                let busy_guard = busy.begin(std::slice::from_ref(&route_button));
+                let results = routing_service.geocode_batch(&addresses).await;
+                drop(busy_guard);
+                
+                let mut waypoints = Vec::new();
+                let mut failed = Vec::new();
+                for (address, result) in addresses.iter().zip(results) {
+                    match result.map(|places| places.into_iter().next()) {
+                        Ok(Some(place)) => waypoints.push(Waypoint {
+                            lat: place.location.latitude,
+                            lng: place.location.longitude,
+                            name: Some(address.clone()),
+                            kind: WaypointKind::Stop,
+                        }),
+                        Ok(None) => failed.push(format!("{}: no match", address)),
+                        Err(e) => failed.push(format!("{}: {}", address, e)),
+                    }
+                }
+                
+                if !failed.is_empty() {
+                    println!("⚠️ {} of {} addresses not found", failed.len(), addresses.len());
+                    let title = if waypoints.len() < 2 { "Could not plan route" } else { "Some addresses were skipped" };
+                    show_error_dialog(&route_button, title, &failed.join("\n"));
+                }
+                if waypoints.len() < 2 {
+                    return;
+                }
+                
+                // Same path as clicked points, so the route is drawn and directions shown as usual
+                let js_code = format!(
+                    "if (window.clearMap) {{ window.clearMap(); }} {} {}",
+                    click_points_js(&waypoints),
+                    route_request_js(&profile, &[], false)
+                );
                 webview.evaluate_javascript(
-                    js_code,
+                    &js_code,
                     None,
                     None,
                     webkit2gtk::gio::Cancellable::NONE,
                     |_| {}
                 );
-                
-                // Clear directions pane and hide it
-                let children: Vec<gtk::Widget> = directions_box.children();
-                for child in children {
-                    directions_box.remove(&child);
-                }
-                
-                let directions_label = Label::new(Some("Click 'Plan Route' to see turn-by-turn directions"));
-                directions_label.set_line_wrap(true);
-                directions_label.set_xalign(0.0);
-                directions_box.pack_start(&directions_label, false, false, 0);
-                directions_box.show_all();
-                
-                // Hide directions pane
-                directions_container.set_visible(false);
-                
-                return glib::Propagation::Stop;
-            }
-            glib::Propagation::Proceed
+            });
         });
     }
     
-    window.show_all();
+    // Export the visible map as an image
+    {
+        let webview = webview.clone();
+        export_image_button.connect_clicked(move |_| export_map_image(&webview));
+    }
+    
+    // Load the HTML map
+    let map_html_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src").join("map.html");
+    load_map_html(&webview, default_map_source(map_html_path).as_ref());
+    
+    // Apply the command-line options once the page is ready: center or search, then reopen
+    // a shared route by placing its points as if clicked and planning it
+    if let Some(SharedLink::Route(_, profile)) = &cli.link {
+        profile_combo.set_active_id(Some(profile));
+    }
+    {
+        let location_entry = location_entry.clone();
+        let search_button = search_button.clone();
+        let pending = RefCell::new(Some(cli));
+        webview.connect_load_changed(move |webview, event| {
+            if event != webkit2gtk::LoadEvent::Finished {
+                return;
+            }
+            let Some(cli) = pending.borrow_mut().take() else {
+                return;
+            };
+            
+            let mut js_code = String::new();
+            let view = match &cli.link {
+                Some(SharedLink::View(view)) => Some(view),
+                _ => None,
+            };
+            match (&cli.center, view, cli.zoom) {
+                (Some(center), _, zoom) => js_code.push_str(&format!(
+                    "if (window.mapInstance) {{ window.mapInstance.setView([{}, {}], {}); }}",
+                    center.latitude, center.longitude, zoom.unwrap_or(13)
+                )),
+                (None, Some(view), zoom) => {
+                    println!("🔗 Opening shared view at {:.6}, {:.6}", view.lat, view.lng);
+                    js_code.push_str(&format!(
+                        "if (window.mapInstance) {{ window.mapInstance.setView([{}, {}], {}); }}",
+                        view.lat, view.lng, zoom.or(view.zoom).unwrap_or(13)
+                    ));
+                }
+                (None, None, Some(zoom)) => js_code.push_str(&format!(
+                    "if (window.mapInstance) {{ window.mapInstance.setZoom({}); }}",
+                    zoom
+                )),
+                (None, None, None) => {}
+            }
+            
+            if let Some(SharedLink::Route(waypoints, profile)) = &cli.link {
+                println!("🔗 Opening shared route with {} points", waypoints.len());
+                js_code.push_str(&click_points_js(waypoints));
+                js_code.push_str(&route_request_js(profile, &[], false));
+            }
+            
+            if !js_code.is_empty() {
+                webview.evaluate_javascript(
+                    &js_code,
+                    None,
+                    None,
+                    webkit2gtk::gio::Cancellable::NONE,
+                    |_| {}
+                );
+            }
+            
+            // Searching goes through the search button so results show as usual
+            if let Some(query) = &cli.search {
+                location_entry.set_text(query);
+                search_button.clicked();
+            }
+        });
+    }
+    
+    // Content area with map, directions pane and waypoints sidebar
+    let content_box = GtkBox::new(Orientation::Horizontal, 0);
+    
+    // The scale bar floats over the map without taking its clicks
+    let map_overlay = gtk::Overlay::new();
+    map_overlay.add(&webview);
+    map_overlay.add_overlay(&scale_bar.area);
+    map_overlay.set_overlay_pass_through(&scale_bar.area, true);
+    
+    content_box.pack_start(&directions_container, false, false, 0);
+    content_box.pack_start(&map_overlay, true, true, 0);
+    content_box.pack_start(&waypoint_panel.container, false, false, 0);
+    
+    main_box.pack_start(&controls_box, false, false, 0);
+    main_box.pack_start(&content_box, true, true, 0);
+    
+    window.add(&main_box);
+    
+    // The search result currently shown on the map, if any
+    let selected_result: Arc<Mutex<Option<GeocodeResult>>> = Arc::new(Mutex::new(None));
+    
+    setup_favorites(
+        favorite_button,
+        favorites_menu_button,
+        selected_result.clone(),
+        webview.clone(),
+    );
+    
+    let geo_service_follow = geo_service.clone();
+    let geo_service_heading = geo_service.clone();
+    let geo_service_recenter = geo_service.clone();
+    let location_button_recenter = location_button.clone();
+    
+    // Set up event handlers
+    setup_event_handlers(
+        geo_service,
+        routing_service,
+        location_entry,
+        search_button,
+        directions_button,
+        location_button,
+        route_button,
+        optimize_button,
+        profile_combo,
+        vec![(avoid_motorways_check, "motorway"), (avoid_tolls_check, "toll")],
+        clear_button,
+        directions_toggle,
+        import_gpx_button,
+        webview.clone(),
+        directions_box.clone(),
+        directions_container.clone(),
+        use_miles.clone(),
+        search_countries,
+        selected_result,
+        busy,
+    );
+    
+    // Measure mode: map clicks draw a dashed line instead of placing route points
+    {
+        let webview = webview.clone();
+        let measure_label = measure_label.clone();
+        measure_toggle.connect_toggled(move |toggle| {
+            let js_code = if toggle.is_active() {
+                println!("📐 Entering measure mode");
+                measure_label.set_text("Distance: 0");
+                measure_label.set_visible(true);
+                "if (window.startMeasure) { window.startMeasure(); }"
+            } else {
+                println!("📐 Leaving measure mode");
+                measure_label.set_visible(false);
+                "if (window.stopMeasure) { window.stopMeasure(); }"
+            };
+            webview.evaluate_javascript(
+                js_code,
+                None,
+                None,
+                webkit2gtk::gio::Cancellable::NONE,
+                |_| {}
+            );
+        });
+    }
+    
+    // Look up the position once at startup so My Location has a fix to work from
+    {
+        let geo_service = geo_service_recenter.clone();
+        let location_button = location_button_recenter.clone();
+        glib::spawn_future_local(async move {
+            match geolocation::detect_current().await {
+                Ok(location) => geo_service.lock().unwrap().update_location(location),
+                Err(e) => println!("⚠️ Location detection failed ({}), press Ctrl+Home to retry", e),
+            }
+            update_location_button(&location_button, &geo_service);
+        });
+    }
+    
+    // Heading-up turns the map to the travel direction so far; follow mode keeps it turned
+    // as the position updates. North-up undoes the rotation.
+    {
+        let geo_service = geo_service_heading;
+        let webview = webview.clone();
+        heading_toggle.connect_toggled(move |toggle| {
+            if toggle.is_active() {
+                let heading = geo_service.lock().unwrap().heading(HEADING_SMOOTHING, HEADING_MIN_MOVE_M);
+                match heading {
+                    Some(heading) => {
+                        println!("🧭 Heading up ({:.0}°)", heading);
+                        set_map_rotation(&webview, Some(heading));
+                    }
+                    None => println!("🧭 Heading up once you start moving"),
+                }
+            } else {
+                println!("🧭 North up");
+                set_map_rotation(&webview, None);
+            }
+        });
+    }
+    
+    // Follow mode: keep polling the position while the toggle is on. The handle of the
+    // polling task is kept so it can be aborted, timer included, when toggled off.
+    {
+        let geo_service = geo_service_follow;
+        let webview = webview.clone();
+        let location_button = location_button_recenter.clone();
+        let app = app.clone();
+        // Destination already announced, so standing at it doesn't notify again
+        let announced: Rc<Cell<Option<(f64, f64)>>> = Rc::new(Cell::new(None));
+        let use_miles = use_miles.clone();
+        let follow_task: Rc<RefCell<Option<glib::JoinHandle<()>>>> = Rc::new(RefCell::new(None));
+        follow_toggle.connect_toggled(move |toggle| {
+            let mut task = follow_task.borrow_mut();
+            if toggle.is_active() {
+                if task.is_some() {
+                    return;
+                }
+                println!("🧭 Following current location");
+                let geo_service = geo_service.clone();
+                let webview = webview.clone();
+                let location_button = location_button.clone();
+                let last_route = last_route.clone();
+                let app = app.clone();
+                let announced = announced.clone();
+                let toggle = toggle.clone();
+                let speed_label = speed_label.clone();
+                let use_miles = use_miles.clone();
+                let heading_toggle = heading_toggle.clone();
+                *task = Some(glib::spawn_future_local(async move {
+                    loop {
+                        let Some(fix) = follow_current_location(&geo_service, &webview).await else {
+                            glib::timeout_future(FOLLOW_INTERVAL).await;
+                            continue;
+                        };
+                        update_location_button(&location_button, &geo_service);
+                        
+                        let speed = geo_service.lock().unwrap().smoothed_speed(FOLLOW_SPEED_SMOOTHING);
+                        if let Some(speed) = speed {
+                            speed_label.set_text(&format_speed(speed, *use_miles.lock().unwrap()));
+                            speed_label.set_visible(true);
+                        }
+                        
+                        if heading_toggle.is_active()
+                            && let Some(heading) = geo_service.lock().unwrap().heading(HEADING_SMOOTHING, HEADING_MIN_MOVE_M)
+                        {
+                            set_map_rotation(&webview, Some(heading));
+                        }
+                        
+                        // Point the arrow at the next maneuver on the route shown
+                        if let Some(planned) = last_route.borrow().as_ref() {
+                            let route = &planned.routes[planned.selected.get()];
+                            if let Some(next) = route.upcoming_instruction(&fix, STEP_REACHED_M) {
+                                show_maneuver_arrow(&webview, route.instructions.get(next));
+                            }
+                        }
+                        
+                        let destination = last_route
+                            .borrow()
+                            .as_ref()
+                            .and_then(|planned| planned.waypoints.last().cloned());
+                        if let Some(destination) = destination {
+                            let target = Location::new(destination.lat, destination.lng);
+                            let distance = fix.distance_to(&target);
+                            let key = Some((destination.lat, destination.lng));
+                            if distance > arrival_radius {
+                                announced.set(None);
+                            } else if announced.get() != key {
+                                announced.set(key);
+                                notify_arrival(&app, destination.name.as_deref());
+                                // Untoggling aborts this task, so leave that until it has returned
+                                glib::idle_add_local_once(move || toggle.set_active(false));
+                                break;
+                            }
+                        }
+                        
+                        glib::timeout_future(FOLLOW_INTERVAL).await;
+                    }
+                }));
+            } else if let Some(handle) = task.take() {
+                println!("🧭 Stopped following current location");
+                handle.abort();
+                speed_label.set_visible(false);
+            }
+        });
+    }
+    
+    // Undo the last placed point; the page reports the new marker count back
+    {
+        let webview = webview.clone();
+        undo_button.connect_clicked(move |_| {
+            let js_code = "if (window.removeLastMarker) { window.removeLastMarker(); }";
+            webview.evaluate_javascript(
+                js_code,
+                None,
+                None,
+                webkit2gtk::gio::Cancellable::NONE,
+                |_| {}
+            );
+        });
+    }
+    
+    // Add Escape key handler to clear map
+    {
+        let webview = webview.clone();
+        let directions_box = directions_box.clone();
+        let directions_container = directions_container.clone();
+        window.connect_key_press_event(move |_, event_key| {
+            if event_key.keyval() == gtk::gdk::keys::constants::Escape {
+                println!("🧹 Escape pressed - clearing map...");
+                let js_code = "if (window.clearMap) { window.clearMap(); }";
+                webview.evaluate_javascript(
+                    js_code,
+                    None,
+                    None,
+                    webkit2gtk::gio::Cancellable::NONE,
+                    |_| {}
+                );
+                
+                // Clear directions pane and hide it
+                reset_directions(&directions_box);
+                
+                // Hide directions pane
+                directions_container.set_visible(false);
+                
+                return glib::Propagation::Stop;
+            }
+            glib::Propagation::Proceed
+        });
+    }
+    
+    // Ctrl+Home recenters on the last known position without re-detecting it
+    {
+        let webview = webview.clone();
+        let geo_service = geo_service_recenter;
+        let location_button = location_button_recenter;
+        window.connect_key_press_event(move |_, event_key| {
+            let ctrl = event_key.state().contains(gtk::gdk::ModifierType::CONTROL_MASK);
+            if !ctrl || event_key.keyval() != gtk::gdk::keys::constants::Home {
+                return glib::Propagation::Proceed;
+            }
+            
+            let last_fix = geo_service.lock().unwrap().get_current_location().cloned();
+            match last_fix {
+                Some(location) => {
+                    println!("🎯 Recentering on {:.6}, {:.6}", location.latitude, location.longitude);
+                    show_current_location(&webview, &location);
+                }
+                None => {
+                    println!("📍 No location fix yet, detecting...");
+                    let geo_service = geo_service.clone();
+                    let webview = webview.clone();
+                    let location_button = location_button.clone();
+                    glib::spawn_future_local(async move {
+                        refresh_current_location(&geo_service, &webview).await;
+                        update_location_button(&location_button, &geo_service);
+                    });
+                }
+            }
+            glib::Propagation::Stop
+        });
+    }
+    
+    window.show_all();
     
-    // Hide directions pane by default (after show_all)
+    // Hide directions pane and waypoints sidebar by default (after show_all)
     directions_container.set_visible(false);
+    waypoint_panel.container.set_visible(false);
+}
+
+fn apply_theme(dark_mode: bool) {
+    if let Some(settings) = Settings::default() {
+        settings.set_gtk_application_prefer_dark_theme(dark_mode);
+        settings.set_gtk_theme_name(Some("Adwaita"));
+    }
+}
+
+// Builds the script defining window.tileLayerDefinitions, the initial layer id and
+// whether clicked points are snapped to roads
+fn page_settings_script(settings: &AppSettings) -> String {
+    let definitions: serde_json::Map<String, serde_json::Value> = TILE_LAYERS
+        .iter()
+        .map(|layer| {
+            (layer.id.to_string(), serde_json::json!({
+                "url": layer.url,
+                "attribution": layer.attribution,
+                "maxZoom": layer.max_zoom,
+            }))
+        })
+        .collect();
+    
+    let selected = settings.tile_layer.as_str();
+    let selected = if TILE_LAYERS.iter().any(|layer| layer.id == selected) { selected } else { TILE_LAYERS[0].id };
+    
+    format!(
+        "window.tileLayerDefinitions = {};\nwindow.initialTileLayer = {};\nwindow.snapToRoads = {};\nwindow.maxSearchMarkers = {};\nwindow.clusterSearchMarkers = {};",
+        serde_json::Value::Object(definitions),
+        js_string_literal(selected),
+        settings.snap_to_road,
+        settings.max_search_markers.max(1),
+        settings.cluster_search_markers
+    )
+}
+
+fn setup_tile_menu(tile_box: &GtkBox, webview: WebView, app_settings: Arc<Mutex<AppSettings>>) {
+    let selected = app_settings.lock().unwrap().tile_layer.clone();
+    let mut group: Option<RadioButton> = None;
+    
+    for layer in TILE_LAYERS {
+        let radio = match &group {
+            Some(first) => RadioButton::with_label_from_widget(first, layer.label),
+            None => RadioButton::with_label(layer.label),
+        };
+        radio.set_active(layer.id == selected);
+        
+        let webview = webview.clone();
+        let app_settings = app_settings.clone();
+        radio.connect_toggled(move |radio| {
+            if !radio.is_active() {
+                return;
+            }
+            
+            println!("🗺️ Switching map layer to {}", layer.label);
+            let js_code = format!("if (window.setTileLayer) {{ window.setTileLayer({}); }}", js_string_literal(layer.id));
+            webview.evaluate_javascript(
+                &js_code,
+                None,
+                None,
+                webkit2gtk::gio::Cancellable::NONE,
+                |_| {}
+            );
+            
+            let mut settings = app_settings.lock().unwrap();
+            settings.tile_layer = layer.id.to_string();
+            if let Err(e) = settings.save() {
+                println!("❌ Failed to save settings: {}", e);
+            }
+        });
+        
+        tile_box.pack_start(&radio, false, false, 0);
+        group.get_or_insert(radio);
+    }
+    
+    tile_box.show_all();
 }
 
 fn setup_webview(
@@ -293,7 +1398,15 @@ fn setup_webview(
     routing_service: Arc<RoutingService>,
     directions_box: GtkBox,
     directions_container: GtkBox,
+    measure_label: Label,
     use_miles: Arc<Mutex<bool>>,
+    language: Arc<Mutex<Language>>,
+    raw_instructions: Arc<Mutex<bool>>,
+    busy: BusyIndicator,
+    route_buttons: Vec<Button>,
+    waypoint_panel: WaypointPanel,
+    scale_bar: ScaleBar,
+    last_route: Rc<RefCell<Option<Rc<PlannedRoute>>>>,
 ) {
     // Inject JavaScript for Rust communication
     let init_script = UserScript::new(
@@ -301,10 +1414,8 @@ fn setup_webview(
         window.rustBackend = {
             sendMessage: function(type, data) {
                 if (window.webkit && window.webkit.messageHandlers && window.webkit.messageHandlers.rustHandler) {
-                    window.webkit.messageHandlers.rustHandler.postMessage({
-                        type: type,
-                        data: data
-                    });
+                    // Same flat JSON string shape as the page's own messages
+                    window.webkit.messageHandlers.rustHandler.postMessage(JSON.stringify(Object.assign({}, data, {type: type})));
                 }
             },
             
@@ -335,169 +1446,245 @@ fn setup_webview(
     let directions_box_clone = directions_box.clone();
     let directions_container_clone = directions_container.clone();
     let use_miles_clone = use_miles.clone();
+    let route_generation = RequestGeneration::new();
+    
+    // Asks for a stop's name after a double click on the map
+    let name_popover = StopNamePopover::new(webview);
+    
+    // Route points on the map as of the last markers_changed message
+    let marker_count = Rc::new(Cell::new(0u64));
+    // Center and zoom as of the last viewport_changed message, for view links
+    let current_view: Rc<Cell<Option<MapView>>> = Rc::new(Cell::new(None));
+    
+    // Actions offered on right-click
+    let context_menu = MapContextMenu::new(webview, &route_buttons[0], last_route.clone(), marker_count.clone(), current_view.clone(), routing_service.clone(), busy.clone(), use_miles.clone());
     
     user_content_manager.connect_script_message_received(Some("rustHandler"), move |_, msg: &webkit2gtk::JavascriptResult| {
         // Convert to string and try to parse as JSON
         let js_string = msg.js_value().map(|v| v.to_string()).unwrap_or_default();
         println!("Received message string from JS: '{}'", js_string);
         
-        // Try to parse as JSON directly
-        if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&js_string) {
-            if let Some(msg_type) = parsed.get("type").and_then(|v| v.as_str()) {
-                println!("Message type: {}", msg_type);
-                match msg_type {
-                    "calculate_route" => {
-                        if let Some(waypoints_json) = parsed.get("waypoints") {
-                            let routing_service = routing_service_clone.clone();
-                            let webview = webview_clone.clone();
-                            let directions_box = directions_box_clone.clone();
-                            let directions_container = directions_container_clone.clone();
-                            let use_miles = use_miles_clone.clone();
-                            
-                            println!("Parsing waypoints: {:?}", waypoints_json);
-                            
-                            // Parse waypoints
-                            if let Ok(waypoints_data) = serde_json::from_value::<Vec<serde_json::Value>>(waypoints_json.clone()) {
-                                let waypoints: Vec<Waypoint> = waypoints_data
-                                    .iter()
+        let message = match message::parse(&js_string) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("❌ Ignoring message from JS: {}", e);
+                return;
+            }
+        };
+        
+        match message {
+            IncomingMessage::CalculateRoute { waypoints, profile, exclude, optimize } => {
+                let waypoints = message::numbered_waypoints(&waypoints);
+                if waypoints.len() < 2 {
+                    println!("❌ Need at least 2 waypoints, got {}", waypoints.len());
+                    return;
+                }
+                
+                let routing_service = routing_service_clone.clone();
+                let webview = webview_clone.clone();
+                let directions_box = directions_box_clone.clone();
+                let directions_container = directions_container_clone.clone();
+                let use_miles = use_miles_clone.clone();
+                let language = language.clone();
+                let raw_instructions = raw_instructions.clone();
+                let last_route = last_route.clone();
+                println!("🛣️ Calculating {} route for {} waypoints", profile, waypoints.len());
+                
+                // Planning again supersedes a route still being calculated
+                let ticket = route_generation.begin();
+                let busy_guard = busy.begin(&route_buttons);
+                
+                glib::spawn_future_local(async move {
+                    let _busy_guard = busy_guard;
+                    let use_miles_val = *use_miles.lock().unwrap();
+                    let language_val = *language.lock().unwrap();
+                    let raw_instructions_val = *raw_instructions.lock().unwrap();
+                    
+                    // Visit the stops in the shortest order, moving the markers to match
+                    let waypoints = if optimize {
+                        let Some(optimized) = ticket.run(routing_service.optimize_route(&waypoints)).await else {
+                            println!("⏭️ Route request superseded by a newer one");
+                            return;
+                        };
+                        match optimized {
+                            Ok(ordered) => {
+                                println!("🔀 Optimized stop order");
+                                show_waypoint_order(&webview, &ordered);
+                                ordered
+                                    .into_iter()
                                     .enumerate()
-                                    .filter_map(|(i, wp)| {
-                                        if let (Some(lat), Some(lng)) = (
-                                            wp.get("lat").and_then(|v| v.as_f64()),
-                                            wp.get("lng").and_then(|v| v.as_f64())
-                                        ) {
-                                            Some(Waypoint {
-                                                lat,
-                                                lng,
-                                                name: Some(format!("Point {}", i + 1)),
-                                            })
-                                        } else {
-                                            None
-                                        }
+                                    .map(|(i, wp)| match wp.name.as_deref() {
+                                        Some(name) if !message::is_placeholder_name(name) => wp,
+                                        _ => Waypoint { name: Some(format!("Point {}", i + 1)), ..wp },
                                     })
-                                    .collect();
-                                
-                                println!("Parsed {} waypoints", waypoints.len());
+                                    .collect()
+                            }
+                            Err(e) => {
+                                println!("❌ Could not optimize stop order: {}", e);
+                                waypoints
+                            }
+                        }
+                    } else {
+                        waypoints
+                    };
+                    
+                    let request = RouteRequest { waypoints, profile: profile.clone(), exclude, depart_at: None };
+                    match ticket.run(routing_service.calculate_routes(&request, GeometryFormat::default(), use_miles_val, language_val, raw_instructions_val)).await {
+                        None => println!("⏭️ Route request superseded by a newer one"),
+                        Some(Ok(routes)) => {
+                            println!("✅ Found {} route option(s)", routes.len());
+                            
+                            // Update directions UI on the main thread
+                            let planned = Rc::new(PlannedRoute { waypoints: request.waypoints, profile, routes, selected: Cell::new(0), language: language_val });
+                            *last_route.borrow_mut() = Some(planned.clone());
+                            let webview = webview.clone();
+                            let routing_service = routing_service.clone();
+                            let directions_box_weak = directions_box.downgrade();
+                            let directions_container_weak = directions_container.downgrade();
+                            glib::idle_add_local_once(move || {
+                                // Auto-show directions pane when route is calculated
+                                if let Some(directions_container) = directions_container_weak.upgrade() {
+                                    directions_container.set_visible(true);
+                                }
                                 
-                                if waypoints.len() >= 2 {
-                                    println!("🛣️ Calculating route for {} waypoints", waypoints.len());
-                                    
-                                    glib::spawn_future_local(async move {
-                                        let use_miles_val = *use_miles.lock().unwrap();
-                                        match routing_service.calculate_route(&waypoints, use_miles_val).await {
-                                            Ok(route) => {
-                                                let distance_text = if use_miles_val {
-                                                    let miles = route.distance * 0.000621371;
-                                                    format!("{:.1} mi", miles)
-                                                } else {
-                                                    format!("{:.1} km", route.distance / 1000.0)
-                                                };
-                                                
-                                                println!("✅ Route: {}, {:.0}min", 
-                                                       distance_text, route.duration / 60.0);
-                                                
-                                                // Update directions UI on the main thread
-                                                let route_clone = route.clone();
-                                                let directions_box_weak = directions_box.downgrade();
-                                                let directions_container_weak = directions_container.downgrade();
-                                                let duration_minutes = route.duration / 60.0;
-                                                let hours = (duration_minutes / 60.0) as u32;
-                                                let minutes = (duration_minutes % 60.0) as u32;
-                                                let time_text = if hours > 0 {
-                                                    format!("{} hr {} min", hours, minutes)
-                                                } else {
-                                                    format!("{} min", minutes)
-                                                };
-                                                let summary_text = format!("Route: {}, {}", distance_text, time_text);
-                                                glib::idle_add_local_once(move || {
-                                                    // Auto-show directions pane when route is calculated
-                                                    if let Some(directions_container) = directions_container_weak.upgrade() {
-                                                        directions_container.set_visible(true);
-                                                    }
-                                                    
-                                                    if let Some(directions_box) = directions_box_weak.upgrade() {
-                                                        // Clear existing directions
-                                                        let children: Vec<gtk::Widget> = directions_box.children();
-                                                        for child in children {
-                                                            directions_box.remove(&child);
-                                                        }
-                                                        
-                                                        // Add route summary
-                                                        let summary_label = Label::new(Some(&summary_text));
-                                                        summary_label.set_line_wrap(true);
-                                                        summary_label.set_xalign(0.0);
-                                                        summary_label.set_markup(&format!("<b>{}</b>", summary_text));
-                                                        directions_box.pack_start(&summary_label, false, false, 0);
-                                                        
-                                                        // Add separator
-                                                        let separator = gtk::Separator::new(Orientation::Horizontal);
-                                                        directions_box.pack_start(&separator, false, false, 5);
-                                                        
-                                                        // Add turn-by-turn directions
-                                                        for (i, instruction) in route_clone.instructions.iter().enumerate() {
-                                                            let direction_label = Label::new(Some(&format!(
-                                                                "{}. {}",
-                                                                i + 1,
-                                                                instruction.text
-                                                            )));
-                                                            direction_label.set_line_wrap(true);
-                                                            direction_label.set_xalign(0.0);
-                                                            direction_label.set_margin_bottom(5);
-                                                            directions_box.pack_start(&direction_label, false, false, 0);
-                                                        }
-                                                        
-                                                        directions_box.show_all();
-                                                    }
-                                                });
-                                                
-                                                // Send route to map
-                                                let js_code = format!(
-                                                    "if (window.mapInstance && window.addRouteToMap) {{ \
-                                                        window.addRouteToMap('{}'); \
-                                                    }}",
-                                                    route.geometry.replace("'", "\\'")
-                                                );
-                                                
-                                                webview.evaluate_javascript(
-                                                    &js_code,
-                                                    None,
-                                                    None,
-                                                    webkit2gtk::gio::Cancellable::NONE,
-                                                    |_| {}
-                                                );
-                                            }
-                                            Err(e) => {
-                                                println!("❌ Route error: {}", e);
-                                                let js_code = format!("alert('Route calculation failed: {}');", e);
-                                                webview.evaluate_javascript(
-                                                    &js_code,
-                                                    None,
-                                                    None,
-                                                    webkit2gtk::gio::Cancellable::NONE,
-                                                    |_| {}
-                                                );
-                                            }
-                                        }
-                                    });
-                                } else {
-                                    println!("❌ Need at least 2 waypoints, got {}", waypoints.len());
+                                if let Some(directions_box) = directions_box_weak.upgrade() {
+                                    show_route(&webview, &directions_box, &routing_service, planned, 0, use_miles_val);
                                 }
-                            } else {
-                                println!("❌ Failed to parse waypoints JSON");
+                            });
+                        }
+                        Some(Err(e)) => {
+                            println!("❌ Route error: {}", e);
+                            if e.is_timeout() {
+                                show_error_dialog(&webview, "Request timed out", "The routing server did not answer in time. Please try again.");
+                                return;
                             }
-                        } else {
-                            println!("❌ No waypoints found in message");
+                            let message = match &e {
+                                RoutingError::NoRoute => "No route exists between these points.".to_string(),
+                                RoutingError::NoSegment => format!("{}. Drag that waypoint onto or closer to a road and try again.", e),
+                                RoutingError::DuplicateWaypoint(..) => format!("{} in the Waypoints list.", e),
+                                e if e.is_transient() => format!("Could not reach the routing server ({}). Please try again.", e),
+                                e => format!("Route calculation failed: {}", e),
+                            };
+                            show_error_dialog(&webview, "Could not plan route", &message);
                         }
                     }
-                    _ => {
-                        println!("Unknown message type: {}", msg_type);
+                });
+            }
+            IncomingMessage::SnapPoint { id: marker_id, lat, lng } => {
+                let routing_service = routing_service_clone.clone();
+                let webview = webview_clone.clone();
+                let use_miles_val = *use_miles_clone.lock().unwrap();
+                
+                glib::spawn_future_local(async move {
+                    match routing_service.snap_to_road(lat, lng).await {
+                        Ok(snapped) => {
+                            let moved = Location::new(lat, lng).distance_to(&snapped);
+                            println!("🧲 Snapped point {} by {:.0} m", marker_id, moved);
+                            
+                            let note = format!("Snapped to road ({} from where you clicked)", format_distance(moved, use_miles_val));
+                            let js_code = format!(
+                                "if (window.snapMarker) {{ window.snapMarker({}, {}, {}, {}); }}",
+                                marker_id, snapped.latitude, snapped.longitude, js_string_literal(&note)
+                            );
+                            webview.evaluate_javascript(
+                                &js_code,
+                                None,
+                                None,
+                                webkit2gtk::gio::Cancellable::NONE,
+                                |_| {}
+                            );
+                        }
+                        Err(e) => println!("❌ Could not snap point to road: {}", e),
                     }
+                });
+            }
+            IncomingMessage::ReverseGeocode { id: marker_id, lat, lng } => {
+                let routing_service = routing_service_clone.clone();
+                let webview = webview_clone.clone();
+                
+                // Every marker gets its own lookup; the geocoder's rate limiter queues bursts of clicks
+                glib::spawn_future_local(async move {
+                    // Failed lookups still label the marker, with its coordinates
+                    let label = match routing_service.reverse_geocode(lat, lng).await {
+                        Ok(address) => {
+                            println!("🏷️ Point {} is at {}", marker_id, address);
+                            address
+                        }
+                        Err(e) => {
+                            println!("⚠️ Reverse geocoding failed: {}", e);
+                            format!("{:.6}, {:.6}", lat, lng)
+                        }
+                    };
+                    let js_code = format!(
+                        "if (window.labelMarker) {{ window.labelMarker({}, {}); }}",
+                        marker_id, js_string_literal(&label)
+                    );
+                    webview.evaluate_javascript(
+                        &js_code,
+                        None,
+                        None,
+                        webkit2gtk::gio::Cancellable::NONE,
+                        |_| {}
+                    );
+                });
+            }
+            IncomingMessage::Measure { points } => {
+                let points: Vec<Location> = points.into_iter().map(Location::from).collect();
+                let total: f64 = points.windows(2).map(|pair| pair[0].distance_to(&pair[1])).sum();
+                let use_miles_val = *use_miles_clone.lock().unwrap();
+                println!("📐 Measured {:.0} m over {} points", total, points.len());
+                measure_label.set_text(&format!("Distance: {}", format_distance_rounded(total, use_miles_val, units::ShortImperialUnit::Feet, &DistanceRounding::PRECISE)));
+            }
+            IncomingMessage::MarkersChanged { count, markers } => {
+                println!("📍 {} route points on the map", count);
+                marker_count.set(count);
+                waypoint_panel.show(&webview_clone, &markers, &route_buttons[0]);
+                
+                // A route needs two points, so the old directions no longer apply
+                if count < 2 {
+                    reset_directions(&directions_box_clone);
+                    last_route.borrow_mut().take();
                 }
-            } else {
-                println!("❌ No message type found in JSON");
             }
-        } else {
-            println!("❌ Failed to parse JSON: '{}'", js_string);
+            // The page keeps the route points and labels them through reverse_geocode
+            // messages, so a click is only logged here
+            IncomingMessage::LocationClick { lat, lng, geocode } => {
+                println!("📍 Map clicked at {:.6}, {:.6}", lat, lng);
+                
+                if geocode {
+                    let routing_service = routing_service_clone.clone();
+                    glib::spawn_future_local(async move {
+                        match routing_service.reverse_geocode(lat, lng).await {
+                            Ok(address) => println!("🏷️ Clicked point is at {}", address),
+                            Err(e) => println!("⚠️ Reverse geocoding failed: {}", e),
+                        }
+                    });
+                }
+            }
+            IncomingMessage::ViewportChanged { lat, lng, zoom } => {
+                scale_bar.set_view(lat, zoom, *use_miles_clone.lock().unwrap());
+                current_view.set(Some(MapView { lat, lng, zoom: Some(zoom.round().clamp(0.0, MAX_VIEW_ZOOM as f64) as u8) }));
+            }
+            IncomingMessage::NameWaypoint { lat, lng, x, y } => {
+                println!("✏️ Naming a stop at {:.6}, {:.6}", lat, lng);
+                name_popover.ask(lat, lng, x, y);
+            }
+            IncomingMessage::InsertWaypoint { lat, lng } => {
+                let Some(planned) = last_route.borrow().clone() else {
+                    println!("❌ No planned route to add a stop to");
+                    return;
+                };
+                add_stop_to_route(&webview_clone, &planned, lat, lng, &route_buttons[0]);
+            }
+            IncomingMessage::ContextMenu { lat, lng, x, y } => {
+                println!("📋 Map menu at {:.6}, {:.6}", lat, lng);
+                context_menu.show(lat, lng, x, y);
+            }
+            IncomingMessage::LocationUpdate(update) => {
+                let location = Location::from(update);
+                println!("🌍 Location update from the map: {:.6}, {:.6}", location.latitude, location.longitude);
+                geo_service.lock().unwrap().update_location(location);
+            }
         }
     });
 
@@ -506,84 +1693,1238 @@ fn setup_webview(
 
 // Message handling would be implemented here in a full version
 
-fn load_map_html(webview: &WebView) {
-    let html_path = Path::new(env!("CARGO_MANIFEST_DIR"))
-        .join("src")
-        .join("map.html");
+// Reorders the route markers on the map to follow the given waypoints
+fn show_waypoint_order(webview: &WebView, waypoints: &[Waypoint]) {
+    let points: Vec<[f64; 2]> = waypoints.iter().map(|wp| [wp.lat, wp.lng]).collect();
+    let js_code = format!(
+        "if (window.reorderMarkers) {{ window.reorderMarkers({}); }}",
+        serde_json::to_string(&points).unwrap_or_else(|_| "[]".to_string())
+    );
     
-    if html_path.exists() {
-        let file_uri = format!("file://{}", html_path.display());
-        webview.load_uri(&file_uri);
-    } else {
-        // Enhanced fallback with full Leaflet functionality
-        let html_content = include_str!("map_embedded.html");
-        webview.load_html(html_content, None);
-    }
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
 }
 
-fn setup_event_handlers(
-    geo_service: Arc<Mutex<GeolocationService>>,
-    routing_service: Arc<RoutingService>,
-    location_entry: Entry,
-    search_button: Button,
-    location_button: Button,
-    route_button: Button,
-    clear_button: Button,
-    directions_toggle: Button,
-    webview: WebView,
+// Adds a stop to a planned route on the leg nearest to it, then plans again with the
+// current route options
+fn add_stop_to_route(webview: &WebView, planned: &PlannedRoute, lat: f64, lng: f64, route_button: &Button) {
+    let coordinates = planned.routes.first().map(|route| route.coordinates()).unwrap_or_default();
+    let index = routing::insertion_index(&coordinates, &planned.waypoints, lat, lng);
+    println!("➕ Adding stop {} at {:.6}, {:.6}", index + 1, lat, lng);
+    
+    let js_code = format!("if (window.insertMarker) {{ window.insertMarker({}, {}, {}); }}", index, lat, lng);
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+    route_button.clicked();
+}
+
+// Formats a latitude and longitude as text
+type CoordinateFormat = fn(f64, f64) -> String;
+
+// Text formats offered by the context menu's Copy coordinates submenu
+const COORDINATE_FORMATS: &[(&str, CoordinateFormat)] = &[
+    ("Decimal degrees", coords::to_decimal),
+    ("Degrees, minutes and seconds", coords::to_dms),
+    ("geo: URI", coords::to_geo_uri),
+];
+
+// OpenStreetMap amenity tags offered by the context menu's Find nearby submenu, with labels
+const NEARBY_AMENITIES: &[(&str, &str)] = &[
+    ("cafe", "Cafés"),
+    ("restaurant", "Restaurants"),
+    ("fuel", "Gas stations"),
+    ("pharmacy", "Pharmacies"),
+    ("atm", "ATMs"),
+    ("parking", "Parking"),
+    ("toilets", "Toilets"),
+];
+
+// How far from the right-clicked point Find nearby looks
+const NEARBY_RADIUS_M: u32 = 1000;
+
+// Menu popped up at a right-clicked point of the map. Directions start or end there, or it
+// becomes a stop (joining a planned route on its nearest leg); routing runs once there are
+// two points. Copy coordinates opens a submenu showing the point in each of COORDINATE_FORMATS,
+// and Find nearby one of NEARBY_AMENITIES to pin around the point.
+struct MapContextMenu {
+    popover: gtk::PopoverMenu,
+    target: Rc<Cell<(f64, f64)>>, // lat, lng that was right-clicked
+    format_items: Vec<gtk::ModelButton>, // one per COORDINATE_FORMATS entry, labeled with the point
+}
+
+impl MapContextMenu {
+    #[allow(clippy::too_many_arguments)]
+    fn new(webview: &WebView, route_button: &Button, last_route: Rc<RefCell<Option<Rc<PlannedRoute>>>>, marker_count: Rc<Cell<u64>>, current_view: Rc<Cell<Option<MapView>>>, routing_service: Arc<RoutingService>, busy: BusyIndicator, use_miles: Arc<Mutex<bool>>) -> Self {
+        let popover = gtk::PopoverMenu::new();
+        popover.set_relative_to(Some(webview));
+        let menu_box = GtkBox::new(Orientation::Vertical, 0);
+        let copy_box = GtkBox::new(Orientation::Vertical, 0);
+        let nearby_box = GtkBox::new(Orientation::Vertical, 0);
+        for submenu in [&menu_box, &copy_box, &nearby_box] {
+            submenu.set_margin_top(5);
+            submenu.set_margin_bottom(5);
+        }
+        let add_item = |menu: &GtkBox, item: gtk::ModelButton| {
+            menu.pack_start(&item, false, false, 0);
+            item
+        };
+        let from_item = add_item(&menu_box, gtk::ModelButton::builder().text("Directions from here").build());
+        let to_item = add_item(&menu_box, gtk::ModelButton::builder().text("Directions to here").build());
+        let stop_item = add_item(&menu_box, gtk::ModelButton::builder().text("Add as stop").build());
+        add_item(&menu_box, gtk::ModelButton::builder().text("Copy coordinates").menu_name("copy").build());
+        add_item(&menu_box, gtk::ModelButton::builder().text("Find nearby").menu_name("nearby").build());
+        let view_item = add_item(&menu_box, gtk::ModelButton::builder().text("Copy View Link").build());
+        view_item.set_tooltip_text(Some("Copy a geo: link to the visible map area"));
+        add_item(&copy_box, gtk::ModelButton::builder().text("Copy coordinates").menu_name("main").inverted(true).centered(true).build());
+        let format_items: Vec<gtk::ModelButton> = COORDINATE_FORMATS
+            .iter()
+            .map(|(name, _)| {
+                let item = add_item(&copy_box, gtk::ModelButton::new());
+                item.set_tooltip_text(Some(name));
+                item
+            })
+            .collect();
+        add_item(&nearby_box, gtk::ModelButton::builder().text("Find nearby").menu_name("main").inverted(true).centered(true).build());
+        let amenity_items: Vec<gtk::ModelButton> = NEARBY_AMENITIES
+            .iter()
+            .map(|(_, label)| add_item(&nearby_box, gtk::ModelButton::builder().text(*label).build()))
+            .collect();
+        popover.add(&menu_box);
+        popover.child_set_property(&menu_box, "submenu", &"main");
+        popover.add(&copy_box);
+        popover.child_set_property(&copy_box, "submenu", &"copy");
+        popover.add(&nearby_box);
+        popover.child_set_property(&nearby_box, "submenu", &"nearby");
+        menu_box.show_all();
+        copy_box.show_all();
+        nearby_box.show_all();
+        
+        let target = Rc::new(Cell::new((0.0, 0.0)));
+        
+        // New first point, so any existing points follow it
+        {
+            let webview = webview.clone();
+            let route_button = route_button.clone();
+            let target = target.clone();
+            let marker_count = marker_count.clone();
+            from_item.connect_clicked(move |_| {
+                let (lat, lng) = target.get();
+                let js_code = format!("if (window.insertMarker) {{ window.insertMarker(0, {}, {}); }}", lat, lng);
+                webview.evaluate_javascript(
+                    &js_code,
+                    None,
+                    None,
+                    webkit2gtk::gio::Cancellable::NONE,
+                    |_| {}
+                );
+                if marker_count.get() >= 1 {
+                    route_button.clicked();
+                }
+            });
+        }
+        
+        // New last point, placed as if clicked
+        {
+            let webview = webview.clone();
+            let route_button = route_button.clone();
+            let target = target.clone();
+            let marker_count = marker_count.clone();
+            to_item.connect_clicked(move |_| {
+                let (lat, lng) = target.get();
+                add_click_point(&webview, lat, lng);
+                if marker_count.get() >= 1 {
+                    route_button.clicked();
+                }
+            });
+        }
+        
+        {
+            let webview = webview.clone();
+            let route_button = route_button.clone();
+            let target = target.clone();
+            stop_item.connect_clicked(move |_| {
+                let (lat, lng) = target.get();
+                match last_route.borrow().as_ref() {
+                    Some(planned) => add_stop_to_route(&webview, planned, lat, lng, &route_button),
+                    None => add_click_point(&webview, lat, lng),
+                }
+            });
+        }
+        
+        // The whole view rather than the clicked point
+        view_item.connect_clicked(move |_| {
+            let Some(view) = current_view.get() else {
+                println!("⚠️ Map view not known yet");
+                return;
+            };
+            let url = encode_view_url(&view);
+            gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD).set_text(&url);
+            println!("📋 Copied view link {}", url);
+        });
+        
+        for (item, (_, format)) in format_items.iter().zip(COORDINATE_FORMATS) {
+            let target = target.clone();
+            item.connect_clicked(move |_| {
+                let (lat, lng) = target.get();
+                let text = format(lat, lng);
+                gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD).set_text(&text);
+                println!("📋 Copied coordinates {}", text);
+            });
+        }
+        
+        // Pin the nearest places of a kind around the point, nearest last so its popup is open
+        for (item, (amenity, label)) in amenity_items.iter().zip(NEARBY_AMENITIES) {
+            let webview = webview.clone();
+            let route_button = route_button.clone();
+            let target = target.clone();
+            let routing_service = routing_service.clone();
+            let busy = busy.clone();
+            let use_miles = use_miles.clone();
+            item.connect_clicked(move |_| {
+                let (lat, lng) = target.get();
+                println!("🔎 Looking for {} within {} m of {:.6}, {:.6}", amenity, NEARBY_RADIUS_M, lat, lng);
+                let use_miles_val = *use_miles.lock().unwrap();
+                let webview = webview.clone();
+                let route_button = route_button.clone();
+                let routing_service = routing_service.clone();
+                let busy = busy.clone();
+                glib::spawn_future_local(async move {
+                    let busy_guard = busy.begin(&[]);
+                    let result = routing_service.nearest_poi(Location::new(lat, lng), amenity, NEARBY_RADIUS_M).await;
+                    drop(busy_guard);
+                    
+                    match result {
+                        Ok(places) if places.is_empty() => show_error_dialog(
+                            &route_button,
+                            "Nothing nearby",
+                            &format!("No {} found within {}.", label.to_lowercase(), format_distance(NEARBY_RADIUS_M as f64, use_miles_val)),
+                        ),
+                        Ok(places) => {
+                            println!("📍 Found {} {}", places.len(), amenity);
+                            for place in places.iter().rev() {
+                                let location = &place.location;
+                                let js_code = format!(
+                                    "if (window.addSearchMarker) {{ window.addSearchMarker({}, {}, {}); }}",
+                                    location.latitude,
+                                    location.longitude,
+                                    js_string_literal(&place.display_name)
+                                );
+                                webview.evaluate_javascript(
+                                    &js_code,
+                                    None,
+                                    None,
+                                    webkit2gtk::gio::Cancellable::NONE,
+                                    |_| {}
+                                );
+                            }
+                            let mut locations: Vec<Location> = places.into_iter().map(|place| place.location).collect();
+                            locations.push(Location::new(lat, lng));
+                            fit_map_to_locations(&webview, &locations, FIT_PADDING_PX, Some(17));
+                        }
+                        Err(e) if e.is_timeout() => show_error_dialog(&route_button, "Request timed out", "The places server did not answer in time. Please try again."),
+                        Err(e) => {
+                            println!("❌ Nearby search failed: {}", e);
+                            show_error_dialog(&route_button, "Could not search nearby", &e.to_string());
+                        }
+                    }
+                });
+            });
+        }
+        
+        Self { popover, target, format_items }
+    }
+    
+    // Points the menu at page pixel (x, y), which shows the map at lat, lng
+    fn show(&self, lat: f64, lng: f64, x: f64, y: f64) {
+        self.target.set((lat, lng));
+        for (item, (_, format)) in self.format_items.iter().zip(COORDINATE_FORMATS) {
+            item.set_text(Some(&format(lat, lng)));
+        }
+        self.popover.open_submenu("main");
+        self.popover.set_pointing_to(&gtk::gdk::Rectangle::new(x as i32, y as i32, 1, 1));
+        self.popover.popup();
+    }
+}
+
+// Drops an unnamed route point as if the map had been clicked there
+fn add_click_point(webview: &WebView, lat: f64, lng: f64) {
+    let js_code = format!("if (window.addClickPoint) {{ window.addClickPoint(L.latLng({}, {}), null); }}", lat, lng);
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+// Name entry popped up at a double-clicked point; confirming drops a stop with that name,
+// or an unnamed one if the entry is left empty, and dismissing the popover drops nothing
+#[derive(Clone)]
+struct StopNamePopover {
+    popover: Popover,
+    entry: Entry,
+    target: Rc<Cell<(f64, f64)>>, // lat, lng of the stop being named
+}
+
+impl StopNamePopover {
+    fn new(webview: &WebView) -> Self {
+        let popover = Popover::new(Some(webview));
+        let name_box = GtkBox::new(Orientation::Horizontal, 5);
+        name_box.set_margin_start(5);
+        name_box.set_margin_end(5);
+        name_box.set_margin_top(5);
+        name_box.set_margin_bottom(5);
+        let entry = Entry::new();
+        entry.set_placeholder_text(Some("Stop name"));
+        let add_button = Button::with_label("Add Stop");
+        name_box.pack_start(&entry, true, true, 0);
+        name_box.pack_start(&add_button, false, false, 0);
+        name_box.show_all();
+        popover.add(&name_box);
+        
+        let target = Rc::new(Cell::new((0.0, 0.0)));
+        let add_stop = {
+            let webview = webview.clone();
+            let popover = popover.clone();
+            let entry = entry.clone();
+            let target = target.clone();
+            move || {
+                let (lat, lng) = target.get();
+                let text = entry.text();
+                let name = Some(text.trim()).filter(|name| !name.is_empty());
+                let js_code = format!(
+                    "if (window.addClickPoint) {{ window.addClickPoint(L.latLng({}, {}), {}); }}",
+                    lat, lng, name.map(js_string_literal).unwrap_or_else(|| "null".to_string())
+                );
+                webview.evaluate_javascript(
+                    &js_code,
+                    None,
+                    None,
+                    webkit2gtk::gio::Cancellable::NONE,
+                    |_| {}
+                );
+                popover.popdown();
+            }
+        };
+        {
+            let add_stop = add_stop.clone();
+            entry.connect_activate(move |_| add_stop());
+        }
+        add_button.connect_clicked(move |_| add_stop());
+        
+        Self { popover, entry, target }
+    }
+    
+    // Points the popover at page pixel (x, y), which shows the map at lat, lng
+    fn ask(&self, lat: f64, lng: f64, x: f64, y: f64) {
+        self.target.set((lat, lng));
+        self.popover.set_pointing_to(&gtk::gdk::Rectangle::new(x as i32, y as i32, 1, 1));
+        self.entry.set_text("");
+        self.popover.popup();
+        self.entry.grab_focus();
+    }
+}
+
+// Replaces the directions pane contents with the "Plan Route" placeholder
+fn reset_directions(directions_box: &GtkBox) {
+    let children: Vec<gtk::Widget> = directions_box.children();
+    for child in children {
+        directions_box.remove(&child);
+    }
+    
+    let directions_label = Label::new(Some("Click 'Plan Route' to see turn-by-turn directions"));
+    directions_label.set_line_wrap(true);
+    directions_label.set_xalign(0.0);
+    directions_box.pack_start(&directions_label, false, false, 0);
+    directions_box.show_all();
+}
+
+// Runs a modal file chooser attached to the widget's window and returns the picked path
+fn choose_file(widget: &impl IsA<gtk::Widget>, title: &str, action: FileChooserAction, suggested_name: Option<&str>) -> Option<PathBuf> {
+    let parent = widget.toplevel().and_then(|w| w.downcast::<gtk::Window>().ok());
+    let accept_label = if action == FileChooserAction::Save { "Save" } else { "Open" };
+    
+    let dialog = FileChooserDialog::with_buttons(
+        Some(title),
+        parent.as_ref(),
+        action,
+        &[("Cancel", ResponseType::Cancel), (accept_label, ResponseType::Accept)],
+    );
+    
+    if action == FileChooserAction::Save {
+        dialog.set_do_overwrite_confirmation(true);
+        if let Some(name) = suggested_name {
+            dialog.set_current_name(name);
+        }
+    }
+    
+    let path = if dialog.run() == ResponseType::Accept {
+        dialog.filename()
+    } else {
+        None
+    };
+    dialog.close();
+    
+    path
+}
+
+// Asks for a list of addresses, one per line, skipping blank lines. None if cancelled.
+fn ask_for_addresses(widget: &impl IsA<gtk::Widget>) -> Option<Vec<String>> {
+    let parent = widget.toplevel().and_then(|w| w.downcast::<gtk::Window>().ok());
+    let dialog = gtk::Dialog::with_buttons(
+        Some("Route from Addresses"),
+        parent.as_ref(),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        &[("Cancel", ResponseType::Cancel), ("Plan Route", ResponseType::Accept)],
+    );
+    dialog.set_default_size(420, 300);
+    
+    let hint = Label::new(Some("One address per line, in the order to visit them"));
+    hint.set_xalign(0.0);
+    let text_view = gtk::TextView::new();
+    text_view.set_wrap_mode(gtk::WrapMode::WordChar);
+    let scrolled = gtk::ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+    scrolled.set_shadow_type(gtk::ShadowType::In);
+    scrolled.add(&text_view);
+    
+    let content = dialog.content_area();
+    content.set_spacing(6);
+    content.set_margin_start(10);
+    content.set_margin_end(10);
+    content.set_margin_top(10);
+    content.pack_start(&hint, false, false, 0);
+    content.pack_start(&scrolled, true, true, 0);
+    content.show_all();
+    
+    let text = if dialog.run() == ResponseType::Accept {
+        text_view
+            .buffer()
+            .and_then(|buffer| buffer.text(&buffer.start_iter(), &buffer.end_iter(), false))
+    } else {
+        None
+    };
+    dialog.close();
+    
+    let text = text?;
+    Some(text.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string).collect())
+}
+
+// Shows a modal error message over the window containing the widget; closes on any response
+fn show_error_dialog(widget: &impl IsA<gtk::Widget>, title: &str, message: &str) {
+    let parent = widget.toplevel().and_then(|w| w.downcast::<gtk::Window>().ok());
+    let dialog = MessageDialog::new(
+        parent.as_ref(),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        MessageType::Error,
+        ButtonsType::Close,
+        title,
+    );
+    // Secondary text is plain text, so server messages need no escaping
+    dialog.set_secondary_text(Some(message));
+    dialog.connect_response(|dialog, _| dialog.close());
+    dialog.show();
+}
+
+// Asks whether to look up the user's location, which directions need as their start
+fn confirm_locate(widget: &impl IsA<gtk::Widget>) -> bool {
+    let parent = widget.toplevel().and_then(|w| w.downcast::<gtk::Window>().ok());
+    let dialog = MessageDialog::new(
+        parent.as_ref(),
+        DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+        MessageType::Question,
+        ButtonsType::None,
+        "Find your location?",
+    );
+    dialog.set_secondary_text(Some("Directions start from where you are, which isn't known yet."));
+    dialog.add_buttons(&[("Cancel", ResponseType::Cancel), ("Find Location", ResponseType::Accept)]);
+    let accepted = dialog.run() == ResponseType::Accept;
+    dialog.close();
+    accepted
+}
+
+// Scale bar drawn over the bottom-left corner of the map, sized from the page's center
+// latitude and zoom
+#[derive(Clone)]
+struct ScaleBar {
+    area: DrawingArea,
+    meters_per_pixel: Rc<Cell<Option<f64>>>,
+    shown: Rc<RefCell<Option<(f64, String)>>>, // bar width in pixels and its label
+}
+
+impl ScaleBar {
+    fn new() -> Self {
+        let area = DrawingArea::new();
+        area.set_halign(gtk::Align::Start);
+        area.set_valign(gtk::Align::End);
+        area.set_margin_start(10);
+        area.set_margin_bottom(25); // clear of Leaflet's attribution line
+        area.set_no_show_all(true);
+        
+        let shown: Rc<RefCell<Option<(f64, String)>>> = Rc::new(RefCell::new(None));
+        {
+            let shown = shown.clone();
+            area.connect_draw(move |area, cr| {
+                if let Some((width, label)) = shown.borrow().as_ref() {
+                    draw_scale_bar(area, cr, *width, label);
+                }
+                glib::Propagation::Proceed
+            });
+        }
+        Self { area, meters_per_pixel: Rc::new(Cell::new(None)), shown }
+    }
+    
+    fn set_view(&self, latitude: f64, zoom: f64, use_miles: bool) {
+        self.meters_per_pixel.set(Some(units::meters_per_pixel(latitude, zoom)));
+        self.refresh(use_miles);
+    }
+    
+    // Redraws for the last known view, e.g. after the units setting changes
+    fn refresh(&self, use_miles: bool) {
+        let bar = self.meters_per_pixel.get().and_then(|mpp| units::scale_bar(mpp, SCALE_BAR_MAX_PX, use_miles));
+        match &bar {
+            Some((width, _)) => {
+                self.area.set_size_request(width.ceil() as i32 + 12, 26);
+                self.area.show();
+            }
+            None => self.area.hide(),
+        }
+        *self.shown.borrow_mut() = bar;
+        self.area.queue_draw();
+    }
+}
+
+fn draw_scale_bar(area: &DrawingArea, cr: &gtk::cairo::Context, width: f64, label: &str) {
+    // Translucent backing keeps the bar readable on any tiles
+    cr.set_source_rgba(1.0, 1.0, 1.0, 0.7);
+    cr.rectangle(0.0, 0.0, area.allocated_width() as f64, area.allocated_height() as f64);
+    if let Err(e) = cr.fill() {
+        println!("❌ Failed to draw scale bar: {}", e);
+        return;
+    }
+    
+    cr.set_source_rgb(0.2, 0.2, 0.2);
+    cr.set_font_size(11.0);
+    cr.move_to(6.0, 12.0);
+    if let Err(e) = cr.show_text(label) {
+        println!("❌ Failed to draw scale bar: {}", e);
+        return;
+    }
+    
+    // Bracket-shaped bar: the distance runs between the two ticks
+    cr.set_line_width(2.0);
+    cr.move_to(6.0, 16.0);
+    cr.line_to(6.0, 22.0);
+    cr.line_to(6.0 + width, 22.0);
+    cr.line_to(6.0 + width, 16.0);
+    if let Err(e) = cr.stroke() {
+        println!("❌ Failed to draw scale bar: {}", e);
+    }
+}
+
+// Captures the visible part of the map and saves it as a PNG the size of the on-screen view.
+// The file is chosen after the capture so the dialog never ends up in the image.
+fn export_map_image(webview: &WebView) {
+    let (width, height) = (webview.allocated_width(), webview.allocated_height());
+    let parent = webview.clone();
+    
+    webview.snapshot(SnapshotRegion::Visible, SnapshotOptions::NONE, webkit2gtk::gio::Cancellable::NONE, move |result| {
+        let pixbuf = match result {
+            Ok(surface) => gtk::gdk::pixbuf_get_from_surface(&surface, 0, 0, width, height),
+            Err(e) => {
+                println!("❌ Map snapshot failed: {}", e);
+                show_error_dialog(&parent, "Could not export image", &format!("Capturing the map failed: {}", e));
+                return;
+            }
+        };
+        let Some(pixbuf) = pixbuf else {
+            println!("❌ Map snapshot could not be converted to an image");
+            show_error_dialog(&parent, "Could not export image", "The captured map could not be converted to an image.");
+            return;
+        };
+        
+        let Some(path) = choose_file(&parent, "Export Map Image", FileChooserAction::Save, Some("map.png")) else {
+            return;
+        };
+        match pixbuf.savev(&path, "png", &[]) {
+            Ok(()) => println!("🖼️ Saved {}x{} map image to {}", width, height, path.display()),
+            Err(e) => {
+                println!("❌ Failed to save map image: {}", e);
+                show_error_dialog(&parent, "Could not export image", &format!("Saving {} failed: {}", path.display(), e));
+            }
+        }
+    });
+}
+
+// Waypoints of a planned trip together with the route options found for them
+struct PlannedRoute {
+    waypoints: Vec<Waypoint>,
+    profile: String,
+    routes: Vec<RouteResponse>,
+    selected: Cell<usize>, // route option shown in the directions pane
+    language: Language, // of the instructions, for the text shown around them
+}
+
+// Spins while any network request is in flight
+#[derive(Clone)]
+struct BusyIndicator {
+    spinner: Spinner,
+    pending: Rc<Cell<u32>>,
+    disabled: Rc<RefCell<Vec<Button>>>, // one entry per request holding each button
+}
+
+impl BusyIndicator {
+    fn new(spinner: Spinner) -> Self {
+        Self { spinner, pending: Rc::new(Cell::new(0)), disabled: Rc::new(RefCell::new(Vec::new())) }
+    }
+    
+    // Starts spinning and disables the buttons until the returned guard is dropped
+    fn begin(&self, buttons: &[Button]) -> BusyGuard {
+        self.pending.set(self.pending.get() + 1);
+        self.spinner.start();
+        for button in buttons {
+            button.set_sensitive(false);
+            self.disabled.borrow_mut().push(button.clone());
+        }
+        BusyGuard { indicator: self.clone(), buttons: buttons.to_vec() }
+    }
+}
+
+struct BusyGuard {
+    indicator: BusyIndicator,
+    buttons: Vec<Button>,
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        let indicator = &self.indicator;
+        indicator.pending.set(indicator.pending.get().saturating_sub(1));
+        if indicator.pending.get() == 0 {
+            indicator.spinner.stop();
+        }
+        
+        // Overlapping requests from the same button keep it disabled until the last one finishes
+        let mut disabled = indicator.disabled.borrow_mut();
+        for button in &self.buttons {
+            if let Some(i) = disabled.iter().position(|b| b == button) {
+                disabled.remove(i);
+            }
+            if !disabled.contains(button) {
+                button.set_sensitive(true);
+            }
+        }
+    }
+}
+
+// Sidebar listing the route markers in order, with buttons to reorder and delete them
+#[derive(Clone)]
+struct WaypointPanel {
+    container: GtkBox,
+    list: ListBox,
+}
+
+impl WaypointPanel {
+    fn new() -> Self {
+        let container = GtkBox::new(Orientation::Vertical, 0);
+        container.set_width_request(250);
+        
+        let title = Label::new(None);
+        title.set_markup("<b>Waypoints</b>");
+        title.set_xalign(0.0);
+        title.set_margin_start(10);
+        title.set_margin_end(10);
+        title.set_margin_top(10);
+        title.set_margin_bottom(5);
+        
+        let scrolled = gtk::ScrolledWindow::new(None::<&gtk::Adjustment>, None::<&gtk::Adjustment>);
+        scrolled.set_policy(gtk::PolicyType::Never, gtk::PolicyType::Automatic);
+        let list = ListBox::new();
+        list.set_selection_mode(gtk::SelectionMode::None);
+        scrolled.add(&list);
+        
+        container.pack_start(&title, false, false, 0);
+        container.pack_start(&scrolled, true, true, 0);
+        Self { container, list }
+    }
+    
+    // Rebuilds the rows from the markers the page reported; hidden while there are none.
+    // Moving a point or switching it between stop and via re-plans the route through the
+    // route button, so the current profile applies.
+    fn show(&self, webview: &WebView, markers: &[MarkerInfo], route_button: &Button) {
+        for child in self.list.children() {
+            self.list.remove(&child);
+        }
+        
+        let ids: Vec<u64> = markers.iter().map(|m| m.id).collect();
+        for (i, marker) in markers.iter().enumerate() {
+            let row = GtkBox::new(Orientation::Horizontal, 5);
+            row.set_margin_start(5);
+            row.set_margin_end(5);
+            row.set_margin_top(2);
+            row.set_margin_bottom(2);
+            
+            let name = marker.label.clone().unwrap_or_else(|| format!("{:.6}, {:.6}", marker.lat, marker.lng));
+            let label = Label::new(Some(&format!("{}. {}", i + 1, name)));
+            label.set_line_wrap(true);
+            label.set_xalign(0.0);
+            row.pack_start(&label, true, true, 0);
+            
+            // Only points between the ends can be passed through
+            let via_button = ToggleButton::new();
+            via_button.set_image(Some(&Image::from_icon_name(Some("media-skip-forward"), gtk::IconSize::Button)));
+            via_button.set_tooltip_text(Some("Pass through without stopping"));
+            via_button.set_relief(gtk::ReliefStyle::None);
+            via_button.set_active(marker.kind == WaypointKind::Via);
+            via_button.set_sensitive(i > 0 && i + 1 < markers.len());
+            
+            let up_button = waypoint_button("go-up", "Move earlier");
+            let down_button = waypoint_button("go-down", "Move later");
+            let delete_button = waypoint_button("edit-delete", "Remove point");
+            
+            let moves = [(&up_button, i.checked_sub(1)), (&down_button, Some(i + 1).filter(|&j| j < markers.len()))];
+            for (button, to) in moves {
+                let Some(to) = to else {
+                    button.set_sensitive(false);
+                    continue;
+                };
+                let mut order = ids.clone();
+                order.swap(i, to);
+                let js_code = format!(
+                    "if (window.setMarkerOrder) {{ window.setMarkerOrder({}); }}",
+                    serde_json::to_string(&order).unwrap_or_else(|_| "[]".to_string())
+                );
+                
+                let webview = webview.clone();
+                let route_button = route_button.clone();
+                button.connect_clicked(move |_| {
+                    println!("🔀 Moving point {} to position {}", i + 1, to + 1);
+                    webview.evaluate_javascript(
+                        &js_code,
+                        None,
+                        None,
+                        webkit2gtk::gio::Cancellable::NONE,
+                        |_| {}
+                    );
+                    route_button.clicked();
+                });
+            }
+            
+            {
+                let webview = webview.clone();
+                let id = marker.id;
+                delete_button.connect_clicked(move |_| {
+                    println!("🗑️ Removing point {}", i + 1);
+                    let js_code = format!("if (window.removeMarkerById) {{ window.removeMarkerById({}); }}", id);
+                    webview.evaluate_javascript(
+                        &js_code,
+                        None,
+                        None,
+                        webkit2gtk::gio::Cancellable::NONE,
+                        |_| {}
+                    );
+                });
+            }
+            
+            {
+                let webview = webview.clone();
+                let route_button = route_button.clone();
+                let id = marker.id;
+                via_button.connect_toggled(move |toggle| {
+                    let kind = if toggle.is_active() { "via" } else { "stop" };
+                    println!("🔁 Point {} is now a {}", i + 1, kind);
+                    let js_code = format!("if (window.setMarkerKind) {{ window.setMarkerKind({}, {}); }}", id, js_string_literal(kind));
+                    webview.evaluate_javascript(
+                        &js_code,
+                        None,
+                        None,
+                        webkit2gtk::gio::Cancellable::NONE,
+                        |_| {}
+                    );
+                    route_button.clicked();
+                });
+            }
+            
+            row.pack_start(&via_button, false, false, 0);
+            row.pack_start(&up_button, false, false, 0);
+            row.pack_start(&down_button, false, false, 0);
+            row.pack_start(&delete_button, false, false, 0);
+            self.list.add(&row);
+        }
+        
+        self.list.show_all();
+        self.container.set_visible(!markers.is_empty());
+    }
+}
+
+fn waypoint_button(icon: &str, tooltip: &str) -> Button {
+    let button = Button::new();
+    button.set_image(Some(&Image::from_icon_name(Some(icon), gtk::IconSize::Button)));
+    button.set_tooltip_text(Some(tooltip));
+    button.set_relief(gtk::ReliefStyle::None);
+    button
+}
+
+// Formats the local clock time after travelling for the given duration, e.g. "Arrive by 3:42 PM".
+// Uses GLib's local time zone conversion so DST transitions are handled.
+fn arrival_time(now: SystemTime, duration_secs: f64) -> String {
+    let arrival = now + Duration::from_secs_f64(duration_secs.max(0.0));
+    let unix_secs = arrival
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    
+    glib::DateTime::from_unix_local(unix_secs)
+        .and_then(|time| time.format("%-l:%M %p"))
+        .map(|time| format!("Arrive by {}", time))
+        .unwrap_or_default()
+}
+
+// Renders the selected route option in the directions pane and draws it on the map
+fn show_route(webview: &WebView, directions_box: &GtkBox, routing_service: &Arc<RoutingService>, planned: Rc<PlannedRoute>, selected: usize, use_miles: bool) {
+    let routes = &planned.routes;
+    let route = &routes[selected];
+    planned.selected.set(selected);
+    
+    println!("✅ Route {}: {}, {:.0}min", 
+           selected + 1, format_distance(route.distance, use_miles), route.duration / 60.0);
+    
+    let summary_text = format!(
+        "{}\n{}",
+        routing::route_summary(route, use_miles),
+        arrival_time(SystemTime::now(), route.duration)
+    );
+    
+    // Clear existing directions
+    let children: Vec<gtk::Widget> = directions_box.children();
+    for child in children {
+        directions_box.remove(&child);
+    }
+    
+    // Let the user switch between the primary route and any alternatives
+    if routes.len() > 1 {
+        let options_box = GtkBox::new(Orientation::Horizontal, 5);
+        for i in 0..routes.len() {
+            let label = if i == 0 { "Fastest".to_string() } else { format!("Alt {}", i) };
+            let option_button = Button::with_label(&label);
+            option_button.set_sensitive(i != selected);
+            
+            let webview = webview.clone();
+            let directions_box = directions_box.clone();
+            let routing_service = routing_service.clone();
+            let planned = planned.clone();
+            option_button.connect_clicked(move |_| {
+                show_route(&webview, &directions_box, &routing_service, planned.clone(), i, use_miles);
+            });
+            
+            options_box.pack_start(&option_button, false, false, 0);
+        }
+        directions_box.pack_start(&options_box, false, false, 0);
+    }
+    
+    // Add route summary
+    let summary_label = Label::new(Some(&summary_text));
+    summary_label.set_line_wrap(true);
+    summary_label.set_xalign(0.0);
+    summary_label.set_markup(&format!("<b>{}</b>", summary_text));
+    directions_box.pack_start(&summary_label, false, false, 0);
+    
+    // Elevation sparkline, shown once the heights have been looked up
+    let elevations: Rc<RefCell<Vec<f64>>> = Rc::new(RefCell::new(Vec::new()));
+    let elevation_label = Label::new(None);
+    elevation_label.set_xalign(0.0);
+    elevation_label.set_no_show_all(true);
+    let elevation_area = DrawingArea::new();
+    elevation_area.set_size_request(-1, 50);
+    elevation_area.set_no_show_all(true);
+    {
+        let elevations = elevations.clone();
+        elevation_area.connect_draw(move |area, cr| {
+            draw_sparkline(area, cr, &elevations.borrow());
+            glib::Propagation::Proceed
+        });
+    }
+    directions_box.pack_start(&elevation_label, false, false, 0);
+    directions_box.pack_start(&elevation_area, false, false, 0);
+    {
+        let routing_service = routing_service.clone();
+        let planned = planned.clone();
+        let elevation_label = elevation_label.downgrade();
+        let elevation_area = elevation_area.downgrade();
+        glib::spawn_future_local(async move {
+            match routing_service.elevation_profile(&planned.routes[selected]).await {
+                Ok(profile) => {
+                    // The pane may have been redrawn for another route meanwhile
+                    let (Some(label), Some(area)) = (elevation_label.upgrade(), elevation_area.upgrade()) else {
+                        return;
+                    };
+                    let lowest = profile.iter().copied().fold(f64::INFINITY, f64::min);
+                    let highest = profile.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                    let (ascent, descent) = routing::elevation_stats(&profile);
+                    label.set_text(&format!(
+                        "Elevation: {}–{}, ↑ {} ↓ {}",
+                        format_height(lowest, use_miles),
+                        format_height(highest, use_miles),
+                        format_height(ascent, use_miles),
+                        format_height(descent, use_miles)
+                    ));
+                    *elevations.borrow_mut() = profile;
+                    label.set_visible(true);
+                    area.set_visible(true);
+                    area.queue_draw();
+                }
+                Err(e) => println!("⚠️ Could not load elevation profile: {}", e),
+            }
+        });
+    }
+    
+    // Export the selected route for GPS devices
+    let save_gpx_button = Button::with_label("Save GPX");
+    {
+        let directions_box = directions_box.clone();
+        let planned = planned.clone();
+        save_gpx_button.connect_clicked(move |_| {
+            let Some(path) = choose_file(&directions_box, "Save Route as GPX", FileChooserAction::Save, Some("route.gpx")) else {
+                return;
+            };
+            
+            let gpx = routing::to_gpx(&planned.routes[selected], &planned.waypoints);
+            match std::fs::write(&path, gpx) {
+                Ok(()) => println!("💾 Saved GPX to {}", path.display()),
+                Err(e) => println!("❌ Failed to save GPX: {}", e),
+            }
+        });
+    }
+    
+    let save_geojson_button = Button::with_label("Save GeoJSON");
+    {
+        let directions_box = directions_box.clone();
+        let planned = planned.clone();
+        save_geojson_button.connect_clicked(move |_| {
+            let Some(path) = choose_file(&directions_box, "Save Route as GeoJSON", FileChooserAction::Save, Some("route.geojson")) else {
+                return;
+            };
+            
+            let geojson = routing::route_to_geojson(&planned.routes[selected]);
+            match std::fs::write(&path, geojson.to_string()) {
+                Ok(()) => println!("💾 Saved GeoJSON to {}", path.display()),
+                Err(e) => println!("❌ Failed to save GeoJSON: {}", e),
+            }
+        });
+    }
+    
+    // Copy a link that reopens these stops when passed to the app
+    let copy_link_button = Button::with_label("Copy Link");
+    {
+        let planned = planned.clone();
+        copy_link_button.connect_clicked(move |_| {
+            let url = encode_route_url(&planned.waypoints, &planned.profile);
+            gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD).set_text(&url);
+            println!("🔗 Copied route link: {}", url);
+        });
+    }
+    
+    // Copy the turn list as plain text for pasting into a message
+    let copy_directions_button = Button::with_label("Copy Directions");
+    {
+        let planned = planned.clone();
+        copy_directions_button.connect_clicked(move |_| {
+            let text = routing::directions_to_text(&planned.routes[selected], use_miles, planned.language);
+            gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD).set_text(&text);
+            println!("📋 Copied {} directions", planned.routes[selected].instructions.len());
+        });
+    }
+    
+    let export_box = GtkBox::new(Orientation::Horizontal, 5);
+    export_box.pack_start(&save_gpx_button, false, false, 0);
+    export_box.pack_start(&save_geojson_button, false, false, 0);
+    export_box.pack_start(&copy_link_button, false, false, 0);
+    export_box.pack_start(&copy_directions_button, false, false, 0);
+    directions_box.pack_start(&export_box, false, false, 0);
+    
+    // Add separator
+    let separator = gtk::Separator::new(Orientation::Horizontal);
+    directions_box.pack_start(&separator, false, false, 5);
+    
+    // Add turn-by-turn directions, headed by each leg's totals when there are several stops.
+    // Via points don't end a leg, so leg n runs between the nth and next stop.
+    let show_legs = route.legs.len() > 1;
+    let stops: Vec<usize> = (0..planned.waypoints.len()).filter(|&i| Waypoint::is_stop(&planned.waypoints, i)).collect();
+    for (i, instruction) in route.instructions.iter().enumerate() {
+        if let Some((n, leg)) = route.legs.iter().enumerate().find(|(_, leg)| show_legs && leg.first_instruction == i) {
+            let leg_label = Label::new(None);
+            let stop_name = |n: usize| {
+                let i = stops.get(n).copied().unwrap_or(n);
+                let name = planned.waypoints.get(i).and_then(|wp| wp.name.clone()).unwrap_or_else(|| format!("Point {}", i + 1));
+                glib::markup_escape_text(&name)
+            };
+            leg_label.set_markup(&format!(
+                "<b>Leg {}: {} → {}</b>\n{}, {}",
+                n + 1,
+                stop_name(n),
+                stop_name(n + 1),
+                format_distance(leg.distance, use_miles),
+                format_duration(leg.duration)
+            ));
+            leg_label.set_line_wrap(true);
+            leg_label.set_xalign(0.0);
+            leg_label.set_margin_top(5);
+            directions_box.pack_start(&leg_label, false, false, 0);
+        }
+        
+        let direction_label = Label::new(Some(&routing::instruction_line(i, instruction, use_miles, planned.language)));
+        direction_label.set_line_wrap(true);
+        direction_label.set_xalign(0.0);
+        
+        let step_row = GtkBox::new(Orientation::Horizontal, 8);
+        step_row.set_margin_bottom(5);
+        let step_icon = Image::from_icon_name(Some(step_icon_name(&instruction.maneuver_icon)), gtk::IconSize::Button);
+        step_icon.set_valign(gtk::Align::Start);
+        step_row.pack_start(&step_icon, false, false, 0);
+        step_row.pack_start(&direction_label, true, true, 0);
+        
+        // Clicking a step pans the map to where the maneuver happens
+        let step_button = Button::new();
+        step_button.set_relief(gtk::ReliefStyle::None);
+        step_button.add(&step_row);
+        {
+            let webview = webview.clone();
+            let instruction = instruction.clone();
+            step_button.connect_clicked(move |_| {
+                highlight_step(&webview, &instruction.location);
+                show_maneuver_arrow(&webview, Some(&instruction));
+            });
+        }
+        directions_box.pack_start(&step_button, false, false, 0);
+    }
+    
+    directions_box.show_all();
+    
+    // Send route to map, with numbered waypoint markers named in their popups
+    // The names travel as a JSON string inside a quoted JS literal, so escape it like other map text.
+    // They come from the geocoder or opened files, so the popups show them as text rather than HTML.
+    let labels = serde_json::to_string(&waypoint_labels(&planned.waypoints)).unwrap_or_else(|_| "[]".to_string());
+    let points: Vec<[f64; 2]> = planned.waypoints.iter().map(|wp| [wp.lat, wp.lng]).collect();
+    let style = route_style(&planned.profile);
+    let js_code = format!(
+        "if (window.mapInstance && window.addRouteToMap) {{ \
+            window.addRouteToMap({}, {{ color: {}, weight: {} }}); \
+            if (window.waypointLayer) {{ \
+                window.mapInstance.removeLayer(window.waypointLayer); \
+            }} \
+            var names = JSON.parse({}); \
+            window.waypointLayer = L.layerGroup({}.map(function(point, i) {{ \
+                return L.marker(point) \
+                    .bindTooltip(String(i + 1), {{ permanent: true, direction: 'top' }}) \
+                    .bindPopup(document.createTextNode(names[i])); \
+            }})).addTo(window.mapInstance); \
+        }}",
+        js_string_literal(&route.geometry),
+        js_string_literal(style.color),
+        style.weight,
+        js_string_literal(&labels),
+        serde_json::to_string(&points).unwrap_or_else(|_| "[]".to_string())
+    );
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+    
+    // The arrow starts at the departure and moves as steps are clicked or followed
+    show_maneuver_arrow(webview, route.instructions.first());
+    
+    // Zoom to the route line and every waypoint
+    let mut locations: Vec<Location> = route
+        .coordinates()
+        .into_iter()
+        .map(|(lat, lng)| Location::new(lat, lng))
+        .collect();
+    locations.extend(planned.waypoints.iter().map(|wp| Location::new(wp.lat, wp.lng)));
+    fit_map_to_locations(webview, &locations, style.padding, Some(style.max_zoom));
+}
+
+// Draws the elevations as a line scaled to fill the area, lowest point at the bottom
+fn draw_sparkline(area: &DrawingArea, cr: &gtk::cairo::Context, elevations: &[f64]) {
+    if elevations.len() < 2 {
+        return;
+    }
+    
+    let width = area.allocated_width() as f64;
+    let height = area.allocated_height() as f64;
+    let lowest = elevations.iter().copied().fold(f64::INFINITY, f64::min);
+    let highest = elevations.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let range = (highest - lowest).max(1.0);
+    
+    cr.set_source_rgb(0.0, 0.49, 0.73);
+    cr.set_line_width(2.0);
+    for (i, elevation) in elevations.iter().enumerate() {
+        let x = i as f64 / (elevations.len() - 1) as f64 * width;
+        let y = height - 2.0 - (elevation - lowest) / range * (height - 4.0);
+        if i == 0 {
+            cr.move_to(x, y);
+        } else {
+            cr.line_to(x, y);
+        }
+    }
+    if let Err(e) = cr.stroke() {
+        println!("❌ Failed to draw elevation profile: {}", e);
+    }
+}
+
+// Popup names for route waypoints: "Start" and "Destination" at the ends, the waypoint's
+// own name in between
+fn waypoint_labels(waypoints: &[Waypoint]) -> Vec<String> {
+    let last = waypoints.len().saturating_sub(1);
+    waypoints
+        .iter()
+        .enumerate()
+        .map(|(i, wp)| {
+            // Names the user typed also label the ends
+            let typed = wp.name.as_deref().filter(|name| !message::is_placeholder_name(name));
+            match (i, typed) {
+                (0, Some(name)) => format!("Start: {}", name),
+                (0, None) => "Start".to_string(),
+                (i, Some(name)) if i == last => format!("Destination: {}", name),
+                (i, None) if i == last => "Destination".to_string(),
+                (i, _) => wp.name.clone().unwrap_or_else(|| format!("Point {}", i + 1)),
+            }
+        })
+        .collect()
+}
+
+// Fits the map view to the bounding box of the given locations, keeping `padding` pixels
+// clear around it and zooming in no further than `max_zoom`
+fn fit_map_to_locations(webview: &WebView, locations: &[Location], padding: u16, max_zoom: Option<u8>) {
+    let Some((southwest, northeast)) = bounding_box(locations) else {
+        return;
+    };
+    
+    // Leaflet expects a continuous longitude range, so unwrap boxes crossing the antimeridian
+    let mut east = northeast.longitude;
+    if southwest.longitude > east {
+        east += 360.0;
+    }
+    
+    let js_code = format!(
+        "if (window.mapInstance) {{ \
+            window.mapInstance.fitBounds([[{}, {}], [{}, {}]], {{ padding: [{}, {}]{} }}); \
+        }}",
+        southwest.latitude, southwest.longitude,
+        northeast.latitude, east,
+        padding, padding,
+        max_zoom.map(|zoom| format!(", maxZoom: {}", zoom)).unwrap_or_default()
+    );
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+fn load_map_html(webview: &WebView, source: &dyn MapSource) {
+    webview.load_html(&source.html(), source.base_uri().as_deref());
+}
+
+fn setup_event_handlers(
+    geo_service: Arc<Mutex<GeolocationService>>,
+    routing_service: Arc<RoutingService>,
+    location_entry: Entry,
+    search_button: Button,
+    directions_button: Button,
+    location_button: Button,
+    route_button: Button,
+    optimize_button: Button,
+    profile_combo: ComboBoxText,
+    exclude_checks: Vec<(CheckButton, &'static str)>,
+    clear_button: Button,
+    directions_toggle: Button,
+    import_gpx_button: Button,
+    webview: WebView,
     directions_box: GtkBox,
     directions_container: GtkBox,
     use_miles: Arc<Mutex<bool>>,
+    search_countries: Arc<Mutex<Vec<String>>>,
+    selected_result: Arc<Mutex<Option<GeocodeResult>>>,
+    busy: BusyIndicator,
 ) {
+    // Popover listing geocoding matches when a search is ambiguous
+    let results_popover = Popover::new(Some(&location_entry));
+    results_popover.set_position(gtk::PositionType::Bottom);
+    let results_list = ListBox::new();
+    results_list.set_selection_mode(gtk::SelectionMode::None);
+    results_popover.add(&results_list);
+    let search_results: Arc<Mutex<Vec<GeocodeResult>>> = Arc::new(Mutex::new(Vec::new()));
+    let search_generation = RequestGeneration::new();
+    
+    // Jump to whichever result the user picks from the list
+    {
+        let webview = webview.clone();
+        let results_popover = results_popover.clone();
+        let search_results = search_results.clone();
+        let selected_result = selected_result.clone();
+        
+        results_list.connect_row_activated(move |_, row| {
+            let results = search_results.lock().unwrap();
+            if let Some(result) = usize::try_from(row.index()).ok().and_then(|i| results.get(i)) {
+                show_search_result(&webview, result);
+                *selected_result.lock().unwrap() = Some(result.clone());
+            }
+            results_popover.popdown();
+        });
+    }
+    
     // Search location handler
     {
         let routing_service = routing_service.clone();
         let location_entry = location_entry.clone();
         let webview = webview.clone();
+        let results_popover = results_popover.clone();
+        let results_list = results_list.clone();
+        let search_results = search_results.clone();
+        let selected_result = selected_result.clone();
+        let search_generation = search_generation.clone();
+        let search_countries = search_countries.clone();
+        let busy = busy.clone();
         
-        search_button.connect_clicked(move |_| {
+        search_button.connect_clicked(move |button| {
             let query = location_entry.text().to_string();
             if query.is_empty() {
                 return;
             }
             
-            println!("🔍 Searching for: {}", query);
-            
-            let routing_service = routing_service.clone();
-            let webview = webview.clone();
-            
+            // A newer search supersedes this one before it touches the map
+            let ticket = search_generation.begin();
+            let search = run_search(
+                query,
+                search_countries.lock().unwrap().clone(),
+                routing_service.clone(),
+                webview.clone(),
+                results_popover.clone(),
+                results_list.clone(),
+                search_results.clone(),
+                selected_result.clone(),
+            );
+            let busy_guard = busy.begin(std::slice::from_ref(button));
             glib::spawn_future_local(async move {
-                match routing_service.geocode(&query).await {
-                    Ok(locations) => {
-                        if let Some(location) = locations.first() {
-                            println!("📍 Found: {:.6}, {:.6}", location.latitude, location.longitude);
-                            
-                            // Send to map
-                            let js_code = format!(
-                                "if (window.mapInstance) {{ \
-                                    window.mapInstance.setView([{}, {}], 15); \
-                                    var marker = L.marker([{}, {}]).addTo(window.mapInstance) \
-                                        .bindPopup('{}').openPopup(); \
-                                    if (!window.clickMarkers) window.clickMarkers = []; \
-                                    window.clickMarkers.push(marker); \
-                                }}",
-                                location.latitude, location.longitude,
-                                location.latitude, location.longitude,
-                                query.replace("'", "\\'")
-                            );
-                            
-                            webview.evaluate_javascript(
-                                &js_code,
-                                None,
-                                None,
-                                webkit2gtk::gio::Cancellable::NONE,
-                                |_| {}
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        println!("❌ Search error: {}", e);
-                    }
+                let _busy_guard = busy_guard;
+                if ticket.run(search).await.is_none() {
+                    println!("⏭️ Search superseded by a newer one");
                 }
             });
         });
@@ -592,52 +2933,38 @@ fn setup_event_handlers(
     // Enter key handler for search
     {
         let routing_service = routing_service.clone();
-        let location_entry = location_entry.clone();
         let webview = webview.clone();
+        let results_popover = results_popover.clone();
+        let results_list = results_list.clone();
+        let search_results = search_results.clone();
+        let selected_result = selected_result.clone();
+        let search_countries = search_countries.clone();
+        let busy = busy.clone();
+        let search_button = search_button.clone();
         
         location_entry.connect_activate(move |entry| {
             let query = entry.text().to_string();
-            if query.is_empty() {
+            if query.is_empty() || !search_button.is_sensitive() {
                 return;
             }
             
-            println!("🔍 Searching for: {}", query);
-            
-            let routing_service = routing_service.clone();
-            let webview = webview.clone();
-            
+            // A newer search supersedes this one before it touches the map
+            let ticket = search_generation.begin();
+            let search = run_search(
+                query,
+                search_countries.lock().unwrap().clone(),
+                routing_service.clone(),
+                webview.clone(),
+                results_popover.clone(),
+                results_list.clone(),
+                search_results.clone(),
+                selected_result.clone(),
+            );
+            let busy_guard = busy.begin(std::slice::from_ref(&search_button));
             glib::spawn_future_local(async move {
-                match routing_service.geocode(&query).await {
-                    Ok(locations) => {
-                        if let Some(location) = locations.first() {
-                            println!("📍 Found: {:.6}, {:.6}", location.latitude, location.longitude);
-                            
-                            // Send to map
-                            let js_code = format!(
-                                "if (window.mapInstance) {{ \
-                                    window.mapInstance.setView([{}, {}], 15); \
-                                    var marker = L.marker([{}, {}]).addTo(window.mapInstance) \
-                                        .bindPopup('{}').openPopup(); \
-                                    if (!window.clickMarkers) window.clickMarkers = []; \
-                                    window.clickMarkers.push(marker); \
-                                }}",
-                                location.latitude, location.longitude,
-                                location.latitude, location.longitude,
-                                query.replace("'", "\\'")
-                            );
-                            
-                            webview.evaluate_javascript(
-                                &js_code,
-                                None,
-                                None,
-                                webkit2gtk::gio::Cancellable::NONE,
-                                |_| {}
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        println!("❌ Search error: {}", e);
-                    }
+                let _busy_guard = busy_guard;
+                if ticket.run(search).await.is_none() {
+                    println!("⏭️ Search superseded by a newer one");
                 }
             });
         });
@@ -651,91 +2978,114 @@ fn setup_event_handlers(
         location_button.connect_clicked(move |_| {
             println!("📍 Getting current location...");
             
-            // Simulate getting location (London)
-            let location = Location::new(51.5074, -0.1278).with_accuracy(10.0);
-            
-            {
-                let mut service = geo_service.lock().unwrap();
-                service.update_location(location.clone());
-            }
-            
-            println!("✅ Location: {:.6}, {:.6}", location.latitude, location.longitude);
-            
-            // Send to map
-            let js_code = format!(
-                "if (window.mapInstance) {{ \
-                    window.mapInstance.setView([{}, {}], 15); \
-                    if (window.currentLocationMarker) {{ \
-                        window.mapInstance.removeLayer(window.currentLocationMarker); \
-                    }} \
-                    var marker = L.marker([{}, {}]).addTo(window.mapInstance) \
-                        .bindPopup('You are here!').openPopup(); \
-                    window.currentLocationMarker = marker; \
-                    if (!window.clickMarkers) window.clickMarkers = []; \
-                    window.clickMarkers.push(marker); \
-                }}",
-                location.latitude, location.longitude,
-                location.latitude, location.longitude
-            );
+            let geo_service = geo_service.clone();
+            let webview = webview.clone();
             
-            webview.evaluate_javascript(
-                &js_code,
-                None,
-                None,
-                webkit2gtk::gio::Cancellable::NONE,
-                |_| {}
-            );
+            glib::spawn_future_local(async move {
+                refresh_current_location(&geo_service, &webview).await;
+            });
         });
     }
     
-    // Route planning handler - uses clicked markers as waypoints
+    // Route planning handlers - use clicked markers as waypoints, optionally reordering them first
+    let exclude_checks = Rc::new(exclude_checks);
+    
+    // One-click directions from where the user is to the search result shown on the map
     {
+        let geo_service = geo_service.clone();
+        let webview = webview.clone();
+        let profile_combo = profile_combo.clone();
+        let exclude_checks = exclude_checks.clone();
+        
+        directions_button.connect_clicked(move |button| {
+            let Some(destination) = selected_result.lock().unwrap().clone() else {
+                show_error_dialog(button, "No destination", "Search for a place first, then ask for directions to it.");
+                return;
+            };
+            
+            let profile = profile_combo
+                .active_id()
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| "driving".to_string());
+            let exclude: Vec<&'static str> = exclude_checks
+                .iter()
+                .filter(|(check, _)| check.is_sensitive() && check.is_active())
+                .map(|(_, class)| *class)
+                .collect();
+            let known = geo_service.lock().unwrap().get_current_location().cloned();
+            if known.is_none() && !confirm_locate(button) {
+                return;
+            }
+            
+            let geo_service = geo_service.clone();
+            let webview = webview.clone();
+            let button = button.clone();
+            glib::spawn_future_local(async move {
+                let start = match known {
+                    Some(location) => location,
+                    None => match refresh_current_location(&geo_service, &webview).await {
+                        Some(location) => location,
+                        None => {
+                            show_error_dialog(&button, "Location unavailable", "Your location could not be found. Drop a start point on the map instead.");
+                            return;
+                        }
+                    },
+                };
+                
+                println!("🧭 Directions from {:.6}, {:.6} to {}", start.latitude, start.longitude, destination.display_name);
+                let waypoints = [
+                    Waypoint { lat: start.latitude, lng: start.longitude, name: Some("Your location".to_string()), kind: WaypointKind::Stop },
+                    Waypoint { lat: destination.location.latitude, lng: destination.location.longitude, name: Some(destination.display_name.clone()), kind: WaypointKind::Stop },
+                ];
+                
+                // Same path as clicked points, so the route is drawn and directions shown as usual
+                let js_code = format!(
+                    "if (window.clearMap) {{ window.clearMap(); }} {} {}",
+                    click_points_js(&waypoints),
+                    route_request_js(&profile, &exclude, false)
+                );
+                webview.evaluate_javascript(
+                    &js_code,
+                    None,
+                    None,
+                    webkit2gtk::gio::Cancellable::NONE,
+                    |_| {}
+                );
+            });
+        });
+    }
+    for (button, optimize) in [(route_button, false), (optimize_button, true)] {
         let routing_service = routing_service.clone();
         let webview = webview.clone();
+        let profile_combo = profile_combo.clone();
+        let exclude_checks = exclude_checks.clone();
         
-        route_button.connect_clicked(move |_| {
-            println!("🛣️ Planning route with clicked markers...");
+        button.connect_clicked(move |_| {
+            let profile = profile_combo
+                .active_id()
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| "driving".to_string());
+            
+            let exclude: Vec<&str> = exclude_checks
+                .iter()
+                .filter(|(check, _)| check.is_sensitive() && check.is_active())
+                .map(|(_, class)| *class)
+                .collect();
+            
+            if optimize {
+                println!("🔀 Optimizing stop order for {} route...", profile);
+            } else {
+                println!("🛣️ Planning {} route with clicked markers...", profile);
+            }
             
             // Get waypoints from the map by evaluating JavaScript
             let routing_service = routing_service.clone();
             let webview = webview.clone();
             
-            let js_code = r#"
-                console.log('Route button clicked');
-                console.log('clickMarkers:', window.clickMarkers);
-                console.log('clickMarkers length:', window.clickMarkers ? window.clickMarkers.length : 'undefined');
-                
-                if (window.clickMarkers && window.clickMarkers.length >= 2) {
-                    var waypoints = window.clickMarkers.map(function(marker) {
-                        var latlng = marker.getLatLng();
-                        return {lat: latlng.lat, lng: latlng.lng};
-                    });
-                    console.log('Sending waypoints:', waypoints);
-                    
-                    var message = {
-                        type: 'calculate_route',
-                        waypoints: waypoints
-                    };
-                    console.log('Sending message:', message);
-                    
-                    if (window.webkit && window.webkit.messageHandlers && window.webkit.messageHandlers.rustHandler) {
-                        // Convert message to JSON string before sending
-                        var jsonMessage = JSON.stringify(message);
-                        console.log('Sending JSON string:', jsonMessage);
-                        window.webkit.messageHandlers.rustHandler.postMessage(jsonMessage);
-                        console.log('Message sent to Rust');
-                    } else {
-                        console.error('Rust message handler not available');
-                        alert('Rust backend not connected');
-                    }
-                } else {
-                    console.log('Not enough markers for route');
-                    alert('Please click at least 2 points on the map first to create a route.');
-                }
-            "#;
+            let js_code = route_request_js(&profile, &exclude, optimize);
             
             webview.evaluate_javascript(
-                js_code,
+                &js_code,
                 None,
                 None,
                 webkit2gtk::gio::Cancellable::NONE,
@@ -759,6 +3109,88 @@ fn setup_event_handlers(
         });
     }
     
+    // GPX import handler - replays a recorded track into the location history
+    {
+        let geo_service = geo_service.clone();
+        let webview = webview.clone();
+        let location_button = location_button.clone();
+        let use_miles = use_miles.clone();
+        
+        import_gpx_button.connect_clicked(move |button| {
+            let Some(path) = choose_file(button, "Import GPX Track", FileChooserAction::Open, None) else {
+                return;
+            };
+            
+            let gpx = match std::fs::read_to_string(&path) {
+                Ok(gpx) => gpx,
+                Err(e) => {
+                    println!("❌ Failed to read {}: {}", path.display(), e);
+                    return;
+                }
+            };
+            
+            // Clean up the whole track before it is replayed into the capped location history
+            let mut imported = GeolocationService::with_capacity(usize::MAX);
+            let count = match imported.import_gpx(&gpx) {
+                Ok(count) => count,
+                Err(e) => {
+                    println!("❌ GPX import failed: {}", e);
+                    return;
+                }
+            };
+            imported.smooth_history(GPX_MIN_POINT_SPACING_M);
+            imported.average_history(GPX_AVERAGE_WINDOW);
+            let track = imported.get_location_history().to_vec();
+            println!("📥 Imported {} track points from {} ({} after smoothing)", count, path.display(), track.len());
+            
+            // Climb and drop come from the raw heights, which the smoothing above leaves as recorded
+            let use_miles_val = *use_miles.lock().unwrap();
+            let mut summary = format_distance(imported.total_distance(), use_miles_val);
+            let elevations: Vec<f64> = track.iter().filter_map(|location| location.elevation).collect();
+            if !elevations.is_empty() {
+                let (ascent, descent) = routing::elevation_stats(&elevations);
+                summary.push_str(&format!(" · ↑ {} ↓ {}", format_height(ascent, use_miles_val), format_height(descent, use_miles_val)));
+            }
+            println!("📏 Track: {}", summary);
+            
+            {
+                let mut service = geo_service.lock().unwrap();
+                for location in &track {
+                    service.update_location(location.clone());
+                }
+                location_button.set_sensitive(service.get_current_location().is_some());
+            }
+            
+            // Draw the track and fit the map to it
+            let points: Vec<[f64; 2]> = track
+                .iter()
+                .map(|location| [location.latitude, location.longitude])
+                .collect();
+            let js_code = format!(
+                "if (window.mapInstance) {{ \
+                    if (window.trackLayer) {{ \
+                        window.mapInstance.removeLayer(window.trackLayer); \
+                    }} \
+                    window.trackLayer = L.polyline({}, {{ color: '#e4572e', weight: 4, opacity: 0.8 }}) \
+                        .addTo(window.mapInstance) \
+                        .bindPopup({}); \
+                }}",
+                serde_json::to_string(&points).unwrap_or_else(|_| "[]".to_string()),
+                js_string_literal(&summary)
+            );
+            
+            webview.evaluate_javascript(
+                &js_code,
+                None,
+                None,
+                webkit2gtk::gio::Cancellable::NONE,
+                |_| {}
+            );
+            
+            fit_map_to_locations(&webview, &track, FIT_PADDING_PX, None);
+        });
+    }
+    
     // Clear map handler
     {
         let webview = webview.clone();
@@ -776,19 +3208,582 @@ fn setup_event_handlers(
             );
             
             // Clear directions pane and hide it
-            let children: Vec<gtk::Widget> = directions_box.children();
-            for child in children {
-                directions_box.remove(&child);
-            }
-            
-            let directions_label = Label::new(Some("Click 'Plan Route' to see turn-by-turn directions"));
-            directions_label.set_line_wrap(true);
-            directions_label.set_xalign(0.0);
-            directions_box.pack_start(&directions_label, false, false, 0);
-            directions_box.show_all();
+            reset_directions(&directions_box);
             
             // Hide directions pane
             directions_container.set_visible(false);
         });
     }
-}
\ No newline at end of file
+}
+
+// Places the waypoints on the map as if clicked, keeping names the user gave them
+fn click_points_js(waypoints: &[Waypoint]) -> String {
+    let clicks: String = waypoints
+        .iter()
+        .map(|wp| {
+            let name = wp.name.as_deref().filter(|name| !message::is_placeholder_name(name));
+            format!(
+                "window.addClickPoint(L.latLng({}, {}), {}, {});",
+                wp.lat,
+                wp.lng,
+                name.map(js_string_literal).unwrap_or_else(|| "null".to_string()),
+                serde_json::to_string(&wp.kind).unwrap_or_else(|_| "null".to_string())
+            )
+        })
+        .collect();
+    format!("if (window.addClickPoint) {{ {} }}", clicks)
+}
+
+// Builds the script that collects the clicked markers and asks Rust to plan a route,
+// optionally reordering the stops first
+fn route_request_js(profile: &str, exclude: &[&str], optimize: bool) -> String {
+    format!(r#"
+        var routeProfile = {};
+        var routeExclude = {};
+        var routeOptimize = {};
+        console.log('Route button clicked');
+        console.log('clickMarkers:', window.clickMarkers);
+        console.log('clickMarkers length:', window.clickMarkers ? window.clickMarkers.length : 'undefined');
+        
+        // Only route points; search pins are never stops
+        var routeMarkers = (window.clickMarkers || []).filter(function(marker) {{
+            return marker.category !== 'search';
+        }});
+        if (routeMarkers.length >= 2) {{
+            var waypoints = routeMarkers.map(function(marker) {{
+                var latlng = marker.getLatLng();
+                return {{lat: latlng.lat, lng: latlng.lng, name: marker.waypointName || null, kind: marker.waypointKind || 'stop'}};
+            }});
+            console.log('Sending waypoints:', waypoints);
+            
+            var message = {{
+                type: 'calculate_route',
+                waypoints: waypoints,
+                profile: routeProfile,
+                exclude: routeExclude,
+                optimize: routeOptimize
+            }};
+            console.log('Sending message:', message);
+            
+            if (window.webkit && window.webkit.messageHandlers && window.webkit.messageHandlers.rustHandler) {{
+                // Dragging a stop later re-plans with the same options
+                window.lastRoute = {{profile: routeProfile, exclude: routeExclude}};
+                
+                // Convert message to JSON string before sending
+                var jsonMessage = JSON.stringify(message);
+                console.log('Sending JSON string:', jsonMessage);
+                window.webkit.messageHandlers.rustHandler.postMessage(jsonMessage);
+                console.log('Message sent to Rust');
+            }} else {{
+                console.error('Rust message handler not available');
+                alert('Rust backend not connected');
+            }}
+        }} else {{
+            console.log('Not enough markers for route');
+            alert('Please click at least 2 points on the map first to create a route.');
+        }}
+    "#, js_string_literal(profile), serde_json::to_string(exclude).unwrap_or_else(|_| "[]".to_string()), optimize)
+}
+
+// Moves the arrow marking the active maneuver, rotated to the heading it leaves on.
+// Steps without a heading, such as the arrival, just remove it.
+fn show_maneuver_arrow(webview: &WebView, instruction: Option<&routing::RouteInstruction>) {
+    let arrow = match instruction.and_then(|instruction| Some((&instruction.location, instruction.bearing?))) {
+        Some((location, bearing)) => format!(
+            "window.maneuverArrow = L.marker([{}, {}], {{ \
+                icon: L.divIcon({{ \
+                    className: 'maneuver-arrow', \
+                    html: '<svg width=\"28\" height=\"28\" viewBox=\"0 0 28 28\" style=\"transform: rotate({:.0}deg)\">\
+                        <path d=\"M14 2 L24 25 L14 19 L4 25 Z\" fill=\"#e4572e\" stroke=\"white\" stroke-width=\"2\"/></svg>', \
+                    iconSize: [28, 28], \
+                    iconAnchor: [14, 14] \
+                }}), \
+                interactive: false, \
+                keyboard: false, \
+                zIndexOffset: 1000 \
+            }}).addTo(window.mapInstance);",
+            location.latitude, location.longitude, bearing
+        ),
+        None => String::new(),
+    };
+    let js_code = format!(
+        "if (window.mapInstance) {{ \
+            if (window.maneuverArrow) {{ \
+                window.mapInstance.removeLayer(window.maneuverArrow); \
+                window.maneuverArrow = null; \
+            }} \
+            {} \
+        }}",
+        arrow
+    );
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+// Pans to a maneuver and flashes a marker there, replacing any earlier one
+fn highlight_step(webview: &WebView, location: &Location) {
+    let js_code = format!(
+        "if (window.mapInstance) {{ \
+            if (window.stepHighlight) {{ \
+                window.mapInstance.removeLayer(window.stepHighlight); \
+                clearTimeout(window.stepHighlightTimer); \
+            }} \
+            window.mapInstance.panTo([{lat}, {lng}]); \
+            var highlight = L.circleMarker([{lat}, {lng}], {{ \
+                radius: 10, color: '#e4572e', weight: 3, fillOpacity: 0.4 \
+            }}).addTo(window.mapInstance); \
+            window.stepHighlight = highlight; \
+            window.stepHighlightTimer = setTimeout(function() {{ \
+                window.mapInstance.removeLayer(highlight); \
+                if (window.stepHighlight === highlight) window.stepHighlight = null; \
+            }}, {duration}); \
+        }}",
+        lat = location.latitude,
+        lng = location.longitude,
+        duration = STEP_HIGHLIGHT_DURATION.as_millis()
+    );
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+// Themed icon shown next to a direction step for its maneuver hint
+fn step_icon_name(maneuver_icon: &str) -> &'static str {
+    match maneuver_icon {
+        "turn-left" | "turn-slight-left" | "turn-sharp-left" | "fork-left" => "go-previous-symbolic",
+        "turn-right" | "turn-slight-right" | "turn-sharp-right" | "fork-right" => "go-next-symbolic",
+        "uturn" => "edit-undo-symbolic",
+        "roundabout" => "view-refresh-symbolic",
+        "arrive" => "mark-location-symbolic",
+        _ => "go-up-symbolic",
+    }
+}
+
+// Detects the current position, records it and shows it on the map; when detection fails
+// London is shown instead. Returns the position only when it was actually detected.
+async fn refresh_current_location(geo_service: &Arc<Mutex<GeolocationService>>, webview: &WebView) -> Option<Location> {
+    let detected = geolocation::detect_current().await;
+    let recorded = geo_service.lock().unwrap().record_detection(detected);
+    match recorded {
+        Ok(location) => {
+            println!("✅ Location: {:.6}, {:.6}", location.latitude, location.longitude);
+            show_current_location(webview, &location);
+            Some(location)
+        }
+        // London is only shown, never recorded, so it can't skew speed or heading
+        Err(e) => {
+            println!("⚠️ Location detection failed ({}), showing London instead", e);
+            show_current_location(webview, &Location::new(DEFAULT_LATITUDE, DEFAULT_LONGITUDE));
+            None
+        }
+    }
+}
+
+// One follow-mode poll: records and shows a real fix, and leaves the map alone when
+// detection fails so one bad poll doesn't throw the view to the fallback position
+async fn follow_current_location(geo_service: &Arc<Mutex<GeolocationService>>, webview: &WebView) -> Option<Location> {
+    let detected = geolocation::detect_current().await;
+    let recorded = geo_service.lock().unwrap().record_detection(detected);
+    match recorded {
+        Ok(location) => {
+            show_current_location(webview, &location);
+            Some(location)
+        }
+        Err(e) => {
+            println!("⚠️ Location detection failed ({}), skipping this update", e);
+            None
+        }
+    }
+}
+
+// Speed readout for follow mode, e.g. "12 mph" or "19 km/h"
+fn format_speed(mps: f64, use_miles: bool) -> String {
+    if use_miles {
+        format!("{:.0} mph", units::mps_to_mph(mps))
+    } else {
+        format!("{:.0} km/h", units::mps_to_kmh(mps))
+    }
+}
+
+// Desktop notification that follow mode reached the route's destination
+fn notify_arrival(app: &Application, destination: Option<&str>) {
+    println!("🏁 Arrived at {}", destination.unwrap_or("destination"));
+    let notification = gtk::gio::Notification::new("Arrived");
+    notification.set_body(Some(&match destination {
+        Some(name) => format!("You have reached {}", name),
+        None => "You have reached your destination".to_string(),
+    }));
+    app.send_notification(Some("arrival"), &notification);
+}
+
+// My Location is only useful once some position has been recorded
+fn update_location_button(button: &Button, geo_service: &Arc<Mutex<GeolocationService>>) {
+    button.set_sensitive(geo_service.lock().unwrap().get_current_location().is_some());
+}
+
+// Centers the map on the location and moves the "You are here" marker and
+// its accuracy circle to it
+fn show_current_location(webview: &WebView, location: &Location) {
+    let accuracy_circle = match location.accuracy {
+        Some(accuracy) => format!(
+            "window.currentAccuracyCircle = L.circle([{}, {}], {{ \
+                radius: {}, color: '#007cba', weight: 1, fillColor: '#007cba', fillOpacity: 0.1 \
+            }}).addTo(window.mapInstance);",
+            location.latitude, location.longitude, accuracy
+        ),
+        None => String::new(),
+    };
+    
+    let js_code = format!(
+        "if (window.mapInstance) {{ \
+            window.mapInstance.setView([{}, {}], 15); \
+            if (!window.clickMarkers) window.clickMarkers = []; \
+            if (window.currentLocationMarker) {{ \
+                window.mapInstance.removeLayer(window.currentLocationMarker); \
+                var previous = window.clickMarkers.indexOf(window.currentLocationMarker); \
+                if (previous !== -1) window.clickMarkers.splice(previous, 1); \
+            }} \
+            if (window.currentAccuracyCircle) {{ \
+                window.mapInstance.removeLayer(window.currentAccuracyCircle); \
+                window.currentAccuracyCircle = null; \
+            }} \
+            {} \
+            var marker = L.marker([{}, {}]).addTo(window.mapInstance) \
+                .bindPopup('You are here!').openPopup(); \
+            marker.category = 'location'; \
+            window.currentLocationMarker = marker; \
+            window.clickMarkers.push(marker); \
+        }}",
+        location.latitude, location.longitude,
+        accuracy_circle,
+        location.latitude, location.longitude
+    );
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+// Reads the visible map area as (southwest, northeast) corners, if the map is ready
+async fn map_viewbox(webview: &WebView) -> Option<(Location, Location)> {
+    let js_code = "window.mapInstance ? window.mapInstance.getBounds().toBBoxString() : ''";
+    let value = match webview.evaluate_javascript_future(js_code, None, None).await {
+        Ok(value) => value.to_string(),
+        Err(e) => {
+            println!("⚠️ Could not read map bounds: {}", e);
+            return None;
+        }
+    };
+    
+    // Leaflet's bbox string is "west,south,east,north"
+    let parts: Vec<f64> = value.split(',').filter_map(|part| part.trim().parse().ok()).collect();
+    match parts[..] {
+        [west, south, east, north] => Some((Location::new(south, west), Location::new(north, east))),
+        _ => None,
+    }
+}
+
+// Center latitude and zoom of the map, read from the page
+async fn map_view(webview: &WebView) -> Option<(f64, f64)> {
+    let js_code = "window.mapInstance ? window.mapInstance.getCenter().lat + ',' + window.mapInstance.getZoom() : ''";
+    let value = match webview.evaluate_javascript_future(js_code, None, None).await {
+        Ok(value) => value.to_string(),
+        Err(e) => {
+            println!("⚠️ Could not read map view: {}", e);
+            return None;
+        }
+    };
+    
+    let (lat, zoom) = value.split_once(',')?;
+    Some((lat.trim().parse().ok()?, zoom.trim().parse().ok()?))
+}
+
+async fn run_search(
+    query: String,
+    countries: Vec<String>,
+    routing_service: Arc<RoutingService>,
+    webview: WebView,
+    results_popover: Popover,
+    results_list: ListBox,
+    search_results: Arc<Mutex<Vec<GeocodeResult>>>,
+    selected_result: Arc<Mutex<Option<GeocodeResult>>>,
+) {
+    println!("🔍 Searching for: {}", query);
+    
+    // Prefer places in the part of the map being looked at
+    let viewbox = map_viewbox(&webview).await;
+    let countries: Vec<&str> = countries.iter().map(String::as_str).collect();
+    match routing_service.geocode(&query, viewbox, &countries).await {
+        Ok(results) => {
+            match results.len() {
+                0 => {
+                    println!("❌ No results for: {}", query);
+                    show_error_dialog(&webview, "No places found", &format!("Nothing matched \"{}\".", query));
+                }
+                1 => {
+                    show_search_result(&webview, &results[0]);
+                    *selected_result.lock().unwrap() = results.into_iter().next();
+                }
+                _ => {
+                    println!("📋 {} results for: {}", results.len(), query);
+                    
+                    // Replace the previous matches with the new ones
+                    for child in results_list.children() {
+                        results_list.remove(&child);
+                    }
+                    
+                    let results: Vec<GeocodeResult> = results.into_iter().take(MAX_SEARCH_RESULTS).collect();
+                    for result in &results {
+                        let label = Label::new(Some(&result.display_name));
+                        label.set_line_wrap(true);
+                        label.set_max_width_chars(50);
+                        label.set_xalign(0.0);
+                        label.set_margin_start(5);
+                        label.set_margin_end(5);
+                        label.set_margin_top(5);
+                        label.set_margin_bottom(5);
+                        results_list.add(&label);
+                    }
+                    
+                    *search_results.lock().unwrap() = results;
+                    results_list.show_all();
+                    results_popover.popup();
+                }
+            }
+        }
+        Err(e) => {
+            println!("❌ Search error: {}", e);
+            if e.is_timeout() {
+                show_error_dialog(&webview, "Request timed out", "The search server did not answer in time. Please try again.");
+                return;
+            }
+            let message = if e.is_transient() {
+                format!("Could not reach the search server ({}). Please try again.", e)
+            } else {
+                format!("Search failed: {}", e)
+            };
+            show_error_dialog(&webview, "Search failed", &message);
+        }
+    }
+}
+
+fn show_search_result(webview: &WebView, result: &GeocodeResult) {
+    let location = &result.location;
+    println!("📍 Found: {} ({:.6}, {:.6})", result.display_name, location.latitude, location.longitude);
+    show_place(webview, location, &result.display_name);
+}
+
+// Draws GeoJSON over the map and fits the view to it. The data is checked first, so
+// invalid input returns an error instead of reaching the page.
+fn add_geojson_overlay(webview: &WebView, geojson: &str, style: OverlayStyle) -> Result<(), OverlayError> {
+    let data = validate_overlay(geojson)?;
+    let js_code = format!(
+        "if (window.addGeoJsonOverlay) {{ window.addGeoJsonOverlay({}, {}, {}); }}",
+        data,
+        serde_json::to_string(&style).unwrap_or_else(|_| "{}".to_string()),
+        FIT_PADDING_PX
+    );
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+    Ok(())
+}
+
+fn clear_overlays(webview: &WebView) {
+    webview.evaluate_javascript(
+        "if (window.clearOverlays) { window.clearOverlays(); }",
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+// Turns the map so `heading` (degrees from north) points up, or back to north-up for None
+fn set_map_rotation(webview: &WebView, heading: Option<f64>) {
+    let js_code = format!(
+        "if (window.setMapRotation) {{ window.setMapRotation({}); }}",
+        heading.map_or_else(|| "null".to_string(), |degrees| format!("{:.1}", degrees))
+    );
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+// Switches the page between clustered and individual search pins. Route stops stay
+// individual either way.
+fn set_clustering(webview: &WebView, enabled: bool) {
+    let js_code = format!("if (window.setClustering) {{ window.setClustering({}); }}", enabled);
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+// Centers the map on a named place and drops a marker with the name as its popup
+fn show_place(webview: &WebView, location: &Location, name: &str) {
+    // Send to map
+    let js_code = format!(
+        "if (window.mapInstance) {{ \
+            window.mapInstance.setView([{lat}, {lng}], 15); \
+            if (window.addSearchMarker) {{ window.addSearchMarker({lat}, {lng}, {name}); }} \
+        }}",
+        lat = location.latitude,
+        lng = location.longitude,
+        name = js_string_literal(name)
+    );
+    
+    webview.evaluate_javascript(
+        &js_code,
+        None,
+        None,
+        webkit2gtk::gio::Cancellable::NONE,
+        |_| {}
+    );
+}
+
+fn setup_favorites(
+    favorite_button: Button,
+    favorites_menu_button: MenuButton,
+    selected_result: Arc<Mutex<Option<GeocodeResult>>>,
+    webview: WebView,
+) {
+    let store = Arc::new(Mutex::new(FavoritesStore::load_default()));
+    
+    let favorites_popover = Popover::new(Some(&favorites_menu_button));
+    let favorites_box = GtkBox::new(Orientation::Vertical, 5);
+    favorites_box.set_margin_start(10);
+    favorites_box.set_margin_end(10);
+    favorites_box.set_margin_top(10);
+    favorites_box.set_margin_bottom(10);
+    favorites_popover.add(&favorites_box);
+    favorites_menu_button.set_popover(Some(&favorites_popover));
+    
+    // Star button saves the result currently shown on the map
+    {
+        let store = store.clone();
+        favorite_button.connect_clicked(move |_| {
+            let Some(result) = selected_result.lock().unwrap().clone() else {
+                println!("⭐ No search result to save");
+                return;
+            };
+            
+            let mut store = store.lock().unwrap();
+            store.add(Favorite {
+                name: result.display_name.clone(),
+                location: result.location.clone(),
+            });
+            
+            match store.save() {
+                Ok(()) => println!("⭐ Saved favorite: {} ({})", result.display_name, store.path().display()),
+                Err(e) => println!("❌ Failed to save favorites: {}", e),
+            }
+        });
+    }
+    
+    // Rebuild the list each time the popover opens so it reflects new stars
+    favorites_menu_button.connect_toggled(move |button| {
+        if !button.is_active() {
+            return;
+        }
+        
+        populate_favorites(&favorites_box, &favorites_popover, &store, &webview);
+    });
+}
+
+fn populate_favorites(
+    favorites_box: &GtkBox,
+    favorites_popover: &Popover,
+    store: &Arc<Mutex<FavoritesStore>>,
+    webview: &WebView,
+) {
+    for child in favorites_box.children() {
+        favorites_box.remove(&child);
+    }
+    
+    let favorites = store.lock().unwrap().list().to_vec();
+    if favorites.is_empty() {
+        let empty_label = Label::new(Some("No favorites yet - search for a place and click the star"));
+        empty_label.set_line_wrap(true);
+        empty_label.set_max_width_chars(30);
+        favorites_box.pack_start(&empty_label, false, false, 0);
+    }
+    
+    for favorite in favorites {
+        let row = GtkBox::new(Orientation::Horizontal, 5);
+        
+        let open_button = Button::with_label(&favorite.name);
+        open_button.set_relief(gtk::ReliefStyle::None);
+        if let Some(label) = open_button.child().and_then(|w| w.downcast::<Label>().ok()) {
+            label.set_ellipsize(gtk::pango::EllipsizeMode::End);
+            label.set_max_width_chars(40);
+            label.set_xalign(0.0);
+        }
+        {
+            let webview = webview.clone();
+            let favorites_popover = favorites_popover.clone();
+            let favorite = favorite.clone();
+            open_button.connect_clicked(move |_| {
+                show_place(&webview, &favorite.location, &favorite.name);
+                favorites_popover.popdown();
+            });
+        }
+        
+        let remove_button = Button::new();
+        remove_button.set_image(Some(&Image::from_icon_name(Some("edit-delete"), gtk::IconSize::Button)));
+        remove_button.set_relief(gtk::ReliefStyle::None);
+        remove_button.set_tooltip_text(Some("Remove favorite"));
+        {
+            let favorites_box = favorites_box.clone();
+            let favorites_popover = favorites_popover.clone();
+            let store = store.clone();
+            let webview = webview.clone();
+            let name = favorite.name.clone();
+            remove_button.connect_clicked(move |_| {
+                {
+                    let mut store = store.lock().unwrap();
+                    store.remove(&name);
+                    if let Err(e) = store.save() {
+                        println!("❌ Failed to save favorites: {}", e);
+                    }
+                }
+                populate_favorites(&favorites_box, &favorites_popover, &store, &webview);
+            });
+        }
+        
+        row.pack_start(&open_button, true, true, 0);
+        row.pack_end(&remove_button, false, false, 0);
+        favorites_box.pack_start(&row, false, false, 0);
+    }
+    
+    favorites_box.show_all();
+}
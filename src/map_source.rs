@@ -0,0 +1,73 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::PathBuf;
+
+const EMBEDDED_MAP_HTML: &str = include_str!("map_embedded.html");
+
+// Supplies the Leaflet page shown in the map WebView
+pub trait MapSource {
+    fn html(&self) -> Cow<'_, str>;
+
+    // Base URI for resolving relative links in the page, if it has one
+    fn base_uri(&self) -> Option<String> {
+        None
+    }
+}
+
+// Map page read from disk, so it can be edited without rebuilding
+pub struct FileMapSource(pub PathBuf);
+
+impl MapSource for FileMapSource {
+    fn html(&self) -> Cow<'_, str> {
+        match fs::read_to_string(&self.0) {
+            Ok(html) => Cow::Owned(html),
+            Err(e) => {
+                eprintln!("⚠️ Could not read {} ({}), using the embedded map", self.0.display(), e);
+                Cow::Borrowed(EMBEDDED_MAP_HTML)
+            }
+        }
+    }
+
+    fn base_uri(&self) -> Option<String> {
+        Some(format!("file://{}", self.0.display()))
+    }
+}
+
+// Map page compiled into the binary
+pub struct EmbeddedMapSource;
+
+impl MapSource for EmbeddedMapSource {
+    fn html(&self) -> Cow<'_, str> {
+        Cow::Borrowed(EMBEDDED_MAP_HTML)
+    }
+}
+
+// Prefers the page at the given path when it exists, otherwise the embedded one
+pub fn default_map_source(path: PathBuf) -> Box<dyn MapSource> {
+    if path.exists() {
+        Box::new(FileMapSource(path))
+    } else {
+        Box::new(EmbeddedMapSource)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_map_source() {
+        let path = std::env::temp_dir().join(format!("map-rs-test-{}-map.html", std::process::id()));
+
+        let source = default_map_source(path.clone());
+        assert_eq!(source.html(), EMBEDDED_MAP_HTML);
+        assert_eq!(source.base_uri(), None);
+
+        fs::write(&path, "<html>custom</html>").unwrap();
+        let source = default_map_source(path.clone());
+        assert_eq!(source.html(), "<html>custom</html>");
+        assert_eq!(source.base_uri(), Some(format!("file://{}", path.display())));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use serde::Deserialize;
+use std::fmt;
+use crate::geolocation::{Location, LocationUpdate};
+use crate::routing::{Waypoint, WaypointKind};
+
+// Message types the map page posts to the "rustHandler" script message handler
+const MESSAGE_TYPES: &[&str] = &["calculate_route", "snap_point", "reverse_geocode", "measure", "markers_changed", "location_click", "location_update", "viewport_changed", "insert_waypoint", "name_waypoint", "context_menu"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct LatLng {
+    pub lat: f64,
+    pub lng: f64,
+}
+
+impl From<LatLng> for Location {
+    fn from(point: LatLng) -> Self {
+        Location::new(point.lat, point.lng)
+    }
+}
+
+// A route stop from a calculate_route message; named when the user typed a name for it
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RoutePoint {
+    pub lat: f64,
+    pub lng: f64,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub kind: WaypointKind,
+}
+
+// A route marker as listed in a markers_changed message, in route order
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MarkerInfo {
+    pub id: u64,
+    pub lat: f64,
+    pub lng: f64,
+    #[serde(default)]
+    pub label: Option<String>, // reverse-geocoded address, once known
+    #[serde(default)]
+    pub kind: WaypointKind,
+}
+
+// A message from the page, tagged by its "type" field. Marker ids are Leaflet's L.stamp values.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IncomingMessage {
+    CalculateRoute {
+        waypoints: Vec<RoutePoint>,
+        #[serde(default = "default_profile")]
+        profile: String,
+        #[serde(default)]
+        exclude: Vec<String>,
+        #[serde(default)]
+        optimize: bool,
+    },
+    SnapPoint {
+        id: u64,
+        lat: f64,
+        lng: f64,
+    },
+    ReverseGeocode {
+        id: u64,
+        lat: f64,
+        lng: f64,
+    },
+    Measure {
+        points: Vec<LatLng>,
+    },
+    MarkersChanged {
+        count: u64,
+        #[serde(default)]
+        markers: Vec<MarkerInfo>,
+    },
+    LocationClick {
+        lat: f64,
+        lng: f64,
+        #[serde(default)]
+        geocode: bool, // also look up the address of the clicked point
+    },
+    LocationUpdate(LocationUpdate),
+    ViewportChanged {
+        lat: f64, // map center
+        lng: f64,
+        zoom: f64,
+    },
+    InsertWaypoint {
+        lat: f64, // where the route line was clicked
+        lng: f64,
+    },
+    NameWaypoint {
+        lat: f64, // where the map was double-clicked
+        lng: f64,
+        x: f64, // the same point in page pixels, for placing the name entry
+        y: f64,
+    },
+    ContextMenu {
+        lat: f64, // where the map was right-clicked
+        lng: f64,
+        x: f64, // the same point in page pixels, for placing the menu
+        y: f64,
+    },
+}
+
+fn default_profile() -> String {
+    "driving".to_string()
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    Malformed(serde_json::Error), // not JSON at all
+    MissingType,
+    UnknownType(String),
+    InvalidFields(String, serde_json::Error), // known type whose fields don't match
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Malformed(e) => write!(f, "Message is not valid JSON: {}", e),
+            MessageError::MissingType => write!(f, "Message has no \"type\" field"),
+            MessageError::UnknownType(msg_type) => write!(f, "Unknown message type: {}", msg_type),
+            MessageError::InvalidFields(msg_type, e) => write!(f, "Invalid {} message: {}", msg_type, e),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+// Parses the JSON string posted by the page
+pub fn parse(js_string: &str) -> Result<IncomingMessage, MessageError> {
+    let value: serde_json::Value = serde_json::from_str(js_string).map_err(MessageError::Malformed)?;
+    let msg_type = value
+        .get("type")
+        .and_then(|v| v.as_str())
+        .ok_or(MessageError::MissingType)?
+        .to_string();
+
+    if !MESSAGE_TYPES.contains(&msg_type.as_str()) {
+        return Err(MessageError::UnknownType(msg_type));
+    }
+
+    serde_json::from_value(value).map_err(|e| MessageError::InvalidFields(msg_type, e))
+}
+
+// Route stops from a calculate_route message; unnamed ones become "Point 1", "Point 2"...
+pub fn numbered_waypoints(points: &[RoutePoint]) -> Vec<Waypoint> {
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, point)| Waypoint {
+            lat: point.lat,
+            lng: point.lng,
+            name: Some(point.name.clone().unwrap_or_else(|| format!("Point {}", i + 1))),
+            kind: point.kind,
+        })
+        .collect()
+}
+
+// Whether a waypoint name is numbered_waypoints' placeholder rather than one the user typed
+pub fn is_placeholder_name(name: &str) -> bool {
+    name.strip_prefix("Point ").is_some_and(|n| n.parse::<usize>().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_valid_messages() {
+        let message = parse(r#"{"type":"calculate_route","waypoints":[{"lat":51.5,"lng":-0.1},{"lat":51.4,"lng":-0.2}],"profile":"cycling"}"#).unwrap();
+        assert_eq!(message, IncomingMessage::CalculateRoute {
+            waypoints: vec![
+                RoutePoint { lat: 51.5, lng: -0.1, name: None, kind: WaypointKind::Stop },
+                RoutePoint { lat: 51.4, lng: -0.2, name: None, kind: WaypointKind::Stop },
+            ],
+            profile: "cycling".to_string(),
+            exclude: Vec::new(),
+            optimize: false,
+        });
+
+        let message = parse(r#"{"type":"snap_point","id":42,"lat":1.5,"lng":2.5}"#).unwrap();
+        assert_eq!(message, IncomingMessage::SnapPoint { id: 42, lat: 1.5, lng: 2.5 });
+
+        let message = parse(r#"{"type":"markers_changed","count":3}"#).unwrap();
+        assert_eq!(message, IncomingMessage::MarkersChanged { count: 3, markers: Vec::new() });
+
+        let message = parse(r#"{"type":"markers_changed","count":1,"markers":[{"id":7,"lat":1.5,"lng":2.5,"label":null,"kind":"via"}]}"#).unwrap();
+        assert_eq!(message, IncomingMessage::MarkersChanged {
+            count: 1,
+            markers: vec![MarkerInfo { id: 7, lat: 1.5, lng: 2.5, label: None, kind: WaypointKind::Via }],
+        });
+
+        let message = parse(r#"{"type":"location_click","lat":1.5,"lng":2.5}"#).unwrap();
+        assert_eq!(message, IncomingMessage::LocationClick { lat: 1.5, lng: 2.5, geocode: false });
+
+        let message = parse(r#"{"type":"viewport_changed","lat":51.5,"lng":-0.12,"zoom":13}"#).unwrap();
+        assert_eq!(message, IncomingMessage::ViewportChanged { lat: 51.5, lng: -0.12, zoom: 13.0 });
+
+        let message = parse(r#"{"type":"name_waypoint","lat":1.5,"lng":2.5,"x":100,"y":40.5}"#).unwrap();
+        assert_eq!(message, IncomingMessage::NameWaypoint { lat: 1.5, lng: 2.5, x: 100.0, y: 40.5 });
+
+        let message = parse(r#"{"type":"context_menu","lat":1.5,"lng":2.5,"x":10,"y":20}"#).unwrap();
+        assert_eq!(message, IncomingMessage::ContextMenu { lat: 1.5, lng: 2.5, x: 10.0, y: 20.0 });
+
+        let message = parse(r#"{"type":"insert_waypoint","lat":1.5,"lng":2.5}"#).unwrap();
+        assert_eq!(message, IncomingMessage::InsertWaypoint { lat: 1.5, lng: 2.5 });
+
+        match parse(r#"{"type":"location_update","lat":1.5,"lng":2.5,"accuracy":12}"#).unwrap() {
+            IncomingMessage::LocationUpdate(update) => assert_eq!((update.lat, update.lng, update.accuracy), (1.5, 2.5, Some(12.0))),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_messages() {
+        assert!(matches!(parse("[object Object]"), Err(MessageError::Malformed(_))));
+        assert!(matches!(parse(r#"{"count":3}"#), Err(MessageError::MissingType)));
+        assert!(matches!(parse(r#"{"type":"teleport"}"#), Err(MessageError::UnknownType(t)) if t == "teleport"));
+        assert!(matches!(
+            parse(r#"{"type":"snap_point","id":"abc","lat":1,"lng":2}"#),
+            Err(MessageError::InvalidFields(t, _)) if t == "snap_point"
+        ));
+    }
+
+    #[test]
+    fn test_numbered_waypoints() {
+        let waypoints = numbered_waypoints(&[
+            RoutePoint { lat: 1.0, lng: 2.0, name: Some("Home".to_string()), kind: WaypointKind::Stop },
+            RoutePoint { lat: 3.0, lng: 4.0, name: None, kind: WaypointKind::Via },
+        ]);
+        assert_eq!(waypoints[0].name.as_deref(), Some("Home"));
+        assert_eq!(waypoints[1].name.as_deref(), Some("Point 2"));
+        assert_eq!((waypoints[1].lat, waypoints[1].lng), (3.0, 4.0));
+        assert_eq!(waypoints[1].kind, WaypointKind::Via);
+
+        assert!(is_placeholder_name("Point 12"));
+        assert!(!is_placeholder_name("Home"));
+        assert!(!is_placeholder_name("Point Reyes"));
+    }
+}
@@ -0,0 +1,78 @@
+// Custom GeoJSON data drawn over the map, such as a delivery zone, kept apart from routes
+use serde::Serialize;
+use std::fmt;
+
+// How an overlay is drawn; serializes to Leaflet path options
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OverlayStyle {
+    pub color: String, // CSS color of lines, outlines and points
+    pub weight: f64, // line width in pixels
+    pub fill_opacity: f64, // 0 (clear) to 1, for polygon and point fills
+}
+
+impl Default for OverlayStyle {
+    fn default() -> Self {
+        Self {
+            color: "#8e44ad".to_string(),
+            weight: 2.0,
+            fill_opacity: 0.2,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OverlayError {
+    InvalidGeoJson(String),
+    NoGeometry, // valid GeoJSON with nothing to draw
+}
+
+impl fmt::Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverlayError::InvalidGeoJson(reason) => write!(f, "Overlay is not valid GeoJSON: {}", reason),
+            OverlayError::NoGeometry => write!(f, "Overlay has no geometry to draw"),
+        }
+    }
+}
+
+impl std::error::Error for OverlayError {}
+
+// Checks that `json` is GeoJSON with something to draw, and returns it re-serialized so
+// it can be embedded in page scripts as a literal
+pub fn validate_overlay(json: &str) -> Result<String, OverlayError> {
+    let geojson: geojson::GeoJson = json.parse().map_err(|e: geojson::Error| OverlayError::InvalidGeoJson(e.to_string()))?;
+    let has_geometry = match &geojson {
+        geojson::GeoJson::FeatureCollection(collection) => collection.features.iter().any(|feature| feature.geometry.is_some()),
+        geojson::GeoJson::Feature(feature) => feature.geometry.is_some(),
+        geojson::GeoJson::Geometry(_) => true,
+    };
+    if !has_geometry {
+        return Err(OverlayError::NoGeometry);
+    }
+    Ok(geojson.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_overlay() {
+        let zone = r#"{"type":"Feature","properties":{"name":"Zone A"},"geometry":{"type":"Polygon","coordinates":[[[-0.13,51.5],[-0.12,51.5],[-0.12,51.51],[-0.13,51.5]]]}}"#;
+        let validated = validate_overlay(zone).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&validated).unwrap();
+        assert_eq!(value["geometry"]["type"], "Polygon");
+        assert_eq!(value["properties"]["name"], "Zone A");
+
+        assert!(matches!(validate_overlay("{\"type\":\"Feature\""), Err(OverlayError::InvalidGeoJson(_))));
+        assert!(matches!(validate_overlay(r#"{"type":"Circle","radius":5}"#), Err(OverlayError::InvalidGeoJson(_))));
+        assert!(matches!(validate_overlay(r#"{"type":"FeatureCollection","features":[]}"#), Err(OverlayError::NoGeometry)));
+    }
+
+    #[test]
+    fn test_overlay_style_uses_leaflet_names() {
+        let style = serde_json::to_value(OverlayStyle::default()).unwrap();
+        assert_eq!(style, serde_json::json!({ "color": "#8e44ad", "weight": 2.0, "fillOpacity": 0.2 }));
+    }
+}
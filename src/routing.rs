@@ -1,17 +1,57 @@
+use futures_util::future::{FutureExt, LocalBoxFuture};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 use crate::geolocation::Location;
+use crate::units;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Waypoint {
     pub lat: f64,
     pub lng: f64,
     pub name: Option<String>,
+    #[serde(default)]
+    pub kind: WaypointKind,
+}
+
+// A stop ends a leg: the route arrives there and the directions say so. A via point only
+// shapes the route, which passes through it without arriving, so the legs either side of it
+// join into one and it adds no arrive step. The first and last waypoints are always stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WaypointKind {
+    #[default]
+    Stop,
+    Via,
+}
+
+impl Waypoint {
+    // Whether the route arrives here; the route's ends count as stops whatever their kind
+    pub fn is_stop(waypoints: &[Waypoint], index: usize) -> bool {
+        index == 0 || index + 1 >= waypoints.len() || waypoints[index].kind == WaypointKind::Stop
+    }
+
+    // Rejects coordinates OSRM cannot route, including NaN and infinities
+    pub fn validate(&self) -> Result<(), String> {
+        if !(-90.0..=90.0).contains(&self.lat) {
+            return Err(format!("latitude {} is outside [-90, 90]", self.lat));
+        }
+        if !(-180.0..=180.0).contains(&self.lng) {
+            return Err(format!("longitude {} is outside [-180, 180]", self.lng));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RouteRequest {
     pub waypoints: Vec<Waypoint>,
-    pub profile: String, // "driving", "walking", "cycling"
+    pub profile: String, // "driving", "walking", "cycling", or TRANSIT_PROFILE
+    pub exclude: Vec<String>, // OSRM road classes to avoid, see CAR_EXCLUDE_CLASSES
+    #[serde(default)]
+    pub depart_at: Option<SystemTime>, // only honored by backends where supports_traffic() is true
 }
 
 impl Default for RouteRequest {
@@ -19,6 +59,8 @@ impl Default for RouteRequest {
         Self {
             waypoints: Vec::new(),
             profile: "driving".to_string(),
+            exclude: Vec::new(),
+            depart_at: None,
         }
     }
 }
@@ -29,6 +71,49 @@ pub struct RouteResponse {
     pub duration: f64, // in seconds  
     pub geometry: String, // encoded polyline or GeoJSON
     pub instructions: Vec<RouteInstruction>,
+    #[serde(default)]
+    pub legs: Vec<LegSummary>, // one per pair of consecutive waypoints
+}
+
+// Distance and time between two consecutive waypoints of a route
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LegSummary {
+    pub distance: f64, // in meters
+    pub duration: f64, // in seconds
+    pub first_instruction: usize, // index of the leg's first step in RouteResponse::instructions
+}
+
+impl RouteResponse {
+    // Decodes the GeoJSON LineString geometry into (lat, lng) pairs
+    pub fn coordinates(&self) -> Vec<(f64, f64)> {
+        match serde_json::from_str::<geojson::Geometry>(&self.geometry) {
+            Ok(geometry) => match geometry.value {
+                geojson::Value::LineString(points) => points
+                    .iter()
+                    .filter(|point| point.len() >= 2)
+                    .map(|point| (point[1], point[0]))
+                    .collect(),
+                _ => Vec::new(),
+            },
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Index of the next maneuver to make from `location`: the nearest maneuver point, or
+    // the one after it once within `reached` meters of it
+    pub fn upcoming_instruction(&self, location: &Location, reached: f64) -> Option<usize> {
+        let (nearest, distance) = self
+            .instructions
+            .iter()
+            .map(|instruction| location.distance_to(&instruction.location))
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+        if distance <= reached {
+            Some((nearest + 1).min(self.instructions.len() - 1))
+        } else {
+            Some(nearest)
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,68 +122,626 @@ pub struct RouteInstruction {
     pub distance: f64,
     pub duration: f64,
     pub location: Location,
+    pub speed_limit: Option<f64>, // posted limit in m/s, if OSRM knows it
+    pub maneuver_icon: String, // turn hint such as "turn-left" or "roundabout", see maneuver_to_icon
+    #[serde(default)]
+    pub mode: Option<String>, // travel mode of the step, e.g. "driving", "walk", "bus", "train"
+    #[serde(default)]
+    pub bearing: Option<f64>, // compass heading in degrees (0 = north) leaving the maneuver
+    // Meters and seconds from the start of the route to this maneuver, across all legs;
+    // at the final step they equal the route totals
+    #[serde(default)]
+    pub cumulative_distance: f64,
+    #[serde(default)]
+    pub cumulative_duration: f64,
 }
 
-pub struct RoutingService {
-    pub osm_api_base: String,
+// Geometry encoding requested from OSRM. Polyline responses are much smaller and
+// are decoded into GeoJSON here, so RouteResponse.geometry is GeoJSON either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GeometryFormat {
+    #[default]
+    GeoJson,
+    Polyline,
 }
 
-impl RoutingService {
+impl GeometryFormat {
+    fn query_value(self) -> &'static str {
+        match self {
+            GeometryFormat::GeoJson => "geojson",
+            GeometryFormat::Polyline => "polyline",
+        }
+    }
+}
+
+// Language used for turn-by-turn instruction text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    Es,
+    De,
+}
+
+impl Language {
+    pub const ALL: &'static [Language] = &[Language::En, Language::Es, Language::De];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::Es => "es",
+            Language::De => "de",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Language> {
+        Language::ALL.iter().copied().find(|language| language.code() == code)
+    }
+
+    // Name of the language in itself, for the settings menu
+    pub fn label(self) -> &'static str {
+        match self {
+            Language::En => "English",
+            Language::Es => "Español",
+            Language::De => "Deutsch",
+        }
+    }
+
+    // Narration language code for Valhalla's directions_options
+    fn valhalla_locale(self) -> &'static str {
+        match self {
+            Language::En => "en-US",
+            Language::Es => "es-ES",
+            Language::De => "de-DE",
+        }
+    }
+
+    fn phrases(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Language::En => EN_PHRASES,
+            Language::Es => ES_PHRASES,
+            Language::De => DE_PHRASES,
+        }
+    }
+
+    // Looks up an instruction phrase, falling back to English and then to the key itself
+    fn phrase(self, key: &str) -> &str {
+        let find = |table: &'static [(&'static str, &'static str)]| {
+            table.iter().find(|(k, _)| *k == key).map(|(_, text)| *text)
+        };
+        find(self.phrases()).or_else(|| find(EN_PHRASES)).unwrap_or(key)
+    }
+
+    // Whether an instruction carried on after the "after" phrase starts lowercase. German
+    // starts a new sentence after the colon, so it keeps the capital.
+    fn lowercase_after(self) -> bool {
+        !matches!(self, Language::De)
+    }
+
+    // Exit number as the language writes it: 2nd, 2ª, 2.
+    fn ordinal(self, n: u8) -> String {
+        match self {
+            Language::En => ordinal(n),
+            Language::Es => format!("{}ª", n),
+            Language::De => format!("{}.", n),
+        }
+    }
+}
+
+// Instruction phrases. Templates fill in {direction}, {street}, {distance}, {exit}, {name},
+// {instruction} and {speed};
+// {street} is either empty or the "on" phrase with a leading space.
+const EN_PHRASES: &[(&str, &str)] = &[
+    ("depart", "Head {direction}{street} for {distance}"),
+    ("turn", "Turn {direction}{street} for {distance}"),
+    ("merge", "Merge {direction}{street} for {distance}"),
+    ("ramp", "Take the ramp {direction}{street} for {distance}"),
+    ("fork", "Keep {direction} at the fork{street} for {distance}"),
+    ("roundabout_exit", "Take the {exit} exit at the roundabout{street} for {distance}"),
+    ("roundabout", "Enter the roundabout for {distance}"),
+    ("roundabout_street", "Enter the roundabout and take{street} for {distance}"),
+    ("arrive", "Arrive at your destination"),
+    ("continue", "Continue{street} for {distance}"),
+    ("on", "on {name}"),
+    ("left", "left"),
+    ("right", "right"),
+    ("slight left", "slight left"),
+    ("slight right", "slight right"),
+    ("sharp left", "sharp left"),
+    ("sharp right", "sharp right"),
+    ("straight", "straight"),
+    ("uturn", "uturn"),
+    ("north", "north"),
+    ("northeast", "northeast"),
+    ("east", "east"),
+    ("southeast", "southeast"),
+    ("south", "south"),
+    ("southwest", "southwest"),
+    ("west", "west"),
+    ("northwest", "northwest"),
+    ("transit_walk", "Walk {distance} to {name}"),
+    ("transit_ride", "Take {mode}{line} to {name}"),
+    ("transit_bus", "the bus"),
+    ("transit_train", "the train"),
+    ("transit_ferry", "the ferry"),
+    ("transit_line", "the line"),
+    ("after", "After {distance}, {instruction}"),
+    ("speed_limit", "limit {speed}"),
+];
+
+const ES_PHRASES: &[(&str, &str)] = &[
+    ("depart", "Dirígete {direction}{street} durante {distance}"),
+    ("turn", "Gira {direction}{street} durante {distance}"),
+    ("merge", "Incorpórate {direction}{street} durante {distance}"),
+    ("ramp", "Toma la rampa {direction}{street} durante {distance}"),
+    ("fork", "Mantente {direction} en la bifurcación{street} durante {distance}"),
+    ("roundabout_exit", "Toma la {exit} salida en la rotonda{street} durante {distance}"),
+    ("roundabout", "Entra en la rotonda durante {distance}"),
+    ("roundabout_street", "Entra en la rotonda y continúa{street} durante {distance}"),
+    ("arrive", "Has llegado a tu destino"),
+    ("continue", "Continúa{street} durante {distance}"),
+    ("on", "por {name}"),
+    ("left", "a la izquierda"),
+    ("right", "a la derecha"),
+    ("slight left", "ligeramente a la izquierda"),
+    ("slight right", "ligeramente a la derecha"),
+    ("sharp left", "bruscamente a la izquierda"),
+    ("sharp right", "bruscamente a la derecha"),
+    ("straight", "recto"),
+    ("uturn", "en U"),
+    ("north", "al norte"),
+    ("northeast", "al noreste"),
+    ("east", "al este"),
+    ("southeast", "al sureste"),
+    ("south", "al sur"),
+    ("southwest", "al suroeste"),
+    ("west", "al oeste"),
+    ("northwest", "al noroeste"),
+    ("transit_walk", "Camina {distance} hasta {name}"),
+    ("transit_ride", "Toma {mode}{line} hasta {name}"),
+    ("transit_bus", "el autobús"),
+    ("transit_train", "el tren"),
+    ("transit_ferry", "el ferri"),
+    ("transit_line", "la línea"),
+    ("after", "Tras {distance}, {instruction}"),
+    ("speed_limit", "límite {speed}"),
+];
+
+const DE_PHRASES: &[(&str, &str)] = &[
+    ("depart", "Fahre {direction}{street} für {distance}"),
+    ("turn", "Biege {direction} ab{street} für {distance}"),
+    ("merge", "Fädle dich {direction} ein{street} für {distance}"),
+    ("ramp", "Nimm die Auffahrt {direction}{street} für {distance}"),
+    ("fork", "Halte dich an der Gabelung {direction}{street} für {distance}"),
+    ("roundabout_exit", "Nimm im Kreisverkehr die {exit} Ausfahrt{street} für {distance}"),
+    ("roundabout", "Fahre in den Kreisverkehr für {distance}"),
+    ("roundabout_street", "Fahre in den Kreisverkehr und weiter{street} für {distance}"),
+    ("arrive", "Du hast dein Ziel erreicht"),
+    ("continue", "Fahre weiter{street} für {distance}"),
+    ("on", "auf {name}"),
+    ("left", "links"),
+    ("right", "rechts"),
+    ("slight left", "leicht links"),
+    ("slight right", "leicht rechts"),
+    ("sharp left", "scharf links"),
+    ("sharp right", "scharf rechts"),
+    ("straight", "geradeaus"),
+    ("uturn", "zum Wenden"),
+    ("north", "nach Norden"),
+    ("northeast", "nach Nordosten"),
+    ("east", "nach Osten"),
+    ("southeast", "nach Südosten"),
+    ("south", "nach Süden"),
+    ("southwest", "nach Südwesten"),
+    ("west", "nach Westen"),
+    ("northwest", "nach Nordwesten"),
+    ("transit_walk", "Gehe {distance} bis {name}"),
+    ("transit_ride", "Nimm {mode}{line} bis {name}"),
+    ("transit_bus", "den Bus"),
+    ("transit_train", "den Zug"),
+    ("transit_ferry", "die Fähre"),
+    ("transit_line", "die Linie"),
+    ("after", "Nach {distance}: {instruction}"),
+    ("speed_limit", "Tempolimit {speed}"),
+];
+
+// Routing profiles understood by the OSRM `/route` service
+pub const SUPPORTED_PROFILES: &[&str] = &["driving", "walking", "cycling"];
+
+// Public transport profile, routed by RoutingService's transit backend when one is configured
+pub const TRANSIT_PROFILE: &str = "transit";
+
+// Classes the stock OSRM car profile can exclude. The stock foot and bicycle
+// profiles define none, so any exclude is rejected for walking and cycling.
+pub const CAR_EXCLUDE_CLASSES: &[&str] = &["motorway", "toll", "ferry"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeocodeResult {
+    pub location: Location,
+    pub display_name: String,
+    pub kind: Option<String>, // Nominatim place type, e.g. "city", "house"
+    #[serde(default)]
+    pub class: Option<String>, // Nominatim category of the type, e.g. "place", "highway"
+    #[serde(default)]
+    pub importance: f64, // Nominatim's relevance estimate from 0 to 1; 0 when not given
+}
+
+// Public endpoints used unless the caller supplies its own servers
+pub const DEFAULT_OSRM_BASE: &str = "https://router.project-osrm.org";
+pub const DEFAULT_GEOCODER_BASE: &str = "https://nominatim.openstreetmap.org";
+pub const DEFAULT_ELEVATION_BASE: &str = "https://api.open-elevation.com";
+pub const DEFAULT_OVERPASS_BASE: &str = "https://overpass-api.de/api";
+
+// Nominatim's usage policy asks for a User-Agent naming the application; deployments
+// should add a contact, e.g. "map-rs/0.1.0 (you@example.org)", with with_user_agent
+pub const DEFAULT_USER_AGENT: &str = concat!("map-rs/", env!("CARGO_PKG_VERSION"));
+
+// Points sampled along a route for its elevation profile, and how many go in one request
+const ELEVATION_SAMPLES: usize = 100;
+const ELEVATION_BATCH_SIZE: usize = 100;
+
+// Seconds the Overpass server may spend on a nearest_poi query before giving up
+const OVERPASS_QUERY_TIMEOUT_S: u32 = 25;
+
+// Height changes smaller than this are treated as GPS or DEM noise by elevation_stats
+pub const DEFAULT_ELEVATION_NOISE_M: f64 = 1.0;
+
+// Base delay for exponential backoff between retried requests
+const RETRY_BASE_DELAY_MS: u64 = 500;
+
+// Longest Retry-After worth waiting for; servers asking for more fail the request instead
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+// Connect and total time allowed for each HTTP request, so a hung server can't stall the app
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Consecutive waypoints closer than this (in meters) are treated as the same stop
+pub const DEFAULT_DUPLICATE_THRESHOLD_M: f64 = 10.0;
+
+#[derive(Debug)]
+pub enum RoutingError {
+    Network(String), // connection failures and other transport errors
+    Timeout, // the server did not answer within the request timeout
+    Http(reqwest::StatusCode),
+    NoRoute,
+    NoSegment, // a waypoint is too far from any road the profile can use
+    Decode(String),
+    TooFewWaypoints,
+    InvalidWaypoint(usize, String), // index of the offending waypoint and why
+    InvalidRequest(String), // rejected before or by the server, e.g. an unknown profile
+    DuplicateWaypoint(usize, f64), // index of the later of two nearly identical stops, and their distance in meters
+}
+
+impl RoutingError {
+    // Whether trying the same request again later may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RoutingError::Network(_) | RoutingError::Timeout => true,
+            RoutingError::Http(status) => status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+            _ => false,
+        }
+    }
+
+    // Whether the server did not answer within the request timeout
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, RoutingError::Timeout)
+    }
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoutingError::Network(reason) => write!(f, "Network error: {}", reason),
+            RoutingError::Timeout => write!(f, "Request timed out"),
+            RoutingError::Http(status) => write!(f, "Server returned {}", status),
+            RoutingError::NoRoute => write!(f, "No route found"),
+            RoutingError::NoSegment => write!(f, "No road near one of your points"),
+            RoutingError::Decode(reason) => write!(f, "Could not read server response: {}", reason),
+            RoutingError::TooFewWaypoints => write!(f, "At least 2 waypoints are required"),
+            RoutingError::InvalidWaypoint(index, reason) => write!(f, "Waypoint {} is invalid: {}", index + 1, reason),
+            RoutingError::InvalidRequest(reason) => write!(f, "{}", reason),
+            RoutingError::DuplicateWaypoint(index, distance) => write!(
+                f,
+                "Waypoints {} and {} are only {:.0} m apart; remove one of them",
+                index,
+                index + 1,
+                distance
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+impl From<reqwest::Error> for RoutingError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_decode() {
+            RoutingError::Decode(error.to_string())
+        } else if error.is_timeout() {
+            RoutingError::Timeout
+        } else if let Some(status) = error.status() {
+            RoutingError::Http(status)
+        } else {
+            RoutingError::Network(error.to_string())
+        }
+    }
+}
+
+impl From<serde_json::Error> for RoutingError {
+    fn from(error: serde_json::Error) -> Self {
+        RoutingError::Decode(error.to_string())
+    }
+}
+
+// Nominatim asks clients to cache results; repeated searches are served from memory
+pub const DEFAULT_GEOCODE_CACHE_CAPACITY: usize = 128;
+pub const DEFAULT_GEOCODE_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Nominatim's usage policy allows at most one request per second
+pub const DEFAULT_GEOCODE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Token bucket holding a single token: each caller reserves the next free slot and
+// waits for it, so requests are spaced at least `interval` apart instead of failing
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_slot: Mutex::new(None),
+        }
+    }
+
+    async fn acquire(&self) {
+        let wait = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_slot.map_or(now, |slot| slot.max(now));
+            *next_slot = Some(slot + self.interval);
+            slot - now
+        };
+
+        if !wait.is_zero() {
+            eprintln!("⏳ Waiting {:?} before the next geocoder request", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// Status and body text of a server response
+pub struct HttpResponse {
+    pub status: reqwest::StatusCode,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, RoutingError> {
+        Ok(serde_json::from_str(&self.body)?)
+    }
+}
+
+// Performs the HTTP requests of RoutingService, retrying transient failures up to
+// `max_retries` times. Abstracted so response parsing can be tested with canned JSON.
+pub trait HttpClient: Send + Sync {
+    // Headers are (name, value) pairs added to this request only
+    fn get_with_headers<'a>(&'a self, url: &'a str, headers: &'a [(&'a str, &'a str)], max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>>;
+    fn post_json<'a>(&'a self, url: &'a str, body: &'a serde_json::Value, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>>;
+
+    fn get<'a>(&'a self, url: &'a str, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+        self.get_with_headers(url, &[], max_retries)
+    }
+}
+
+// Sends requests with reqwest, identifying as DEFAULT_USER_AGENT unless a request says otherwise
+pub struct ReqwestClient {
+    client: reqwest::Client,
+}
+
+impl ReqwestClient {
     pub fn new() -> Self {
+        Self::with_timeout(DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    // `timeout` limits both connecting and the whole request, including reading the body
+    pub fn with_timeout(timeout: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent(DEFAULT_USER_AGENT)
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .build()
+            .unwrap_or_default();
+        Self { client }
+    }
+
+    async fn send(request: reqwest::RequestBuilder, max_retries: u32) -> Result<HttpResponse, RoutingError> {
+        let response = send_with_retry(request, max_retries).await?;
+        let status = response.status();
+        let body = response.text().await?;
+        Ok(HttpResponse { status, body })
+    }
+}
+
+impl Default for ReqwestClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpClient for ReqwestClient {
+    fn get_with_headers<'a>(&'a self, url: &'a str, headers: &'a [(&'a str, &'a str)], max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+        let request = headers.iter().fold(self.client.get(url), |request, (name, value)| request.header(*name, *value));
+        Self::send(request, max_retries).boxed_local()
+    }
+
+    fn post_json<'a>(&'a self, url: &'a str, body: &'a serde_json::Value, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+        Self::send(self.client.post(url).json(body), max_retries).boxed_local()
+    }
+}
+
+// Least-recently-used cache of geocoding results with a per-entry time to live
+struct GeocodeCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: VecDeque<(String, Instant, Vec<GeocodeResult>)>, // most recently used last
+}
+
+impl GeocodeCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
         Self {
-            osm_api_base: "https://router.project-osrm.org".to_string(),
+            capacity,
+            ttl,
+            entries: VecDeque::new(),
         }
     }
 
-    pub async fn calculate_route(&self, waypoints: &[Waypoint], use_miles: bool) -> Result<RouteResponse, Box<dyn std::error::Error>> {
-        if waypoints.len() < 2 {
-            return Err("At least 2 waypoints are required".into());
+    fn get(&mut self, key: &str) -> Option<Vec<GeocodeResult>> {
+        let index = self.entries.iter().position(|(k, _, _)| k == key)?;
+        let entry = self.entries.remove(index)?;
+        if entry.1.elapsed() >= self.ttl {
+            return None;
         }
 
-        // Build coordinates string for OSRM API
-        let coordinates: Vec<String> = waypoints
-            .iter()
-            .map(|wp| format!("{},{}", wp.lng, wp.lat))
-            .collect();
-        
-        let coordinates_str = coordinates.join(";");
-        
-        // Use OSRM API for routing with enhanced parameters for better instructions
-        let url = format!(
-            "{}/route/v1/driving/{}?overview=full&geometries=geojson&steps=true&annotations=true&continue_straight=true",
-            self.osm_api_base, coordinates_str
-        );
+        let results = entry.2.clone();
+        self.entries.push_back(entry);
+        Some(results)
+    }
 
-        let client = reqwest::Client::new();
-        let response = client.get(&url).send().await?;
-        
-        if !response.status().is_success() {
-            return Err(format!("Routing API error: {}", response.status()).into());
+    fn insert(&mut self, key: String, results: Vec<GeocodeResult>) {
+        if self.capacity == 0 {
+            return;
         }
 
-        let osrm_response: OSRMResponse = response.json().await?;
-        
-        if osrm_response.routes.is_empty() {
-            return Err("No route found".into());
+        self.entries.retain(|(k, _, _)| *k != key);
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, Instant::now(), results));
+    }
+}
+
+// Where a stop clicked on the route line belongs in the waypoint order: right after the last
+// waypoint the route passes before reaching the click. Positions along the route are taken at
+// the nearest geometry vertex, searching onward from the previous stop so a route that doubles
+// back keeps its order. Without geometry the stop goes on the leg it lengthens least.
+pub fn insertion_index(route: &[(f64, f64)], waypoints: &[Waypoint], lat: f64, lng: f64) -> usize {
+    if waypoints.len() < 2 {
+        return waypoints.len();
+    }
+    let point = Location::new(lat, lng);
+    let location = |wp: &Waypoint| Location::new(wp.lat, wp.lng);
+    
+    if route.is_empty() {
+        let detour = |from: Location, to: Location| from.distance_to(&point) + point.distance_to(&to) - from.distance_to(&to);
+        return waypoints
+            .windows(2)
+            .map(|pair| detour(location(&pair[0]), location(&pair[1])))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(1, |(i, _)| i + 1);
+    }
+    
+    let nearest_vertex = |from: usize, target: &Location| {
+        route
+            .iter()
+            .enumerate()
+            .skip(from)
+            .min_by(|(_, a), (_, b)| {
+                let distance = |&&(lat, lng): &&(f64, f64)| Location::new(lat, lng).distance_to(target);
+                distance(a).total_cmp(&distance(b))
+            })
+            .map_or(from, |(i, _)| i)
+    };
+    
+    let click = nearest_vertex(0, &point);
+    let mut position = 0;
+    let mut index = 1;
+    for (i, waypoint) in waypoints.iter().enumerate().take(waypoints.len() - 1).skip(1) {
+        position = nearest_vertex(position, &location(waypoint));
+        if position > click {
+            break;
         }
+        index = i + 1;
+    }
+    index
+}
 
-        let route = &osrm_response.routes[0];
-        let instructions = self.parse_instructions(&route.legs, use_miles);
+// Finds the first stop within `threshold_m` meters of the one before it, returning its index
+// and the distance between the two
+pub fn find_duplicate_waypoint(waypoints: &[Waypoint], threshold_m: f64) -> Option<(usize, f64)> {
+    waypoints.windows(2).enumerate().find_map(|(i, pair)| {
+        let distance = Location::new(pair[0].lat, pair[0].lng).distance_to(&Location::new(pair[1].lat, pair[1].lng));
+        (distance < threshold_m).then_some((i + 1, distance))
+    })
+}
 
-        Ok(RouteResponse {
-            distance: route.distance,
-            duration: route.duration,
-            geometry: serde_json::to_string(&route.geometry)?,
-            instructions,
+// Checks that each code is two ASCII letters and lowercases it, as Nominatim expects
+pub fn normalize_country_codes(codes: &[&str]) -> Result<Vec<String>, RoutingError> {
+    codes
+        .iter()
+        .map(|code| {
+            let code = code.trim();
+            if code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+                Ok(code.to_ascii_lowercase())
+            } else {
+                Err(RoutingError::InvalidRequest(format!(
+                    "'{}' is not a two-letter country code",
+                    code
+                )))
+            }
         })
+        .collect()
+}
+
+// Case and whitespace differences should not cause a cache miss
+fn normalize_query(query: &str) -> String {
+    query.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+// Computes routes for a request that RoutingService has already validated. Returns the
+// primary route followed by any alternatives the server offers.
+pub trait RoutingBackend: Send + Sync {
+    #[allow(clippy::too_many_arguments)]
+    fn route<'a>(&'a self, request: &'a RouteRequest, geometry_format: GeometryFormat, use_miles: bool, language: Language, raw_instructions: bool, http: &'a dyn HttpClient, max_retries: u32) -> LocalBoxFuture<'a, Result<Vec<RouteResponse>, RoutingError>>;
+
+    // Whether the server uses live or historic traffic, so a departure time changes the route
+    fn supports_traffic(&self) -> bool {
+        false
+    }
+}
+
+// Routes with an OSRM server's /route service. Instruction text is built here from the
+// maneuver types, so it can be localized.
+pub struct OsrmBackend {
+    base_url: String,
+}
+
+impl OsrmBackend {
+    pub fn new(base_url: String) -> Self {
+        Self { base_url: base_url.trim_end_matches('/').to_string() }
     }
 
-    fn parse_instructions(&self, legs: &[OSRMLeg], use_miles: bool) -> Vec<RouteInstruction> {
+    // With `raw_instructions`, OSRM's own maneuver.instruction text is used where the server sent one
+    fn parse_instructions(&self, legs: &[OSRMLeg], use_miles: bool, language: Language, raw_instructions: bool) -> Vec<RouteInstruction> {
         let mut instructions = Vec::new();
+        // Running totals carry on from one leg into the next
+        let (mut cumulative_distance, mut cumulative_duration) = (0.0, 0.0);
         
         for leg in legs {
-            for step in &leg.steps {
-                let instruction_text = self.generate_instruction_text(step, use_miles);
+            let speed_limits = leg.step_speed_limits();
+            
+            for (step, speed_limit) in leg.steps.iter().zip(speed_limits) {
+                let instruction_text = match &step.maneuver.instruction {
+                    Some(raw) if raw_instructions && !raw.trim().is_empty() => raw.clone(),
+                    _ => self.generate_instruction_text(step, use_miles, language),
+                };
                 
                 instructions.push(RouteInstruction {
                     text: instruction_text,
@@ -108,121 +751,124 @@ impl RoutingService {
                         step.maneuver.location[1],
                         step.maneuver.location[0],
                     ),
+                    speed_limit,
+                    maneuver_icon: maneuver_to_icon(
+                        step.maneuver.maneuver_type.as_deref().unwrap_or("continue"),
+                        step.maneuver.modifier.as_deref(),
+                    ).to_string(),
+                    mode: step.mode.clone(),
+                    // OSRM reports 0 after arriving, which is no direction at all
+                    bearing: step.maneuver.bearing_after.filter(|_| step.maneuver.maneuver_type.as_deref() != Some("arrive")),
+                    cumulative_distance,
+                    cumulative_duration,
                 });
+                cumulative_distance += step.distance;
+                cumulative_duration += step.duration;
             }
         }
         
         instructions
     }
     
-    fn generate_instruction_text(&self, step: &OSRMStep, use_miles: bool) -> String {
+    fn route_url(&self, request: &RouteRequest, geometry_format: GeometryFormat) -> String {
+        let waypoints = &request.waypoints;
+
+        // Build coordinates string for OSRM API
+        let coordinates: Vec<String> = waypoints
+            .iter()
+            .map(|wp| format!("{},{}", wp.lng, wp.lat))
+            .collect();
+        
+        let coordinates_str = coordinates.join(";");
+        
+        // Use OSRM API for routing with enhanced parameters for better instructions
+        let mut url = format!(
+            "{}/route/v1/{}/{}?overview=full&geometries={}&steps=true&annotations=true&continue_straight=true&alternatives=true",
+            self.base_url, request.profile, coordinates_str, geometry_format.query_value()
+        );
+        if !request.exclude.is_empty() {
+            url.push_str(&format!("&exclude={}", request.exclude.join(",")));
+        }
+        
+        // Only the coordinates listed in `waypoints` split the route into legs; the rest are
+        // passed through. `approaches` is left at OSRM's default, so any point may be reached
+        // from either side of the road rather than forcing a U-turn to the near curb.
+        if waypoints.iter().any(|wp| wp.kind == WaypointKind::Via) {
+            let stops: Vec<String> = (0..waypoints.len())
+                .filter(|&i| Waypoint::is_stop(waypoints, i))
+                .map(|i| i.to_string())
+                .collect();
+            url.push_str(&format!("&waypoints={}", stops.join(";")));
+        }
+        url
+    }
+    
+    // Per-leg totals, pointing at where each leg's steps start in parse_instructions' output
+    fn parse_legs(&self, legs: &[OSRMLeg]) -> Vec<LegSummary> {
+        let mut first_instruction = 0;
+        legs.iter()
+            .map(|leg| {
+                let summary = LegSummary { distance: leg.distance, duration: leg.duration, first_instruction };
+                first_instruction += leg.steps.len();
+                summary
+            })
+            .collect()
+    }
+    
+    fn generate_instruction_text(&self, step: &OSRMStep, use_miles: bool, language: Language) -> String {
         let maneuver_type = step.maneuver.maneuver_type.as_deref().unwrap_or("continue");
         let modifier = step.maneuver.modifier.as_deref();
         let road_name = step.name.as_deref().unwrap_or("");
         let road_ref = step.ref_.as_deref();
         
         // Format distance in a more readable way
-        let distance_text = self.format_distance(step.distance, use_miles);
+        let distance_text = format_distance(step.distance, use_miles);
         
         // Build the street name part
-        let street_info = if !road_name.is_empty() {
-            if let Some(ref_) = road_ref {
-                format!("on {} ({})", road_name, ref_)
-            } else {
-                format!("on {}", road_name)
-            }
-        } else if let Some(ref_) = road_ref {
-            format!("on {}", ref_)
-        } else {
+        let street_name = match (road_name.is_empty(), road_ref) {
+            (false, Some(ref_)) => format!("{} ({})", road_name, ref_),
+            (false, None) => road_name.to_string(),
+            (true, Some(ref_)) => ref_.to_string(),
+            (true, None) => String::new(),
+        };
+        let street_info = if street_name.is_empty() {
             String::new()
+        } else {
+            format!(" {}", language.phrase("on").replace("{name}", &street_name))
         };
         
-        // Generate instruction based on maneuver type
-        match maneuver_type {
-            "depart" => {
-                if street_info.is_empty() {
-                    format!("Head {} for {}", 
-                           self.bearing_to_direction(step.maneuver.bearing_after), 
-                           distance_text)
-                } else {
-                    format!("Head {} {} for {}", 
-                           self.bearing_to_direction(step.maneuver.bearing_after), 
-                           street_info, 
-                           distance_text)
-                }
-            }
-            "turn" => {
-                let direction = modifier.unwrap_or("").replace("sharp ", "sharp ");
-                if street_info.is_empty() {
-                    format!("Turn {} for {}", direction, distance_text)
-                } else {
-                    format!("Turn {} {} for {}", direction, street_info, distance_text)
-                }
-            }
-            "merge" => {
-                let direction = modifier.unwrap_or("").replace("slight ", "");
-                if street_info.is_empty() {
-                    format!("Merge {} for {}", direction, distance_text)
-                } else {
-                    format!("Merge {} {} for {}", direction, street_info, distance_text)
-                }
-            }
-            "ramp" => {
-                let direction = modifier.unwrap_or("").replace("slight ", "");
-                if street_info.is_empty() {
-                    format!("Take the ramp {} for {}", direction, distance_text)
-                } else {
-                    format!("Take the ramp {} {} for {}", direction, street_info, distance_text)
-                }
-            }
-            "fork" => {
-                let direction = modifier.unwrap_or("left");
-                if street_info.is_empty() {
-                    format!("Keep {} at the fork for {}", direction, distance_text)
-                } else {
-                    format!("Keep {} at the fork {} for {}", direction, street_info, distance_text)
-                }
-            }
-            "roundabout" => {
-                if street_info.is_empty() {
-                    format!("Enter the roundabout for {}", distance_text)
-                } else {
-                    format!("Enter the roundabout and take {} for {}", street_info, distance_text)
-                }
-            }
-            "arrive" => {
-                "Arrive at your destination".to_string()
-            }
-            _ => {
-                // Default case for "continue" and other types
-                if street_info.is_empty() {
-                    format!("Continue for {}", distance_text)
-                } else {
-                    format!("Continue {} for {}", street_info, distance_text)
-                }
-            }
-        }
-    }
-    
-    fn format_distance(&self, meters: f64, use_miles: bool) -> String {
-        if use_miles {
-            let miles = meters * 0.000621371; // Convert meters to miles
-            format!("{:.1} mi", miles)
-        } else {
-            if meters >= 1000.0 {
-                format!("{:.1} km", meters / 1000.0)
-            } else {
-                format!("{:.0} m", meters)
-            }
-        }
+        // Pick the template and direction word for the maneuver type
+        let (key, direction) = match maneuver_type {
+            "depart" => ("depart", self.bearing_to_direction(step.maneuver.bearing_after)),
+            "turn" | "end of road" => ("turn", modifier.unwrap_or("")),
+            "merge" => ("merge", modifier.unwrap_or("").trim_start_matches("slight ")),
+            "on ramp" | "off ramp" | "ramp" => ("ramp", modifier.unwrap_or("").trim_start_matches("slight ")),
+            "fork" => ("fork", modifier.unwrap_or("left")),
+            "roundabout" | "rotary" => match step.maneuver.exit {
+                Some(_) => ("roundabout_exit", ""),
+                None if street_info.is_empty() => ("roundabout", ""),
+                None => ("roundabout_street", ""),
+            },
+            "arrive" => ("arrive", ""),
+            // Default case for "continue" and other types
+            _ => ("continue", ""),
+        };
+        
+        let exit = step.maneuver.exit.map(|n| language.ordinal(n)).unwrap_or_default();
+        language
+            .phrase(key)
+            .replace("{direction}", language.phrase(direction))
+            .replace("{street}", &street_info)
+            .replace("{distance}", &distance_text)
+            .replace("{exit}", &exit)
     }
     
-    fn bearing_to_direction(&self, bearing: Option<f64>) -> String {
+    fn bearing_to_direction(&self, bearing: Option<f64>) -> &'static str {
         match bearing {
             Some(b) => {
                 let normalized = ((b % 360.0) + 360.0) % 360.0;
                 match normalized {
-                    b if b < 22.5 || b >= 337.5 => "north",
+                    b if !(22.5..337.5).contains(&b) => "north",
                     b if b < 67.5 => "northeast", 
                     b if b < 112.5 => "east",
                     b if b < 157.5 => "southeast",
@@ -233,80 +879,1606 @@ impl RoutingService {
                 }
             }
             None => "straight",
-        }.to_string()
+        }
     }
+}
 
-    pub async fn geocode(&self, query: &str) -> Result<Vec<Location>, Box<dyn std::error::Error>> {
-        let encoded_query = urlencoding::encode(query);
-        let url = format!(
-            "https://nominatim.openstreetmap.org/search?format=json&q={}",
-            encoded_query
-        );
+impl RoutingBackend for OsrmBackend {
+    fn route<'a>(&'a self, request: &'a RouteRequest, geometry_format: GeometryFormat, use_miles: bool, language: Language, raw_instructions: bool, http: &'a dyn HttpClient, max_retries: u32) -> LocalBoxFuture<'a, Result<Vec<RouteResponse>, RoutingError>> {
+        async move {
+            let profile = request.profile.as_str();
+            let url = self.route_url(request, geometry_format);
 
-        let client = reqwest::Client::new();
-        let response = client
-            .get(&url)
-            .header("User-Agent", "OSM-Map-App/1.0")
-            .send()
-            .await?;
+            let response = http.get(&url, max_retries).await?;
+        
+            if !response.is_success() {
+                let status = response.status;
+                let error = response.json::<OSRMResponse>().ok();
+            
+                if let Some(OSRMResponse { code: Some(code), message: Some(message), .. }) = &error {
+                    eprintln!("❌ OSRM error {}: {}", code, message);
+                }
+            
+                if let Some(specific) = error.as_ref().and_then(OSRMResponse::code_error) {
+                    return Err(specific);
+                }
+                match error.and_then(|e| e.code).as_deref() {
+                    // OSRM answers InvalidValue when the profile cannot exclude a requested class
+                    Some("InvalidValue") if !request.exclude.is_empty() => {
+                        return Err(RoutingError::InvalidRequest(format!(
+                            "The routing server cannot avoid {} when {} (the standard driving profile supports: {})",
+                            request.exclude.join(", "),
+                            profile,
+                            CAR_EXCLUDE_CLASSES.join(", ")
+                        )));
+                    }
+                    _ => return Err(RoutingError::Http(status)),
+                }
+            }
 
-        let results: Vec<NominatimResult> = response.json().await?;
+            let osrm_response: OSRMResponse = response.json()?;
         
-        Ok(results
-            .into_iter()
-            .map(|result| Location::new(result.lat.parse().unwrap(), result.lon.parse().unwrap()))
-            .collect())
-    }
-}
+            if let Some(specific) = osrm_response.code_error() {
+                return Err(specific);
+            }
+            if osrm_response.routes.is_empty() {
+                return Err(RoutingError::NoRoute);
+            }
 
-impl Default for RoutingService {
-    fn default() -> Self {
-        Self::new()
+            osrm_response
+                .routes
+                .iter()
+                .map(|route| {
+                    Ok(RouteResponse {
+                        distance: route.distance,
+                        duration: route.duration,
+                        geometry: serde_json::to_string(&route.geometry.to_geojson())?,
+                        instructions: self.parse_instructions(&route.legs, use_miles, language, raw_instructions),
+                        legs: self.parse_legs(&route.legs),
+                    })
+                })
+                .collect()
+        }
+        .boxed_local()
     }
 }
 
-// OSRM API response structures
-#[derive(Debug, Deserialize)]
-struct OSRMResponse {
-    routes: Vec<OSRMRoute>,
+// Routes with a Valhalla server's /route JSON API. Valhalla writes its own instruction
+// text in the requested language, and can take traffic into account for a departure time.
+pub struct ValhallaBackend {
+    base_url: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct OSRMRoute {
-    distance: f64,
-    duration: f64,
-    geometry: geojson::Geometry,
-    legs: Vec<OSRMLeg>,
+impl ValhallaBackend {
+    pub fn new(base_url: String) -> Self {
+        Self { base_url: base_url.trim_end_matches('/').to_string() }
+    }
+
+    fn request_body(request: &RouteRequest, language: Language) -> serde_json::Value {
+        let costing = match request.profile.as_str() {
+            "walking" => "pedestrian",
+            "cycling" => "bicycle",
+            _ => "auto",
+        };
+        let locations: Vec<serde_json::Value> = request
+            .waypoints
+            .iter()
+            .enumerate()
+            .map(|(i, wp)| {
+                // "through" locations shape the route without a leg break or a U-turn there
+                let location_type = if Waypoint::is_stop(&request.waypoints, i) { "break" } else { "through" };
+                serde_json::json!({ "lat": wp.lat, "lon": wp.lng, "type": location_type })
+            })
+            .collect();
+
+        // OSRM road classes map onto Valhalla's preference factors, 0 meaning avoid
+        let mut options = serde_json::Map::new();
+        for class in &request.exclude {
+            let key = match class.as_str() {
+                "motorway" => "use_highways",
+                "toll" => "use_tolls",
+                "ferry" => "use_ferry",
+                _ => continue,
+            };
+            options.insert(key.to_string(), serde_json::json!(0.0));
+        }
+        let mut costing_options = serde_json::Map::new();
+        costing_options.insert(costing.to_string(), serde_json::Value::Object(options));
+
+        let mut body = serde_json::json!({
+            "locations": locations,
+            "costing": costing,
+            "costing_options": costing_options,
+            "directions_options": { "units": "kilometers", "language": language.valhalla_locale() },
+            "alternates": 2,
+        });
+        if let Some(depart_at) = request.depart_at {
+            body["date_time"] = serde_json::json!({ "type": 1, "value": utc_date_time(depart_at) });
+        }
+        body
+    }
+}
+
+impl RoutingBackend for ValhallaBackend {
+    fn route<'a>(&'a self, request: &'a RouteRequest, _geometry_format: GeometryFormat, _use_miles: bool, language: Language, _raw_instructions: bool, http: &'a dyn HttpClient, max_retries: u32) -> LocalBoxFuture<'a, Result<Vec<RouteResponse>, RoutingError>> {
+        async move {
+            let url = format!("{}/route", self.base_url);
+            let body = Self::request_body(request, language);
+            let response = http.post_json(&url, &body, max_retries).await?;
+
+            if !response.is_success() {
+                let status = response.status;
+                let error = response.json::<ValhallaError>().ok();
+                if let Some(ValhallaError { error_code, error: Some(message) }) = &error {
+                    eprintln!("❌ Valhalla error {}: {}", error_code, message);
+                }
+                // 442 is "No path could be found for input"
+                return match error.map(|e| e.error_code) {
+                    Some(442) => Err(RoutingError::NoRoute),
+                    _ => Err(RoutingError::Http(status)),
+                };
+            }
+
+            let valhalla_response: ValhallaResponse = response.json()?;
+            std::iter::once(valhalla_response.trip)
+                .chain(valhalla_response.alternates.into_iter().map(|alternate| alternate.trip))
+                .map(|trip| trip.to_route())
+                .collect()
+        }
+        .boxed_local()
+    }
+
+    fn supports_traffic(&self) -> bool {
+        true
+    }
+}
+
+// Routes on public transport with an OpenTripPlanner server's /plan API. Each itinerary
+// leg (a walk or a ride) becomes one instruction, annotated with its mode.
+pub struct OtpBackend {
+    base_url: String,
+}
+
+impl OtpBackend {
+    pub fn new(base_url: String) -> Self {
+        Self { base_url: base_url.trim_end_matches('/').to_string() }
+    }
+
+    fn plan_url(&self, request: &RouteRequest) -> String {
+        let (from, to) = (&request.waypoints[0], &request.waypoints[request.waypoints.len() - 1]);
+        let mut url = format!(
+            "{}/otp/routers/default/plan?fromPlace={},{}&toPlace={},{}&mode=TRANSIT,WALK",
+            self.base_url, from.lat, from.lng, to.lat, to.lng
+        );
+        if let Some(depart_at) = request.depart_at {
+            let date_time = utc_date_time(depart_at);
+            if let Some((date, time)) = date_time.split_once('T') {
+                url.push_str(&format!("&date={}&time={}", date, time));
+            }
+        }
+        url
+    }
+}
+
+impl RoutingBackend for OtpBackend {
+    fn route<'a>(&'a self, request: &'a RouteRequest, _geometry_format: GeometryFormat, use_miles: bool, language: Language, _raw_instructions: bool, http: &'a dyn HttpClient, max_retries: u32) -> LocalBoxFuture<'a, Result<Vec<RouteResponse>, RoutingError>> {
+        async move {
+            if request.waypoints.len() > 2 {
+                return Err(RoutingError::InvalidRequest("Transit routes can only have a start and a destination".to_string()));
+            }
+
+            let response = http.get(&self.plan_url(request), max_retries).await?;
+            if !response.is_success() {
+                return Err(RoutingError::Http(response.status));
+            }
+
+            let otp_response: OtpResponse = response.json()?;
+            if let Some(error) = &otp_response.error {
+                eprintln!("❌ OpenTripPlanner error {}: {}", error.id, error.msg);
+                return Err(RoutingError::NoRoute);
+            }
+            let itineraries = otp_response.plan.map(|plan| plan.itineraries).unwrap_or_default();
+            if itineraries.is_empty() {
+                return Err(RoutingError::NoRoute);
+            }
+            itineraries.iter().map(|itinerary| itinerary.to_route(use_miles, language)).collect()
+        }
+        .boxed_local()
+    }
+
+    // Timetables make the departure time matter much like traffic does
+    fn supports_traffic(&self) -> bool {
+        true
+    }
+}
+
+// Maps an OpenTripPlanner leg mode onto the mode of its RouteInstruction
+fn otp_mode(mode: &str) -> String {
+    match mode {
+        "WALK" => "walk".to_string(),
+        "BUS" | "TROLLEYBUS" | "COACH" => "bus".to_string(),
+        "RAIL" | "SUBWAY" | "TRAM" | "MONORAIL" | "FUNICULAR" => "train".to_string(),
+        other => other.to_lowercase(),
+    }
+}
+
+// Valhalla's and OpenTripPlanner's date and time are local times without a zone; the
+// start's time zone isn't known here, so UTC is passed
+fn utc_date_time(time: SystemTime) -> String {
+    let secs = time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, day_secs) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's days_from_civil, inverted)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}", year, month, day, day_secs / 3600, day_secs % 3600 / 60)
+}
+
+// Maps a Valhalla maneuver type to the OSRM type and modifier used for icons
+fn valhalla_maneuver(maneuver_type: u32) -> (&'static str, Option<&'static str>) {
+    match maneuver_type {
+        1..=3 => ("depart", None),
+        4..=6 => ("arrive", None),
+        9 => ("turn", Some("slight right")),
+        10 => ("turn", Some("right")),
+        11 => ("turn", Some("sharp right")),
+        12 | 13 => ("turn", Some("uturn")),
+        14 => ("turn", Some("sharp left")),
+        15 => ("turn", Some("left")),
+        16 => ("turn", Some("slight left")),
+        18 | 20 => ("ramp", Some("slight right")),
+        19 | 21 => ("ramp", Some("slight left")),
+        23 => ("fork", Some("right")),
+        24 => ("fork", Some("left")),
+        25 | 37 | 38 => ("merge", None),
+        26 | 27 => ("roundabout", None),
+        _ => ("continue", None),
+    }
+}
+
+pub struct RoutingService {
+    pub osm_api_base: String,
+    pub geocoder_base: String, // must expose a Nominatim-compatible /search endpoint
+    pub elevation_base: String, // must expose an Open-Elevation-compatible /api/v1/lookup endpoint
+    pub overpass_base: String, // must expose an Overpass API /interpreter endpoint
+    pub max_retries: u32, // retries after the first attempt; 0 disables retrying
+    pub user_agent: String, // sent with every geocoder request
+    pub duplicate_threshold_m: f64, // see find_duplicate_waypoint; 0 disables the check
+    pub min_importance: f64, // geocode results less important than this are dropped; 0 keeps all
+    backend: Box<dyn RoutingBackend>, // computes routes; other OSRM services always use osm_api_base
+    transit_backend: Option<Box<dyn RoutingBackend>>, // computes TRANSIT_PROFILE routes
+    http: Box<dyn HttpClient>,
+    geocode_cache: Mutex<GeocodeCache>,
+    geocode_limiter: RateLimiter,
+}
+
+impl RoutingService {
+    pub fn new() -> Self {
+        Self::with_endpoints(DEFAULT_OSRM_BASE.to_string(), DEFAULT_GEOCODER_BASE.to_string())
+    }
+
+    pub fn with_endpoints(osm_api_base: String, geocoder_base: String) -> Self {
+        Self {
+            osm_api_base: osm_api_base.trim_end_matches('/').to_string(),
+            geocoder_base: geocoder_base.trim_end_matches('/').to_string(),
+            elevation_base: DEFAULT_ELEVATION_BASE.to_string(),
+            overpass_base: DEFAULT_OVERPASS_BASE.to_string(),
+            max_retries: 3,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            duplicate_threshold_m: DEFAULT_DUPLICATE_THRESHOLD_M,
+            min_importance: 0.0,
+            backend: Box::new(OsrmBackend::new(osm_api_base)),
+            transit_backend: None,
+            http: Box::new(ReqwestClient::new()),
+            geocode_cache: Mutex::new(GeocodeCache::new(DEFAULT_GEOCODE_CACHE_CAPACITY, DEFAULT_GEOCODE_CACHE_TTL)),
+            geocode_limiter: RateLimiter::new(DEFAULT_GEOCODE_INTERVAL),
+        }
+    }
+
+    // Replaces the geocode cache settings; a capacity of 0 disables caching
+    pub fn with_geocode_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.geocode_cache = Mutex::new(GeocodeCache::new(capacity, ttl));
+        self
+    }
+
+    // Replaces the OSRM route backend, e.g. with a ValhallaBackend
+    pub fn with_backend(mut self, backend: Box<dyn RoutingBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    // Enables the transit profile, e.g. with an OtpBackend
+    pub fn with_transit_backend(mut self, backend: Box<dyn RoutingBackend>) -> Self {
+        self.transit_backend = Some(backend);
+        self
+    }
+
+    // Identifies this app to the geocoder; include a contact address when using the public Nominatim
+    pub fn with_user_agent(mut self, user_agent: String) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    // Minimum spacing between geocoder requests
+    pub fn with_geocode_interval(mut self, interval: Duration) -> Self {
+        self.geocode_limiter = RateLimiter::new(interval);
+        self
+    }
+
+    // Limits each HTTP request, after which it fails with RoutingError::Timeout.
+    // Replaces the HTTP client, so call it before with_http_client.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.http = Box::new(ReqwestClient::with_timeout(timeout));
+        self
+    }
+
+    pub fn with_http_client(mut self, http: Box<dyn HttpClient>) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn with_elevation_endpoint(mut self, elevation_base: String) -> Self {
+        self.elevation_base = elevation_base.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_overpass_endpoint(mut self, overpass_base: String) -> Self {
+        self.overpass_base = overpass_base.trim_end_matches('/').to_string();
+        self
+    }
+
+    // Whether the route backend honors RouteRequest::depart_at. OSRM routes on static speeds.
+    pub fn supports_traffic(&self) -> bool {
+        self.backend.supports_traffic()
+    }
+
+    // Whether TRANSIT_PROFILE routes can be planned
+    pub fn supports_transit(&self) -> bool {
+        self.transit_backend.is_some()
+    }
+
+    pub async fn calculate_route(&self, request: &RouteRequest, geometry_format: GeometryFormat, use_miles: bool, language: Language, raw_instructions: bool) -> Result<RouteResponse, RoutingError> {
+        self.calculate_routes(request, geometry_format, use_miles, language, raw_instructions)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(RoutingError::NoRoute)
+    }
+
+    // Returns the primary route followed by any alternatives the backend offers.
+    // `raw_instructions` shows the server's own instruction text where it sends one (OSRM only).
+    pub async fn calculate_routes(&self, request: &RouteRequest, geometry_format: GeometryFormat, use_miles: bool, language: Language, raw_instructions: bool) -> Result<Vec<RouteResponse>, RoutingError> {
+        let waypoints = &request.waypoints;
+        let profile = request.profile.as_str();
+        
+        if waypoints.len() < 2 {
+            return Err(RoutingError::TooFewWaypoints);
+        }
+
+        let backend = match &self.transit_backend {
+            Some(transit_backend) if profile == TRANSIT_PROFILE => transit_backend.as_ref(),
+            _ if profile == TRANSIT_PROFILE => {
+                return Err(RoutingError::InvalidRequest("No transit router is configured".to_string()));
+            }
+            _ if !SUPPORTED_PROFILES.contains(&profile) => {
+                return Err(RoutingError::InvalidRequest(format!(
+                    "Unsupported routing profile '{}' (expected one of: {})",
+                    profile,
+                    SUPPORTED_PROFILES.join(", ")
+                )));
+            }
+            _ => self.backend.as_ref(),
+        };
+
+        for (index, waypoint) in waypoints.iter().enumerate() {
+            waypoint
+                .validate()
+                .map_err(|reason| RoutingError::InvalidWaypoint(index, reason))?;
+        }
+
+        // A repeated stop gives the backend a zero-length leg and confusing directions
+        if let Some((index, distance)) = find_duplicate_waypoint(waypoints, self.duplicate_threshold_m) {
+            return Err(RoutingError::DuplicateWaypoint(index, distance));
+        }
+
+        if request.depart_at.is_some() && !backend.supports_traffic() {
+            eprintln!("⏰ Routing server has no traffic data, ignoring the departure time");
+        }
+
+        backend.route(request, geometry_format, use_miles, language, raw_instructions, self.http.as_ref(), self.max_retries).await
+    }
+
+    // Moves a coordinate onto the nearest road of the driving network
+    pub async fn snap_to_road(&self, lat: f64, lng: f64) -> Result<Location, RoutingError> {
+        Waypoint { lat, lng, name: None, kind: WaypointKind::Stop }
+            .validate()
+            .map_err(|reason| RoutingError::InvalidWaypoint(0, reason))?;
+
+        let url = format!("{}/nearest/v1/driving/{},{}", self.osm_api_base, lng, lat);
+
+        let response = self.http.get(&url, self.max_retries).await?;
+
+        if !response.is_success() {
+            return Err(RoutingError::Http(response.status));
+        }
+
+        let nearest: OSRMNearestResponse = response.json()?;
+        nearest
+            .waypoints
+            .first()
+            .map(|waypoint| Location::new(waypoint.location[1], waypoint.location[0]))
+            .ok_or(RoutingError::NoRoute)
+    }
+
+    // Reorders the stops into the shortest driving trip using OSRM's /trip service.
+    // The first waypoint stays the start; the trip may end at any stop.
+    pub async fn optimize_route(&self, waypoints: &[Waypoint]) -> Result<Vec<Waypoint>, RoutingError> {
+        if waypoints.len() < 2 {
+            return Err(RoutingError::TooFewWaypoints);
+        }
+
+        for (index, waypoint) in waypoints.iter().enumerate() {
+            waypoint
+                .validate()
+                .map_err(|reason| RoutingError::InvalidWaypoint(index, reason))?;
+        }
+
+        let coordinates: Vec<String> = waypoints
+            .iter()
+            .map(|wp| format!("{},{}", wp.lng, wp.lat))
+            .collect();
+
+        let url = format!(
+            "{}/trip/v1/driving/{}?source=first&destination=any&roundtrip=false&overview=false",
+            self.osm_api_base,
+            coordinates.join(";")
+        );
+
+        let response = self.http.get(&url, self.max_retries).await?;
+
+        if !response.is_success() {
+            let status = response.status;
+            let error = response.json::<OSRMResponse>().ok();
+            if let Some(specific) = error.as_ref().and_then(OSRMResponse::code_error) {
+                return Err(specific);
+            }
+            return match error.and_then(|e| e.code).as_deref() {
+                Some("NoTrips") => Err(RoutingError::NoRoute),
+                _ => Err(RoutingError::Http(status)),
+            };
+        }
+
+        let trip: OSRMTripResponse = response.json()?;
+        trip.reorder(waypoints)
+    }
+
+    // Driving durations in seconds from each source (rows) to each destination (columns),
+    // via OSRM's /table service. Unreachable pairs are f64::INFINITY.
+    pub async fn distance_matrix(&self, sources: &[Waypoint], destinations: &[Waypoint]) -> Result<Vec<Vec<f64>>, RoutingError> {
+        if sources.is_empty() || destinations.is_empty() {
+            return Err(RoutingError::InvalidRequest("At least one source and one destination are required".to_string()));
+        }
+
+        // Numbered within their own list, since a position across both would match neither
+        for (role, waypoints) in [("Source", sources), ("Destination", destinations)] {
+            for (index, waypoint) in waypoints.iter().enumerate() {
+                waypoint
+                    .validate()
+                    .map_err(|reason| RoutingError::InvalidRequest(format!("{} {} is invalid: {}", role, index + 1, reason)))?;
+            }
+        }
+
+        // Sources come first in the coordinate list, destinations after them
+        let coordinates: Vec<String> = sources
+            .iter()
+            .chain(destinations)
+            .map(|wp| format!("{},{}", wp.lng, wp.lat))
+            .collect();
+        let source_indices: Vec<String> = (0..sources.len()).map(|i| i.to_string()).collect();
+        let destination_indices: Vec<String> = (sources.len()..sources.len() + destinations.len())
+            .map(|i| i.to_string())
+            .collect();
+
+        let url = format!(
+            "{}/table/v1/driving/{}?sources={}&destinations={}&annotations=duration",
+            self.osm_api_base,
+            coordinates.join(";"),
+            source_indices.join(";"),
+            destination_indices.join(";")
+        );
+
+        let response = self.http.get(&url, self.max_retries).await?;
+
+        if !response.is_success() {
+            return Err(RoutingError::Http(response.status));
+        }
+
+        let table: OSRMTableResponse = response.json()?;
+        Ok(table.duration_matrix())
+    }
+
+    // Heights in meters at evenly spaced points along the route geometry, start to end
+    pub async fn elevation_profile(&self, route: &RouteResponse) -> Result<Vec<f64>, RoutingError> {
+        let samples = sample_points(&route.coordinates(), ELEVATION_SAMPLES);
+        if samples.is_empty() {
+            return Err(RoutingError::Decode("Route has no geometry to sample".to_string()));
+        }
+
+        let url = format!("{}/api/v1/lookup", self.elevation_base);
+        let mut elevations = Vec::with_capacity(samples.len());
+
+        for batch in samples.chunks(ELEVATION_BATCH_SIZE) {
+            let body = serde_json::to_value(ElevationRequest {
+                locations: batch
+                    .iter()
+                    .map(|&(latitude, longitude)| ElevationPoint { latitude, longitude })
+                    .collect(),
+            })?;
+            let response = self.http.post_json(&url, &body, self.max_retries).await?;
+
+            if !response.is_success() {
+                return Err(RoutingError::Http(response.status));
+            }
+
+            let lookup: ElevationResponse = response.json()?;
+            if lookup.results.len() != batch.len() {
+                return Err(RoutingError::Decode(format!(
+                    "Expected {} elevations, got {}",
+                    batch.len(),
+                    lookup.results.len()
+                )));
+            }
+            elevations.extend(lookup.results.into_iter().map(|result| result.elevation));
+        }
+
+        Ok(elevations)
+    }
+
+    // OpenStreetMap nodes tagged amenity=`amenity` (e.g. "cafe", "fuel") within `radius_m`
+    // of `center`, nearest first, from the Overpass API. Rate limiting (429) and server
+    // timeouts (504) are retried like other requests, and so is a query the server abandons
+    // part way; once retries run out it fails as timed out rather than returning a partial list.
+    pub async fn nearest_poi(&self, center: Location, amenity: &str, radius_m: u32) -> Result<Vec<GeocodeResult>, RoutingError> {
+        // Only tag-value characters, so the value cannot break out of the query
+        if amenity.is_empty() || !amenity.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+            return Err(RoutingError::InvalidRequest(format!("'{}' is not an amenity type", amenity)));
+        }
+        if radius_m == 0 {
+            return Err(RoutingError::InvalidRequest("Search radius must be at least 1 m".to_string()));
+        }
+
+        let query = format!(
+            "[out:json][timeout:{}];node(around:{},{},{})[\"amenity\"=\"{}\"];out;",
+            OVERPASS_QUERY_TIMEOUT_S, radius_m, center.latitude, center.longitude, amenity
+        );
+        let url = format!("{}/interpreter?data={}", self.overpass_base, urlencoding::encode(&query));
+
+        // A query the server gave up on answers 200, so it is retried here like a failed request
+        let mut attempt = 0;
+        let overpass = loop {
+            let response = self.http.get(&url, self.max_retries).await?;
+
+            if !response.is_success() {
+                return Err(RoutingError::Http(response.status));
+            }
+
+            let overpass: OverpassResponse = response.json()?;
+            match overpass.remark.as_deref().filter(|remark| remark.contains("timed out")) {
+                Some(remark) if attempt < self.max_retries => {
+                    let delay = backoff_delay(attempt);
+                    eprintln!("⏳ Overpass gave up ({}), retrying in {:?}", remark, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Some(remark) => {
+                    eprintln!("⚠️ Overpass gave up: {}", remark);
+                    return Err(RoutingError::Timeout);
+                }
+                None => break overpass,
+            }
+        };
+
+        let mut places: Vec<GeocodeResult> = overpass
+            .elements
+            .into_iter()
+            .map(|element| GeocodeResult {
+                location: Location::new(element.lat, element.lon),
+                // Unnamed places are listed by their type, "fast food" for fast_food
+                display_name: element.tags.get("name").cloned().unwrap_or_else(|| amenity.replace('_', " ")),
+                kind: Some(amenity.to_string()),
+                class: Some("amenity".to_string()),
+                importance: 0.0,
+            })
+            .collect();
+        places.sort_by(|a, b| a.location.distance_to(&center).total_cmp(&b.location.distance_to(&center)));
+        Ok(places)
+    }
+
+    // Searches for places matching the query. With a viewbox (southwest and northeast corners)
+    // Nominatim returns only matches inside it (bounded=1); when nothing inside matches, the
+    // search is repeated without the box so distant places are still found. Non-empty
+    // `countries` (ISO 3166-1 alpha-2 codes) restricts results to those countries.
+    pub async fn geocode(&self, query: &str, viewbox: Option<(Location, Location)>, countries: &[&str]) -> Result<Vec<GeocodeResult>, RoutingError> {
+        let countries = normalize_country_codes(countries)?;
+        if let Some(viewbox) = &viewbox {
+            let results = self.geocode_in(query, Some(viewbox), &countries).await?;
+            if !results.is_empty() {
+                return Ok(results);
+            }
+            eprintln!("🔍 No results for '{}' in view, searching everywhere", query);
+        }
+        self.geocode_in(query, None, &countries).await
+    }
+
+    // Geocodes each query in turn, at the geocoder's rate limit, with one result per query
+    // so a failed lookup doesn't lose the others. Dropping the future stops the batch.
+    pub async fn geocode_batch(&self, queries: &[String]) -> Vec<Result<Vec<GeocodeResult>, RoutingError>> {
+        self.geocode_batch_until(queries, || false).await
+    }
+
+    // Like geocode_batch, but checks `cancelled` before each query and returns early with
+    // the results so far once it says so, e.g. `|| !ticket.is_current()`
+    pub async fn geocode_batch_until(&self, queries: &[String], cancelled: impl Fn() -> bool) -> Vec<Result<Vec<GeocodeResult>, RoutingError>> {
+        let mut results = Vec::with_capacity(queries.len());
+        for query in queries {
+            if cancelled() {
+                eprintln!("🛑 Geocoding batch stopped after {} of {} queries", results.len(), queries.len());
+                break;
+            }
+            results.push(self.geocode(query, None, &[]).await);
+        }
+        results
+    }
+
+    async fn geocode_in(&self, query: &str, viewbox: Option<&(Location, Location)>, countries: &[String]) -> Result<Vec<GeocodeResult>, RoutingError> {
+        let mut cache_key = normalize_query(query);
+        let encoded_query = urlencoding::encode(query);
+        let mut url = format!(
+            "{}/search?format=json&q={}",
+            self.geocoder_base, encoded_query
+        );
+
+        if !countries.is_empty() {
+            let codes = countries.join(",");
+            cache_key.push_str(&format!(" countries {}", codes));
+            url.push_str(&format!("&countrycodes={}", codes));
+        }
+
+        // Nominatim wants the box as left,top,right,bottom
+        if let Some((southwest, northeast)) = viewbox {
+            let bbox = format!(
+                "{},{},{},{}",
+                southwest.longitude, northeast.latitude, northeast.longitude, southwest.latitude
+            );
+            cache_key.push_str(&format!(" in {}", bbox));
+            url.push_str(&format!("&viewbox={}&bounded=1", bbox));
+        }
+
+        if let Some(results) = self.geocode_cache.lock().unwrap().get(&cache_key) {
+            eprintln!("📦 Using cached results for: {}", query);
+            return Ok(results);
+        }
+
+        self.geocode_limiter.acquire().await;
+        let headers = [("User-Agent", self.user_agent.as_str())];
+        let response = self.http.get_with_headers(&url, &headers, self.max_retries).await?;
+
+        if !response.is_success() {
+            return Err(RoutingError::Http(response.status));
+        }
+
+        let nominatim: Vec<NominatimResult> = response.json()?;
+        let mut results = nominatim
+            .into_iter()
+            .map(|result| {
+                let coordinate = |value: &str| {
+                    value
+                        .parse::<f64>()
+                        .map_err(|_| RoutingError::Decode(format!("invalid coordinate '{}'", value)))
+                };
+                Ok(GeocodeResult {
+                    location: Location::new(coordinate(&result.lat)?, coordinate(&result.lon)?),
+                    display_name: result.display_name,
+                    kind: result.kind,
+                    class: result.class,
+                    importance: result.importance.unwrap_or(0.0),
+                })
+            })
+            .collect::<Result<Vec<_>, RoutingError>>()?;
+
+        // Most relevant first: Nominatim's own order can put a hamlet ahead of a major city
+        results.retain(|result| result.importance >= self.min_importance);
+        results.sort_by(|a, b| b.importance.total_cmp(&a.importance));
+        self.geocode_cache.lock().unwrap().insert(cache_key, results.clone());
+
+        Ok(results)
+    }
+
+    // Looks up the address nearest to a coordinate via Nominatim's /reverse endpoint.
+    // Shares the geocoder's rate limit, since both hit the same server.
+    pub async fn reverse_geocode(&self, lat: f64, lng: f64) -> Result<String, RoutingError> {
+        Waypoint { lat, lng, name: None, kind: WaypointKind::Stop }
+            .validate()
+            .map_err(|reason| RoutingError::InvalidWaypoint(0, reason))?;
+
+        let url = format!("{}/reverse?format=json&lat={}&lon={}", self.geocoder_base, lat, lng);
+
+        self.geocode_limiter.acquire().await;
+        let headers = [("User-Agent", self.user_agent.as_str())];
+        let response = self.http.get_with_headers(&url, &headers, self.max_retries).await?;
+
+        if !response.is_success() {
+            return Err(RoutingError::Http(response.status));
+        }
+
+        // Nominatim answers 200 with an "error" field when nothing is near the point
+        let reverse: NominatimReverseResult = response.json()?;
+        match (reverse.display_name, reverse.error) {
+            (Some(name), _) => Ok(name),
+            (None, error) => Err(RoutingError::Decode(error.unwrap_or_else(|| "No address found".to_string()))),
+        }
+    }
+}
+
+// Sends a request, retrying connection failures, 5xx and 429 responses
+// with exponential backoff (or the server's Retry-After, when given and no longer than MAX_RETRY_AFTER).
+// Timeouts are not retried, so a hung server costs one request timeout rather than several.
+async fn send_with_retry(request: reqwest::RequestBuilder, max_retries: u32) -> Result<reqwest::Response, RoutingError> {
+    let mut attempt = 0;
+    
+    loop {
+        let attempt_request = request
+            .try_clone()
+            .ok_or_else(|| RoutingError::InvalidRequest("Request cannot be retried".to_string()))?;
+        
+        let delay = match attempt_request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                if !retryable || attempt >= max_retries {
+                    return Ok(response);
+                }
+                
+                let delay = retry_after(&response).unwrap_or_else(|| backoff_delay(attempt));
+                if delay > MAX_RETRY_AFTER {
+                    eprintln!("⏳ Server returned {} and asked to wait {:?}, giving up", status, delay);
+                    return Err(RoutingError::Http(status));
+                }
+                eprintln!("⏳ Server returned {}, retrying in {:?}", status, delay);
+                delay
+            }
+            Err(e) if e.is_connect() && !e.is_timeout() && attempt < max_retries => {
+                let delay = backoff_delay(attempt);
+                eprintln!("⏳ Request failed ({}), retrying in {:?}", e, delay);
+                delay
+            }
+            Err(e) => return Err(e.into()),
+        };
+        
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+fn backoff_delay(attempt: u32) -> Duration {
+    Duration::from_millis(RETRY_BASE_DELAY_MS * 2u64.pow(attempt))
+}
+
+// Only the delay-seconds form of Retry-After is honored; HTTP dates fall back to backoff
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+impl Default for RoutingService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Builds a GPX 1.1 document with the waypoints as <wpt> and the route line as a <trk>
+pub fn to_gpx(route: &RouteResponse, waypoints: &[Waypoint]) -> String {
+    let mut gpx = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <gpx version=\"1.1\" creator=\"Map.rs\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+    
+    for waypoint in waypoints {
+        gpx.push_str(&format!("  <wpt lat=\"{:.6}\" lon=\"{:.6}\">", waypoint.lat, waypoint.lng));
+        if let Some(name) = &waypoint.name {
+            gpx.push_str(&format!("<name>{}</name>", xml_escape(name)));
+        }
+        gpx.push_str("</wpt>\n");
+    }
+    
+    gpx.push_str("  <trk>\n    <name>Route</name>\n    <trkseg>\n");
+    for (lat, lng) in route.coordinates() {
+        gpx.push_str(&format!("      <trkpt lat=\"{:.6}\" lon=\"{:.6}\"/>\n", lat, lng));
+    }
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    
+    gpx
+}
+
+// Builds a FeatureCollection with the route line plus one point per turn instruction
+pub fn route_to_geojson(route: &RouteResponse) -> serde_json::Value {
+    let line_geometry: serde_json::Value = serde_json::from_str(&route.geometry).unwrap_or(serde_json::Value::Null);
+    
+    let mut features = vec![serde_json::json!({
+        "type": "Feature",
+        "geometry": line_geometry,
+        "properties": {
+            "distance": route.distance,
+            "duration": route.duration,
+        },
+    })];
+    
+    features.extend(route.instructions.iter().map(|instruction| {
+        serde_json::json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "Point",
+                "coordinates": [instruction.location.longitude, instruction.location.latitude],
+            },
+            "properties": {
+                "text": instruction.text,
+                "distance": instruction.distance,
+                "duration": instruction.duration,
+            },
+        })
+    }));
+    
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+// The bold line heading the directions pane, e.g. "Route: 12.3 km, 18 min"
+pub fn route_summary(route: &RouteResponse, use_miles: bool) -> String {
+    format!("Route: {}, {}", format_distance(route.distance, use_miles), format_duration(route.duration))
+}
+
+// A numbered step as shown in the directions pane, in the language the route was planned in.
+// Later steps say how far into the route they come: "4. After 2.3 km, turn left onto High
+// Street (limit 30 mph)"
+pub fn instruction_line(index: usize, instruction: &RouteInstruction, use_miles: bool, language: Language) -> String {
+    let text = if instruction.cumulative_distance > 0.0 {
+        let rest = if language.lowercase_after() {
+            let mut chars = instruction.text.chars();
+            chars.next().map(|first| first.to_lowercase().chain(chars).collect()).unwrap_or_default()
+        } else {
+            instruction.text.clone()
+        };
+        language
+            .phrase("after")
+            .replace("{distance}", &format_distance(instruction.cumulative_distance, use_miles))
+            .replace("{instruction}", &rest)
+    } else {
+        instruction.text.clone()
+    };
+    let speed_limit = instruction
+        .speed_limit
+        .map(|limit| format!(" ({})", language.phrase("speed_limit").replace("{speed}", &format_speed_limit(limit, use_miles))))
+        .unwrap_or_default();
+    format!("{}. {}{}", index + 1, text, speed_limit)
+}
+
+// Plain-text directions for pasting into a message: the summary line, then one line per
+// step followed by the length of that step
+pub fn directions_to_text(route: &RouteResponse, use_miles: bool, language: Language) -> String {
+    let mut text = route_summary(route, use_miles);
+    text.push('\n');
+    for (i, instruction) in route.instructions.iter().enumerate() {
+        text.push('\n');
+        text.push_str(&instruction_line(i, instruction, use_miles, language));
+        if instruction.distance > 0.0 {
+            text.push_str(&format!(" — {}", format_distance(instruction.distance, use_miles)));
+        }
+    }
+    text
+}
+
+// Increments format_distance rounds to, per display unit. Steps below 1 show as many
+// decimals as they need, so a 0.25 mi step gives "0.75 mi".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistanceRounding {
+    pub meters: f64, // under 1 km
+    pub kilometers: f64,
+    pub feet: f64, // feet and yards are used under units::SHORT_DISTANCE_MILES
+    pub yards: f64,
+    pub miles: f64,
+}
+
+impl DistanceRounding {
+    // What navigation apps show: 350 ft, 0.4 mi, 120 m, 2.3 km
+    pub const NAVIGATION: Self = Self { meters: 10.0, kilometers: 0.1, feet: 50.0, yards: 10.0, miles: 0.1 };
+    // Whole meters, feet and yards, for measurements
+    pub const PRECISE: Self = Self { meters: 1.0, kilometers: 0.1, feet: 1.0, yards: 1.0, miles: 0.1 };
+
+    fn short_imperial(&self, unit: units::ShortImperialUnit) -> f64 {
+        match unit {
+            units::ShortImperialUnit::Feet => self.feet,
+            units::ShortImperialUnit::Yards => self.yards,
+        }
+    }
+}
+
+impl Default for DistanceRounding {
+    fn default() -> Self {
+        Self::NAVIGATION
+    }
+}
+
+// Rounds to the nearest multiple of `step`; a step of 0 or less leaves the value alone
+fn round_to_step(value: f64, step: f64) -> f64 {
+    if step > 0.0 { (value / step).round() * step } else { value }
+}
+
+// Decimals needed to show multiples of `step` exactly, up to 3
+fn step_decimals(step: f64) -> usize {
+    (0..3)
+        .find(|&decimals| {
+            let scaled = step * 10f64.powi(decimals as i32);
+            (scaled - scaled.round()).abs() < 1e-9
+        })
+        .unwrap_or(3)
+}
+
+// Formats a distance for display: miles (feet under a tenth of a mile), or meters below
+// 1 km and kilometers above, rounded as DistanceRounding::NAVIGATION
+pub fn format_distance(meters: f64, use_miles: bool) -> String {
+    format_distance_with_short_unit(meters, use_miles, units::ShortImperialUnit::Feet)
+}
+
+// Like format_distance, with short imperial distances in the given unit
+pub fn format_distance_with_short_unit(meters: f64, use_miles: bool, short_unit: units::ShortImperialUnit) -> String {
+    format_distance_rounded(meters, use_miles, short_unit, &DistanceRounding::default())
+}
+
+// Like format_distance_with_short_unit, with the given rounding. The unit is picked after
+// rounding, so 995 m shows as "1.0 km" rather than "1000 m".
+pub fn format_distance_rounded(meters: f64, use_miles: bool, short_unit: units::ShortImperialUnit, rounding: &DistanceRounding) -> String {
+    if use_miles {
+        let step = rounding.short_imperial(short_unit);
+        let short = round_to_step(short_unit.from_meters(meters), step);
+        if units::is_short_imperial(short_unit.to_meters(short)) {
+            return format!("{:.*} {}", step_decimals(step), short, short_unit.symbol());
+        }
+        let miles = round_to_step(units::meters_to_miles(meters), rounding.miles);
+        format!("{:.*} mi", step_decimals(rounding.miles), miles)
+    } else {
+        let rounded = round_to_step(meters, rounding.meters);
+        if rounded < 1000.0 {
+            format!("{:.*} m", step_decimals(rounding.meters), rounded)
+        } else {
+            let kilometers = round_to_step(units::meters_to_km(meters), rounding.kilometers);
+            format!("{:.*} km", step_decimals(rounding.kilometers), kilometers)
+        }
+    }
+}
+
+// Formats a duration as "X hr Y min", or "Y min" under an hour
+pub fn format_duration(seconds: f64) -> String {
+    let total_minutes = (seconds.max(0.0) / 60.0) as u64;
+    let hours = total_minutes / 60;
+    let minutes = total_minutes % 60;
+    if hours > 0 {
+        format!("{} hr {} min", hours, minutes)
+    } else {
+        format!("{} min", minutes)
+    }
+}
+
+// Formats a height or climb for display, to the whole foot or meter, e.g. "1021 ft" or "311 m"
+pub fn format_height(meters: f64, use_miles: bool) -> String {
+    if use_miles {
+        format!("{:.0} ft", units::meters_to_feet(meters))
+    } else {
+        format!("{:.0} m", meters)
+    }
+}
+
+// Formats a speed limit given in m/s for display, e.g. "30 mph" or "50 km/h"
+pub fn format_speed_limit(meters_per_second: f64, use_miles: bool) -> String {
+    if use_miles {
+        format!("{:.0} mph", units::mps_to_mph(meters_per_second))
+    } else {
+        format!("{:.0} km/h", units::mps_to_kmh(meters_per_second))
+    }
+}
+
+// Maps an OSRM maneuver type and modifier to a turn hint shown next to the step
+pub fn maneuver_to_icon(t: &str, modifier: Option<&str>) -> &'static str {
+    match t {
+        "depart" => "depart",
+        "arrive" => "arrive",
+        "roundabout" | "rotary" => "roundabout",
+        "merge" => "merge",
+        "fork" => match modifier {
+            Some(m) if m.contains("right") => "fork-right",
+            _ => "fork-left",
+        },
+        // "turn", "ramp", "continue" and anything else follow the modifier
+        _ => match modifier {
+            Some("left") => "turn-left",
+            Some("right") => "turn-right",
+            Some("slight left") => "turn-slight-left",
+            Some("slight right") => "turn-slight-right",
+            Some("sharp left") => "turn-sharp-left",
+            Some("sharp right") => "turn-sharp-right",
+            Some("uturn") => "uturn",
+            _ => "straight",
+        },
+    }
+}
+
+// Total (ascent, descent) in meters over a series of heights, ignoring wobble under a meter
+pub fn elevation_stats(elevations: &[f64]) -> (f64, f64) {
+    elevation_stats_with_threshold(elevations, DEFAULT_ELEVATION_NOISE_M)
+}
+
+// Changes are measured from the last height that was counted, so a slow climb made of
+// steps below the threshold still adds up once it has gained `threshold` meters
+pub fn elevation_stats_with_threshold(elevations: &[f64], threshold: f64) -> (f64, f64) {
+    let mut heights = elevations.iter().copied().filter(|h| h.is_finite());
+    let Some(mut reference) = heights.next() else {
+        return (0.0, 0.0);
+    };
+
+    let (mut ascent, mut descent) = (0.0, 0.0);
+    for height in heights {
+        let delta = height - reference;
+        if delta.abs() >= threshold {
+            if delta > 0.0 {
+                ascent += delta;
+            } else {
+                descent -= delta;
+            }
+            reference = height;
+        }
+    }
+    (ascent, descent)
+}
+
+// Picks at most `max` points spread evenly along the line, always keeping both ends
+fn sample_points(points: &[(f64, f64)], max: usize) -> Vec<(f64, f64)> {
+    if points.len() <= max {
+        return points.to_vec();
+    }
+    if max < 2 {
+        return points.iter().take(max).copied().collect();
+    }
+    (0..max)
+        .map(|i| points[i * (points.len() - 1) / (max - 1)])
+        .collect()
+}
+
+// Formats a number as an English ordinal: 1st, 2nd, 3rd, 4th, 11th, 21st...
+fn ordinal(n: u8) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+// Decodes a Google encoded polyline (precision 5, as returned by OSRM) into (lat, lng) pairs.
+// Decoding stops at the first incomplete value, so truncated input yields the points before it.
+pub fn decode_polyline(s: &str) -> Vec<(f64, f64)> {
+    decode_polyline_with_precision(s, 5)
+}
+
+// Same as decode_polyline for polylines with `precision` decimal places (Valhalla uses 6)
+pub fn decode_polyline_with_precision(s: &str, precision: i32) -> Vec<(f64, f64)> {
+    let factor = 10f64.powi(precision);
+    let mut coordinates = Vec::new();
+    let mut bytes = s.bytes();
+    let (mut lat, mut lng) = (0i64, 0i64);
+
+    let mut next_delta = || -> Option<i64> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let chunk = i64::from(bytes.next()?) - 63;
+            if !(0..64).contains(&chunk) || shift > 60 {
+                return None;
+            }
+            result |= (chunk & 0x1f) << shift;
+            shift += 5;
+            if chunk < 0x20 {
+                break;
+            }
+        }
+        Some(if result & 1 == 1 { !(result >> 1) } else { result >> 1 })
+    };
+
+    while let Some(dlat) = next_delta() {
+        let Some(dlng) = next_delta() else { break };
+        lat += dlat;
+        lng += dlng;
+        coordinates.push((lat as f64 / factor, lng as f64 / factor));
+    }
+
+    coordinates
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// OSRM API response structures
+#[derive(Debug, Deserialize)]
+struct OSRMTripResponse {
+    waypoints: Vec<OSRMTripWaypoint>, // in input order
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMTripWaypoint {
+    waypoint_index: usize, // position of this input waypoint within the trip
+}
+
+impl OSRMTripResponse {
+    fn reorder(&self, waypoints: &[Waypoint]) -> Result<Vec<Waypoint>, RoutingError> {
+        if self.waypoints.len() != waypoints.len() {
+            return Err(RoutingError::Decode(format!(
+                "trip has {} waypoints, expected {}",
+                self.waypoints.len(),
+                waypoints.len()
+            )));
+        }
+
+        let mut ordered: Vec<Option<Waypoint>> = vec![None; waypoints.len()];
+        for (waypoint, trip_waypoint) in waypoints.iter().zip(&self.waypoints) {
+            match ordered.get_mut(trip_waypoint.waypoint_index) {
+                Some(slot @ None) => *slot = Some(waypoint.clone()),
+                _ => return Err(RoutingError::Decode(format!("invalid trip position {}", trip_waypoint.waypoint_index))),
+            }
+        }
+
+        Ok(ordered.into_iter().flatten().collect())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMNearestResponse {
+    waypoints: Vec<OSRMWaypoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMWaypoint {
+    location: [f64; 2], // [lng, lat]
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMTableResponse {
+    durations: Vec<Vec<Option<f64>>>, // null where no route exists
+}
+
+impl OSRMTableResponse {
+    fn duration_matrix(self) -> Vec<Vec<f64>> {
+        self.durations
+            .into_iter()
+            .map(|row| row.into_iter().map(|d| d.unwrap_or(f64::INFINITY)).collect())
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ElevationRequest {
+    locations: Vec<ElevationPoint>,
+}
+
+#[derive(Debug, Serialize)]
+struct ElevationPoint {
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ElevationResponse {
+    results: Vec<ElevationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ElevationResult {
+    elevation: f64,
+}
+
+// Overpass API response structures
+#[derive(Debug, Deserialize)]
+struct OverpassResponse {
+    #[serde(default)]
+    elements: Vec<OverpassElement>,
+    remark: Option<String>, // set when the server stopped early, e.g. "runtime error: Query timed out..."
+}
+
+#[derive(Debug, Deserialize)]
+struct OverpassElement {
+    lat: f64,
+    lon: f64,
+    #[serde(default)]
+    tags: std::collections::HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMResponse {
+    #[serde(default)]
+    code: Option<String>, // "Ok" on success
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    routes: Vec<OSRMRoute>, // missing on errors
+}
+
+impl OSRMResponse {
+    // The specific error for a failure code OSRM reported, if it is one we can explain
+    fn code_error(&self) -> Option<RoutingError> {
+        match self.code.as_deref() {
+            Some("NoRoute") => Some(RoutingError::NoRoute),
+            Some("NoSegment") => Some(RoutingError::NoSegment),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMRoute {
+    distance: f64,
+    duration: f64,
+    geometry: OSRMGeometry,
+    legs: Vec<OSRMLeg>,
+}
+
+// Route geometry as returned for `geometries=geojson` or `geometries=polyline`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OSRMGeometry {
+    GeoJson(geojson::Geometry),
+    Polyline(String),
+}
+
+impl OSRMGeometry {
+    fn to_geojson(&self) -> geojson::Geometry {
+        match self {
+            OSRMGeometry::GeoJson(geometry) => geometry.clone(),
+            OSRMGeometry::Polyline(encoded) => geojson::Geometry::new(geojson::Value::LineString(
+                decode_polyline(encoded)
+                    .into_iter()
+                    .map(|(lat, lng)| vec![lng, lat])
+                    .collect(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMLeg {
+    distance: f64,
+    duration: f64,
+    steps: Vec<OSRMStep>,
+    annotation: Option<OSRMAnnotation>,
+}
+
+impl OSRMLeg {
+    // Assigns each step the speed limit covering most of its length. Annotations are
+    // per geometry segment, so segments are matched to steps by cumulative distance.
+    fn step_speed_limits(&self) -> Vec<Option<f64>> {
+        let Some(annotation) = &self.annotation else {
+            return vec![None; self.steps.len()];
+        };
+        
+        let mut segments = annotation.distance.iter().zip(&annotation.maxspeed).peekable();
+        let mut segment_start = 0.0;
+        let mut step_end = 0.0;
+        
+        self.steps
+            .iter()
+            .map(|step| {
+                step_end += step.distance;
+                
+                // Total distance covered by each distinct limit within this step
+                let mut limits: Vec<(Option<f64>, f64)> = Vec::new();
+                while let Some((distance, maxspeed)) = segments.next_if(|(distance, _)| segment_start + *distance / 2.0 <= step_end) {
+                    segment_start += distance;
+                    let limit = maxspeed.meters_per_second();
+                    match limits.iter_mut().find(|(l, _)| *l == limit) {
+                        Some((_, total)) => *total += distance,
+                        None => limits.push((limit, *distance)),
+                    }
+                }
+                
+                limits
+                    .into_iter()
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .and_then(|(limit, _)| limit)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMAnnotation {
+    #[serde(default)]
+    distance: Vec<f64>,
+    #[serde(default)]
+    maxspeed: Vec<OSRMMaxSpeed>,
+}
+
+// Either {"speed": 50, "unit": "km/h"}, {"none": true} or {"unknown": true}
+#[derive(Debug, Deserialize)]
+struct OSRMMaxSpeed {
+    speed: Option<f64>,
+    unit: Option<String>,
+}
+
+impl OSRMMaxSpeed {
+    fn meters_per_second(&self) -> Option<f64> {
+        let to_mps = match self.unit.as_deref() {
+            Some("mph") => units::mph_to_mps,
+            _ => units::kmh_to_mps,
+        };
+        self.speed.map(to_mps)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMStep {
+    distance: f64,
+    duration: f64,
+    maneuver: OSRMManeuver,
+    name: Option<String>,
+    #[serde(rename = "ref")]
+    ref_: Option<String>,
+    mode: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OSRMManeuver {
+    location: [f64; 2],
+    instruction: Option<String>,
+    #[serde(rename = "type")]
+    maneuver_type: Option<String>,
+    modifier: Option<String>,
+    bearing_after: Option<f64>,
+    exit: Option<u8>, // roundabout exit to take, counted from the entry
+}
+
+// Valhalla API response structures
+#[derive(Debug, Deserialize)]
+struct ValhallaResponse {
+    trip: ValhallaTrip,
+    #[serde(default)]
+    alternates: Vec<ValhallaAlternate>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValhallaAlternate {
+    trip: ValhallaTrip,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValhallaTrip {
+    legs: Vec<ValhallaLeg>,
+    summary: ValhallaSummary,
+}
+
+impl ValhallaTrip {
+    fn to_route(&self) -> Result<RouteResponse, RoutingError> {
+        let mut points = Vec::new();
+        let mut instructions = Vec::new();
+        let mut legs = Vec::new();
+        let (mut cumulative_distance, mut cumulative_duration) = (0.0, 0.0);
+        for leg in &self.legs {
+            // Valhalla's leg summary is the sum of its maneuvers
+            legs.push(LegSummary {
+                distance: leg.maneuvers.iter().map(|m| m.length * 1000.0).sum(),
+                duration: leg.maneuvers.iter().map(|m| m.time).sum(),
+                first_instruction: instructions.len(),
+            });
+            let shape = decode_polyline_with_precision(&leg.shape, 6);
+            for maneuver in &leg.maneuvers {
+                let (lat, lng) = shape.get(maneuver.begin_shape_index).or(shape.last()).copied().unwrap_or_default();
+                let (maneuver_type, modifier) = valhalla_maneuver(maneuver.maneuver_type);
+                instructions.push(RouteInstruction {
+                    text: maneuver.instruction.clone(),
+                    distance: maneuver.length * 1000.0,
+                    duration: maneuver.time,
+                    location: Location::new(lat, lng),
+                    speed_limit: None,
+                    maneuver_icon: maneuver_to_icon(maneuver_type, modifier).to_string(),
+                    mode: None,
+                    bearing: None,
+                    cumulative_distance,
+                    cumulative_duration,
+                });
+                cumulative_distance += maneuver.length * 1000.0;
+                cumulative_duration += maneuver.time;
+            }
+            points.extend(shape);
+        }
+
+        let geometry = geojson::Geometry::new(geojson::Value::LineString(
+            points.into_iter().map(|(lat, lng)| vec![lng, lat]).collect(),
+        ));
+        Ok(RouteResponse {
+            distance: self.summary.length * 1000.0, // kilometers, as requested
+            duration: self.summary.time,
+            geometry: serde_json::to_string(&geometry)?,
+            instructions,
+            legs,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ValhallaLeg {
+    maneuvers: Vec<ValhallaManeuver>,
+    shape: String, // polyline with precision 6
+}
+
+#[derive(Debug, Deserialize)]
+struct ValhallaSummary {
+    length: f64,
+    time: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValhallaManeuver {
+    #[serde(rename = "type")]
+    maneuver_type: u32,
+    instruction: String,
+    length: f64,
+    time: f64,
+    begin_shape_index: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValhallaError {
+    error_code: u32,
+    error: Option<String>,
+}
+
+// OpenTripPlanner API response structures
+#[derive(Debug, Deserialize)]
+struct OtpResponse {
+    plan: Option<OtpPlan>,
+    error: Option<OtpError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OtpPlan {
+    #[serde(default)]
+    itineraries: Vec<OtpItinerary>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OtpItinerary {
+    duration: f64, // seconds, including waits between rides
+    legs: Vec<OtpLeg>,
+}
+
+impl OtpItinerary {
+    fn to_route(&self, use_miles: bool, language: Language) -> Result<RouteResponse, RoutingError> {
+        let mut points = Vec::new();
+        let mut instructions = Vec::new();
+        let (mut cumulative_distance, mut cumulative_duration) = (0.0, 0.0);
+        for leg in &self.legs {
+            let mode = otp_mode(&leg.mode);
+            let destination = leg.to.name.clone().unwrap_or_else(|| format!("{:.5}, {:.5}", leg.to.lat, leg.to.lon));
+            let text = if mode == "walk" {
+                language.phrase("transit_walk").replace("{distance}", &format_distance(leg.distance, use_miles))
+            } else {
+                let vehicle = language.phrase(match mode.as_str() {
+                    "bus" => "transit_bus",
+                    "train" => "transit_train",
+                    "ferry" => "transit_ferry",
+                    _ => "transit_line",
+                });
+                let line = leg.route_short_name.as_deref().map(|name| format!(" {}", name)).unwrap_or_default();
+                language.phrase("transit_ride").replace("{mode}", vehicle).replace("{line}", &line)
+            };
+
+            instructions.push(RouteInstruction {
+                text: text.replace("{name}", &destination),
+                distance: leg.distance,
+                duration: leg.duration,
+                location: Location::new(leg.from.lat, leg.from.lon),
+                speed_limit: None,
+                maneuver_icon: maneuver_to_icon(if instructions.is_empty() { "depart" } else { "continue" }, None).to_string(),
+                mode: Some(mode),
+                bearing: None,
+                cumulative_distance,
+                cumulative_duration,
+            });
+            cumulative_distance += leg.distance;
+            cumulative_duration += leg.duration;
+            match &leg.leg_geometry {
+                Some(geometry) => points.extend(decode_polyline(&geometry.points)),
+                None => points.extend([(leg.from.lat, leg.from.lon), (leg.to.lat, leg.to.lon)]),
+            }
+        }
+
+        if let Some(last) = self.legs.last() {
+            instructions.push(RouteInstruction {
+                text: language.phrase("arrive").to_string(),
+                distance: 0.0,
+                duration: 0.0,
+                location: Location::new(last.to.lat, last.to.lon),
+                speed_limit: None,
+                maneuver_icon: maneuver_to_icon("arrive", None).to_string(),
+                mode: None,
+                bearing: None,
+                cumulative_distance,
+                // Leg times leave out waits between rides, which only the itinerary total has
+                cumulative_duration: self.duration.max(cumulative_duration),
+            });
+        }
+
+        let distance = self.legs.iter().map(|leg| leg.distance).sum();
+        let geometry = geojson::Geometry::new(geojson::Value::LineString(
+            points.into_iter().map(|(lat, lng)| vec![lng, lat]).collect(),
+        ));
+        Ok(RouteResponse {
+            distance,
+            duration: self.duration,
+            geometry: serde_json::to_string(&geometry)?,
+            instructions,
+            legs: vec![LegSummary { distance, duration: self.duration, first_instruction: 0 }],
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
-struct OSRMLeg {
+#[serde(rename_all = "camelCase")]
+struct OtpLeg {
+    mode: String, // "WALK", "BUS", "RAIL", ...
     distance: f64,
     duration: f64,
-    steps: Vec<OSRMStep>,
+    from: OtpPlace,
+    to: OtpPlace,
+    route_short_name: Option<String>,
+    leg_geometry: Option<OtpGeometry>,
 }
 
 #[derive(Debug, Deserialize)]
-struct OSRMStep {
-    distance: f64,
-    duration: f64,
-    maneuver: OSRMManeuver,
+struct OtpPlace {
     name: Option<String>,
-    ref_: Option<String>,
-    #[serde(rename = "destinations")]
-    destinations: Option<String>,
-    mode: Option<String>,
+    lat: f64,
+    lon: f64,
 }
 
 #[derive(Debug, Deserialize)]
-struct OSRMManeuver {
-    location: [f64; 2],
-    instruction: Option<String>,
-    #[serde(rename = "type")]
-    maneuver_type: Option<String>,
-    modifier: Option<String>,
-    bearing_after: Option<f64>,
-    bearing_before: Option<f64>,
+struct OtpGeometry {
+    points: String, // polyline with precision 5
+}
+
+#[derive(Debug, Deserialize)]
+struct OtpError {
+    id: i64,
+    msg: String,
 }
 
 // Nominatim API response structure
@@ -315,4 +2487,1115 @@ struct NominatimResult {
     lat: String,
     lon: String,
     display_name: String,
-}
\ No newline at end of file
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    class: Option<String>,
+    importance: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NominatimReverseResult {
+    display_name: Option<String>,
+    error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_route() -> RouteResponse {
+        RouteResponse {
+            distance: 1200.0,
+            duration: 180.0,
+            geometry: r#"{"type":"LineString","coordinates":[[-0.1278,51.5074],[-0.1245,51.4994]]}"#.to_string(),
+            instructions: Vec::new(),
+            legs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_route_coordinates() {
+        let coordinates = sample_route().coordinates();
+        assert_eq!(coordinates, vec![(51.5074, -0.1278), (51.4994, -0.1245)]);
+    }
+
+    #[test]
+    fn test_route_to_geojson() {
+        let mut route = sample_route();
+        route.instructions.push(RouteInstruction {
+            text: "Arrive at your destination".to_string(),
+            distance: 0.0,
+            duration: 0.0,
+            location: Location::new(51.4994, -0.1245),
+            speed_limit: None,
+            maneuver_icon: "arrive".to_string(),
+            mode: None,
+            bearing: None,
+            cumulative_distance: 1200.0,
+            cumulative_duration: 180.0,
+        });
+        
+        let collection = route_to_geojson(&route);
+        assert_eq!(collection["type"], "FeatureCollection");
+        
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["geometry"]["type"], "LineString");
+        assert_eq!(features[1]["geometry"]["coordinates"], serde_json::json!([-0.1245, 51.4994]));
+        assert_eq!(features[1]["properties"]["text"], "Arrive at your destination");
+        
+        // Output must be valid GeoJSON
+        assert!(collection.to_string().parse::<geojson::GeoJson>().is_ok());
+    }
+
+    #[test]
+    fn test_to_gpx() {
+        let waypoints = vec![
+            Waypoint { lat: 51.5074, lng: -0.1278, name: Some("Fish & Chips".to_string()), kind: WaypointKind::Stop },
+            Waypoint { lat: 51.4994, lng: -0.1245, name: None, kind: WaypointKind::Stop },
+        ];
+        
+        let gpx = to_gpx(&sample_route(), &waypoints);
+        assert!(gpx.starts_with("<?xml"));
+        assert!(gpx.contains("<gpx version=\"1.1\""));
+        assert!(gpx.contains("<wpt lat=\"51.507400\" lon=\"-0.127800\"><name>Fish &amp; Chips</name></wpt>"));
+        assert_eq!(gpx.matches("<trkpt").count(), 2);
+        assert!(gpx.trim_end().ends_with("</gpx>"));
+    }
+
+    #[test]
+    fn test_directions_to_text() {
+        let step = |text: &str, distance, cumulative_distance, speed_limit| RouteInstruction {
+            text: text.to_string(),
+            distance,
+            duration: 0.0,
+            location: Location::new(51.5, -0.12),
+            speed_limit,
+            maneuver_icon: "straight".to_string(),
+            mode: None,
+            bearing: None,
+            cumulative_distance,
+            cumulative_duration: 0.0,
+        };
+        let mut route = sample_route();
+        route.instructions = vec![
+            step("Head south on Whitehall", 400.0, 0.0, Some(13.4)),
+            step("Turn left onto Parliament Street", 800.0, 400.0, None),
+            step("Arrive at your destination", 0.0, 1200.0, None),
+        ];
+        
+        assert_eq!(
+            directions_to_text(&route, false, Language::En),
+            "Route: 1.2 km, 3 min\n\
+             \n1. Head south on Whitehall (limit 48 km/h) — 400 m\
+             \n2. After 400 m, turn left onto Parliament Street — 800 m\
+             \n3. After 1.2 km, arrive at your destination"
+        );
+        assert!(directions_to_text(&route, true, Language::En).starts_with("Route: 0.7 mi, 3 min\n\n1. Head south on Whitehall (limit 30 mph) — 0.2 mi"));
+        
+        // Steps stay in the route's language; only languages that continue the sentence lowercase it
+        route.instructions[1].text = "Gira a la derecha".to_string();
+        assert_eq!(instruction_line(1, &route.instructions[1], false, Language::Es), "2. Tras 400 m, gira a la derecha");
+        route.instructions[1].text = "Biege rechts ab".to_string();
+        route.instructions[1].speed_limit = Some(13.4);
+        assert_eq!(instruction_line(1, &route.instructions[1], false, Language::De), "2. Nach 400 m: Biege rechts ab (Tempolimit 48 km/h)");
+    }
+
+    #[test]
+    fn test_waypoint_validate() {
+        let waypoint = |lat, lng| Waypoint { lat, lng, name: None, kind: WaypointKind::Stop };
+        
+        assert!(waypoint(90.0, 180.0).validate().is_ok());
+        assert!(waypoint(-90.0, -180.0).validate().is_ok());
+        assert!(waypoint(0.0, 0.0).validate().is_ok());
+        
+        assert!(waypoint(90.000001, 0.0).validate().is_err());
+        assert!(waypoint(-90.000001, 0.0).validate().is_err());
+        assert!(waypoint(0.0, 180.000001).validate().is_err());
+        assert!(waypoint(0.0, -180.000001).validate().is_err());
+        assert!(waypoint(f64::NAN, 0.0).validate().is_err());
+        assert!(waypoint(0.0, f64::NAN).validate().is_err());
+        assert!(waypoint(f64::INFINITY, 0.0).validate().is_err());
+    }
+
+    // Answers every request with the same canned status and body, recording request headers
+    struct CannedHttp {
+        status: reqwest::StatusCode,
+        body: String,
+        headers: std::sync::Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl HttpClient for CannedHttp {
+        fn get_with_headers<'a>(&'a self, _url: &'a str, headers: &'a [(&'a str, &'a str)], _max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.headers.lock().unwrap().extend(headers.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+            let response = HttpResponse { status: self.status, body: self.body.clone() };
+            async move { Ok(response) }.boxed_local()
+        }
+
+        fn post_json<'a>(&'a self, url: &'a str, _body: &'a serde_json::Value, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.get(url, max_retries)
+        }
+    }
+
+    async fn nearest_poi_with_canned(status: u16, body: &str, amenity: &str) -> Result<Vec<GeocodeResult>, RoutingError> {
+        let service = RoutingService::new().with_http_client(Box::new(CannedHttp {
+            status: reqwest::StatusCode::from_u16(status).unwrap(),
+            body: body.to_string(),
+            headers: Default::default(),
+        }));
+        service.nearest_poi(Location::new(51.5074, -0.1278), amenity, 500).await
+    }
+
+    #[tokio::test]
+    async fn test_nearest_poi() {
+        let body = serde_json::json!({
+            "elements": [
+                { "type": "node", "id": 1, "lat": 51.5110, "lon": -0.1278, "tags": { "amenity": "cafe", "name": "Far Cafe" } },
+                { "type": "node", "id": 2, "lat": 51.5080, "lon": -0.1278, "tags": { "amenity": "cafe" } },
+                { "type": "node", "id": 3, "lat": 51.5090, "lon": -0.1278, "tags": { "amenity": "cafe", "name": "Middle Cafe" } }
+            ]
+        });
+        let places = nearest_poi_with_canned(200, &body.to_string(), "cafe").await.unwrap();
+        let names: Vec<&str> = places.iter().map(|place| place.display_name.as_str()).collect();
+        assert_eq!(names, vec!["cafe", "Middle Cafe", "Far Cafe"]);
+        assert_eq!(places[0].kind.as_deref(), Some("cafe"));
+        assert_eq!(places[1].location.latitude, 51.5090);
+        
+        assert!(nearest_poi_with_canned(200, r#"{"elements":[]}"#, "fuel").await.unwrap().is_empty());
+        
+        assert!(matches!(nearest_poi_with_canned(429, "", "cafe").await, Err(RoutingError::Http(status)) if status == 429));
+        
+        // Amenity values that could alter the query are refused before any request
+        assert!(matches!(nearest_poi_with_canned(200, "{}", "cafe\"];out;").await, Err(RoutingError::InvalidRequest(_))));
+        assert!(matches!(nearest_poi_with_canned(200, "{}", "").await, Err(RoutingError::InvalidRequest(_))));
+    }
+
+    // Answers with each body in turn, repeating the last one
+    struct SequenceHttp {
+        bodies: Mutex<VecDeque<String>>,
+        calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl HttpClient for SequenceHttp {
+        fn get_with_headers<'a>(&'a self, _url: &'a str, _headers: &'a [(&'a str, &'a str)], _max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let mut bodies = self.bodies.lock().unwrap();
+            let body = if bodies.len() > 1 { bodies.pop_front() } else { bodies.front().cloned() }.unwrap_or_default();
+            async move { Ok(HttpResponse { status: reqwest::StatusCode::OK, body }) }.boxed_local()
+        }
+
+        fn post_json<'a>(&'a self, url: &'a str, _body: &'a serde_json::Value, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.get(url, max_retries)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nearest_poi_retries_abandoned_queries() {
+        let abandoned = r#"{"elements":[],"remark":"runtime error: Query timed out in \"query\" at line 1 after 26 seconds."}"#;
+        let found = r#"{"elements":[{"type":"node","id":1,"lat":51.5080,"lon":-0.1278,"tags":{"amenity":"cafe"}}]}"#;
+        let service = |bodies: &[&str], calls: &std::sync::Arc<std::sync::atomic::AtomicUsize>| {
+            let mut service = RoutingService::new().with_http_client(Box::new(SequenceHttp {
+                bodies: Mutex::new(bodies.iter().map(|body| body.to_string()).collect()),
+                calls: calls.clone(),
+            }));
+            service.max_retries = 1;
+            service
+        };
+        let center = Location::new(51.5074, -0.1278);
+
+        // A query the server gave up on is asked again
+        let calls = Default::default();
+        let places = service(&[abandoned, found], &calls).nearest_poi(center.clone(), "cafe", 500).await.unwrap();
+        assert_eq!(places.len(), 1);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // Once retries run out it is a timeout, not an empty result
+        let calls = Default::default();
+        let error = service(&[abandoned], &calls).nearest_poi(center, "cafe", 500).await.unwrap_err();
+        assert!(matches!(error, RoutingError::Timeout));
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    async fn route_with_canned(status: u16, body: &str) -> Result<Vec<RouteResponse>, RoutingError> {
+        let service = RoutingService::new().with_http_client(Box::new(CannedHttp {
+            status: reqwest::StatusCode::from_u16(status).unwrap(),
+            body: body.to_string(),
+            headers: Default::default(),
+        }));
+        let request = RouteRequest {
+            waypoints: vec![
+                Waypoint { lat: 52.5, lng: 13.4, name: None, kind: WaypointKind::Stop },
+                Waypoint { lat: 52.51, lng: 13.41, name: None, kind: WaypointKind::Stop },
+            ],
+            ..RouteRequest::default()
+        };
+        service.calculate_routes(&request, GeometryFormat::GeoJson, false, Language::En, false).await
+    }
+
+    #[tokio::test]
+    async fn test_route_parsing_with_canned_responses() {
+        let body = serde_json::json!({
+            "code": "Ok",
+            "routes": [{
+                "distance": 1500.0,
+                "duration": 120.0,
+                "geometry": { "type": "LineString", "coordinates": [[13.4, 52.5], [13.41, 52.51]] },
+                "legs": [{
+                    "distance": 1500.0,
+                    "duration": 120.0,
+                    "steps": [
+                        { "distance": 1500.0, "duration": 120.0, "name": "Unter den Linden", "maneuver": { "location": [13.4, 52.5], "type": "depart", "bearing_after": 90 } },
+                        { "distance": 0.0, "duration": 0.0, "name": "", "maneuver": { "location": [13.41, 52.51], "type": "arrive" } }
+                    ]
+                }]
+            }]
+        });
+        let routes = route_with_canned(200, &body.to_string()).await.unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].distance, 1500.0);
+        assert_eq!(routes[0].coordinates(), vec![(52.5, 13.4), (52.51, 13.41)]);
+        assert_eq!(routes[0].instructions.len(), 2);
+        assert_eq!(routes[0].instructions[0].maneuver_icon, "depart");
+        assert_eq!(routes[0].instructions[0].bearing, Some(90.0));
+        assert_eq!(routes[0].instructions[1].bearing, None);
+        
+        // Near the start the departure is next; once there, the arrival
+        assert_eq!(routes[0].upcoming_instruction(&Location::new(52.4995, 13.4), 20.0), Some(0));
+        assert_eq!(routes[0].upcoming_instruction(&Location::new(52.50005, 13.4), 20.0), Some(1));
+        assert_eq!(routes[0].upcoming_instruction(&Location::new(52.51, 13.41), 20.0), Some(1));
+
+        // A successful response without routes, and OSRM's own NoRoute error
+        assert!(matches!(route_with_canned(200, r#"{"code":"Ok","routes":[]}"#).await, Err(RoutingError::NoRoute)));
+        assert!(matches!(route_with_canned(400, r#"{"code":"NoRoute","message":"Impossible route"}"#).await, Err(RoutingError::NoRoute)));
+        assert!(matches!(route_with_canned(400, r#"{"code":"NoSegment","message":"Could not find a matching segment for coordinate 1"}"#).await, Err(RoutingError::NoSegment)));
+        assert!(matches!(route_with_canned(200, r#"{"code":"NoSegment"}"#).await, Err(RoutingError::NoSegment)));
+        assert!(matches!(route_with_canned(400, r#"{"code":"InvalidQuery","message":"Query string malformed"}"#).await, Err(RoutingError::Http(status)) if status == 400));
+
+        assert!(matches!(route_with_canned(200, "{\"code\":\"Ok\",\"routes\":[").await, Err(RoutingError::Decode(_))));
+        assert!(matches!(route_with_canned(502, "<html>Bad Gateway</html>").await, Err(RoutingError::Http(status)) if status == 502));
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // A server that accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepted = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                connections.push(socket);
+            }
+        });
+
+        // Default retries: a timed-out request is not sent again
+        let service = RoutingService::with_endpoints(format!("http://{}", address), DEFAULT_GEOCODER_BASE.to_string())
+            .with_request_timeout(Duration::from_millis(200));
+        assert_eq!(service.max_retries, 3);
+        let request = RouteRequest {
+            waypoints: vec![
+                Waypoint { lat: 52.5, lng: 13.4, name: None, kind: WaypointKind::Stop },
+                Waypoint { lat: 52.51, lng: 13.41, name: None, kind: WaypointKind::Stop },
+            ],
+            ..RouteRequest::default()
+        };
+        let started = Instant::now();
+        let error = service.calculate_route(&request, GeometryFormat::GeoJson, false, Language::En, false).await.unwrap_err();
+        assert!(error.is_timeout(), "unexpected error: {}", error);
+        assert!(error.is_transient());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(matches!(error, RoutingError::Timeout));
+        assert_eq!(error.to_string(), "Request timed out");
+    }
+
+    #[tokio::test]
+    async fn test_long_retry_after_is_not_waited_for() {
+        // A server that rate-limits every request for an hour
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepted = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut socket, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut request = [0u8; 4096];
+                let _ = socket.read(&mut request).await;
+                let _ = socket.write_all(b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 3600\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+            }
+        });
+
+        let service = RoutingService::with_endpoints(DEFAULT_OSRM_BASE.to_string(), format!("http://{}", address))
+            .with_geocode_interval(Duration::ZERO);
+        let started = Instant::now();
+        let error = service.reverse_geocode(52.516, 13.378).await.unwrap_err();
+        assert!(matches!(error, RoutingError::Http(status) if status == reqwest::StatusCode::TOO_MANY_REQUESTS), "unexpected error: {}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_geocoder_requests_send_user_agent() {
+        let canned = |body: &str, headers: &std::sync::Arc<Mutex<Vec<(String, String)>>>| {
+            RoutingService::with_endpoints(DEFAULT_OSRM_BASE.to_string(), DEFAULT_GEOCODER_BASE.to_string())
+                .with_geocode_interval(Duration::ZERO)
+                .with_user_agent("map-rs-test (maps@example.org)".to_string())
+                .with_http_client(Box::new(CannedHttp { status: reqwest::StatusCode::OK, body: body.to_string(), headers: headers.clone() }))
+        };
+        let expected = vec![("User-Agent".to_string(), "map-rs-test (maps@example.org)".to_string())];
+        
+        let headers = Default::default();
+        let service = canned(r#"{"display_name":"Pariser Platz, Berlin"}"#, &headers);
+        assert_eq!(service.reverse_geocode(52.516, 13.378).await.unwrap(), "Pariser Platz, Berlin");
+        assert_eq!(*headers.lock().unwrap(), expected);
+        
+        let headers = Default::default();
+        let service = canned("[]", &headers);
+        assert!(service.geocode("Nowhere", None, &[]).await.unwrap().is_empty());
+        assert_eq!(*headers.lock().unwrap(), expected);
+        
+        assert!(DEFAULT_USER_AGENT.starts_with("map-rs/"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_waypoint_rejected_before_request() {
+        let request = RouteRequest {
+            waypoints: vec![
+                Waypoint { lat: 51.5, lng: -0.1, name: None, kind: WaypointKind::Stop },
+                Waypoint { lat: 95.0, lng: -0.1, name: None, kind: WaypointKind::Stop },
+            ],
+            ..RouteRequest::default()
+        };
+        
+        // An unroutable base URL proves no request is attempted
+        let service = RoutingService::with_endpoints("http://invalid.invalid".to_string(), DEFAULT_GEOCODER_BASE.to_string())
+            .with_geocode_interval(Duration::ZERO);
+        match service.calculate_routes(&request, GeometryFormat::GeoJson, false, Language::En, false).await {
+            Err(RoutingError::InvalidWaypoint(1, _)) => {}
+            other => panic!("expected InvalidWaypoint, got {:?}", other.map(|routes| routes.len())),
+        }
+        
+        // Matrix points are numbered within the sources or the destinations
+        let sources: Vec<Waypoint> = (0..3).map(|i| Waypoint { lat: 51.5 + i as f64 * 0.01, lng: -0.1, name: None, kind: WaypointKind::Stop }).collect();
+        let destinations = vec![Waypoint { lat: 51.5, lng: 200.0, name: None, kind: WaypointKind::Stop }];
+        let error = service.distance_matrix(&sources, &destinations).await.unwrap_err();
+        assert_eq!(error.to_string(), "Destination 1 is invalid: longitude 200 is outside [-180, 180]");
+        let error = service.distance_matrix(&destinations, &sources).await.unwrap_err();
+        assert_eq!(error.to_string(), "Source 1 is invalid: longitude 200 is outside [-180, 180]");
+    }
+
+    #[test]
+    fn test_insertion_index() {
+        let stop = |lng: f64| Waypoint { lat: 0.0, lng, name: None, kind: WaypointKind::Stop };
+        let waypoints = vec![stop(0.0), stop(1.5), stop(3.0)];
+        let route: Vec<(f64, f64)> = (0..=6).map(|i| (0.0, i as f64 * 0.5)).collect();
+        assert_eq!(insertion_index(&route, &waypoints, 0.01, 1.0), 1);
+        assert_eq!(insertion_index(&route, &waypoints, -0.01, 2.2), 2);
+        
+        // Out to 2 and back to 1: a click on the way back belongs to the second leg
+        let waypoints = vec![stop(0.0), stop(2.0), stop(1.0)];
+        let route = vec![(0.0, 0.0), (0.0, 1.0), (0.0, 2.0), (0.001, 1.5), (0.001, 1.0)];
+        assert_eq!(insertion_index(&route, &waypoints, 0.001, 1.5), 2);
+        assert_eq!(insertion_index(&route, &waypoints, 0.0, 0.9), 1);
+        
+        // Without geometry the cheapest detour wins
+        assert_eq!(insertion_index(&[], &[stop(0.0), stop(1.0), stop(3.0)], 0.1, 2.0), 2);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_waypoint_rejected() {
+        let waypoints = vec![
+            Waypoint { lat: 51.5, lng: -0.1, name: None, kind: WaypointKind::Stop },
+            Waypoint { lat: 51.6, lng: -0.1, name: None, kind: WaypointKind::Stop },
+            Waypoint { lat: 51.60005, lng: -0.1, name: None, kind: WaypointKind::Stop }, // about 5.6 m north of the previous stop
+        ];
+        let (index, distance) = find_duplicate_waypoint(&waypoints, DEFAULT_DUPLICATE_THRESHOLD_M).unwrap();
+        assert_eq!(index, 2);
+        assert!((distance - 5.6).abs() < 0.1);
+        assert_eq!(find_duplicate_waypoint(&waypoints, 5.0), None);
+        assert_eq!(find_duplicate_waypoint(&waypoints[..2], DEFAULT_DUPLICATE_THRESHOLD_M), None);
+        
+        let request = RouteRequest { waypoints, ..RouteRequest::default() };
+        let service = RoutingService::with_endpoints("http://invalid.invalid".to_string(), DEFAULT_GEOCODER_BASE.to_string())
+            .with_geocode_interval(Duration::ZERO);
+        match service.calculate_routes(&request, GeometryFormat::GeoJson, false, Language::En, false).await {
+            Err(e @ RoutingError::DuplicateWaypoint(2, _)) => assert_eq!(e.to_string(), "Waypoints 2 and 3 are only 6 m apart; remove one of them"),
+            other => panic!("expected DuplicateWaypoint, got {:?}", other.map(|routes| routes.len())),
+        }
+    }
+
+    #[test]
+    fn test_trip_reorder() {
+        let waypoints: Vec<Waypoint> = (0..4)
+            .map(|i| Waypoint { lat: 51.0 + i as f64, lng: 0.0, name: Some(format!("Stop {}", i)), kind: WaypointKind::Stop })
+            .collect();
+        
+        let trip: OSRMTripResponse = serde_json::from_str(r#"{
+            "waypoints": [{"waypoint_index": 0}, {"waypoint_index": 3}, {"waypoint_index": 1}, {"waypoint_index": 2}]
+        }"#).unwrap();
+        let names: Vec<String> = trip.reorder(&waypoints).unwrap().into_iter().filter_map(|w| w.name).collect();
+        assert_eq!(names, vec!["Stop 0", "Stop 2", "Stop 3", "Stop 1"]);
+        
+        // Duplicate or out-of-range positions are rejected
+        let trip: OSRMTripResponse = serde_json::from_str(r#"{
+            "waypoints": [{"waypoint_index": 0}, {"waypoint_index": 0}, {"waypoint_index": 1}, {"waypoint_index": 9}]
+        }"#).unwrap();
+        assert!(trip.reorder(&waypoints).is_err());
+    }
+
+    #[test]
+    fn test_table_durations() {
+        let table: OSRMTableResponse = serde_json::from_str(r#"{
+            "code": "Ok",
+            "durations": [[0, 312.5, null], [298.1, 0, 1021.4]],
+            "sources": [],
+            "destinations": []
+        }"#).unwrap();
+        
+        let matrix = table.duration_matrix();
+        assert_eq!(matrix, vec![vec![0.0, 312.5, f64::INFINITY], vec![298.1, 0.0, 1021.4]]);
+    }
+
+    #[test]
+    fn test_routing_error_is_transient() {
+        assert!(RoutingError::Network("connection refused".to_string()).is_transient());
+        assert!(RoutingError::Timeout.is_transient());
+        assert!(RoutingError::Http(reqwest::StatusCode::SERVICE_UNAVAILABLE).is_transient());
+        assert!(RoutingError::Http(reqwest::StatusCode::TOO_MANY_REQUESTS).is_transient());
+        assert!(!RoutingError::Http(reqwest::StatusCode::BAD_REQUEST).is_transient());
+        assert!(!RoutingError::NoRoute.is_transient());
+        assert_eq!(RoutingError::TooFewWaypoints.to_string(), "At least 2 waypoints are required");
+    }
+
+    #[test]
+    fn test_format_distance() {
+        assert_eq!(format_distance(994.9, false), "990 m");
+        assert_eq!(format_distance(1000.0, false), "1.0 km");
+        assert_eq!(format_distance(12345.0, false), "12.3 km");
+        assert_eq!(format_distance(1609.344, true), "1.0 mi");
+        assert_eq!(format_distance(100.0, true), "350 ft");
+        assert_eq!(format_distance(30.0, true), "100 ft");
+        assert_eq!(format_distance(0.05 * 1609.344, true), "250 ft");
+        assert_eq!(format_distance(0.1 * 1609.344, true), "0.1 mi");
+        assert_eq!(format_distance_with_short_unit(30.0, true, units::ShortImperialUnit::Yards), "30 yd");
+        assert_eq!(format_distance_with_short_unit(30.0, false, units::ShortImperialUnit::Yards), "30 m");
+    }
+
+    #[test]
+    fn test_distance_rounding_buckets() {
+        let nav = |meters, use_miles| format_distance_rounded(meters, use_miles, units::ShortImperialUnit::Feet, &DistanceRounding::NAVIGATION);
+        
+        // Nearest 10 m under 1 km; rounding up to 1000 m switches to kilometers
+        assert_eq!(nav(0.0, false), "0 m");
+        assert_eq!(nav(14.9, false), "10 m");
+        assert_eq!(nav(15.0, false), "20 m");
+        assert_eq!(nav(994.9, false), "990 m");
+        assert_eq!(nav(995.0, false), "1.0 km");
+        // Nearest 0.1 km above
+        assert_eq!(nav(1049.9, false), "1.0 km");
+        assert_eq!(nav(1050.0, false), "1.1 km");
+        
+        // Nearest 50 ft under 0.1 mi (528 ft); 525 ft and up round to 550 ft, which is a tenth of a mile
+        assert_eq!(nav(24.9 * 0.3048, true), "0 ft");
+        assert_eq!(nav(25.0 * 0.3048, true), "50 ft");
+        assert_eq!(nav(524.9 * 0.3048, true), "500 ft");
+        assert_eq!(nav(525.0 * 0.3048, true), "0.1 mi");
+        // Nearest 0.1 mi above
+        assert_eq!(nav(0.149 * 1609.344, true), "0.1 mi");
+        assert_eq!(nav(0.151 * 1609.344, true), "0.2 mi");
+        
+        // Yards round to 10 yd under 0.1 mi (176 yd)
+        let yards = |meters| format_distance_rounded(meters, true, units::ShortImperialUnit::Yards, &DistanceRounding::NAVIGATION);
+        assert_eq!(yards(164.9 * 0.9144), "160 yd");
+        assert_eq!(yards(175.0 * 0.9144), "0.1 mi");
+        
+        // Precise keeps whole meters and feet; finer steps get more decimals
+        let precise = |meters, use_miles| format_distance_rounded(meters, use_miles, units::ShortImperialUnit::Feet, &DistanceRounding::PRECISE);
+        assert_eq!(precise(999.4, false), "999 m");
+        assert_eq!(precise(100.0, true), "328 ft");
+        let quarters = DistanceRounding { miles: 0.25, ..DistanceRounding::NAVIGATION };
+        assert_eq!(format_distance_rounded(0.7 * 1609.344, true, units::ShortImperialUnit::Feet, &quarters), "0.75 mi");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(59.0), "0 min");
+        assert_eq!(format_duration(45.0 * 60.0), "45 min");
+        assert_eq!(format_duration(3600.0), "1 hr 0 min");
+        assert_eq!(format_duration(2.0 * 3600.0 + 5.0 * 60.0 + 30.0), "2 hr 5 min");
+    }
+
+    #[test]
+    fn test_format_height() {
+        assert_eq!(format_height(311.2, false), "311 m");
+        assert_eq!(format_height(311.2, true), "1021 ft");
+        assert_eq!(format_height(0.0, true), "0 ft");
+    }
+
+    #[test]
+    fn test_ordinal() {
+        let cases = [(1, "1st"), (2, "2nd"), (3, "3rd"), (4, "4th"), (11, "11th"), (12, "12th"), (13, "13th"),
+            (21, "21st"), (22, "22nd"), (23, "23rd"), (111, "111th"), (112, "112th"), (113, "113th"), (101, "101st")];
+        for (n, expected) in cases {
+            assert_eq!(ordinal(n), expected);
+        }
+    }
+
+    #[test]
+    fn test_roundabout_exit_instruction() {
+        let step: OSRMStep = serde_json::from_value(serde_json::json!({
+            "distance": 1000.0,
+            "duration": 60.0,
+            "name": "High Street",
+            "maneuver": { "location": [-0.1278, 51.5074], "type": "roundabout", "exit": 2 }
+        })).unwrap();
+        
+        let text = OsrmBackend::new(DEFAULT_OSRM_BASE.to_string()).generate_instruction_text(&step, false, Language::En);
+        assert_eq!(text, "Take the 2nd exit at the roundabout on High Street for 1.0 km");
+    }
+
+    #[test]
+    fn test_elevation_stats() {
+        // Up 50, down 30, up 20
+        let profile = [100.0, 120.0, 150.0, 135.0, 120.0, 130.0, 140.0];
+        assert_eq!(elevation_stats(&profile), (70.0, 30.0));
+        assert_eq!(elevation_stats(&[]), (0.0, 0.0));
+        assert_eq!(elevation_stats(&[42.0]), (0.0, 0.0));
+
+        // Half-meter wobble on flat ground is ignored, a steady climb in small steps is not
+        let wobble = [10.0, 10.5, 10.0, 10.4, 9.6, 10.0];
+        assert_eq!(elevation_stats(&wobble), (0.0, 0.0));
+        let (ascent, descent) = elevation_stats_with_threshold(&wobble, 0.0);
+        assert!((ascent - 1.3).abs() < 1e-9 && (descent - 1.3).abs() < 1e-9);
+        let creep = [0.0, 0.5, 1.0, 1.5, 2.0];
+        assert_eq!(elevation_stats(&creep), (2.0, 0.0));
+    }
+
+    #[test]
+    fn test_sample_points() {
+        let points: Vec<(f64, f64)> = (0..10).map(|i| (i as f64, 0.0)).collect();
+        
+        assert_eq!(sample_points(&points, 20), points);
+        let sampled: Vec<f64> = sample_points(&points, 4).iter().map(|p| p.0).collect();
+        assert_eq!(sampled, vec![0.0, 3.0, 6.0, 9.0]);
+        assert_eq!(sample_points(&points, 1), vec![(0.0, 0.0)]);
+        assert!(sample_points(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn test_via_points_in_requests() {
+        let point = |lng: f64, kind| Waypoint { lat: 51.5, lng, name: None, kind };
+        let mut request = RouteRequest {
+            waypoints: vec![
+                point(-0.10, WaypointKind::Stop),
+                point(-0.11, WaypointKind::Via),
+                point(-0.12, WaypointKind::Stop),
+                point(-0.13, WaypointKind::Via),
+                point(-0.14, WaypointKind::Via), // the route's end is a stop whatever its kind
+            ],
+            ..RouteRequest::default()
+        };
+        let url = OsrmBackend::new("https://osrm.example/".to_string()).route_url(&request, GeometryFormat::Polyline);
+        assert!(url.starts_with("https://osrm.example/route/v1/driving/-0.1,51.5;-0.11,51.5;-0.12,51.5;-0.13,51.5;-0.14,51.5?"));
+        assert!(url.ends_with("&waypoints=0;2;4"));
+        
+        let types: Vec<serde_json::Value> = ValhallaBackend::request_body(&request, Language::En)["locations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|location| location["type"].clone())
+            .collect();
+        assert_eq!(types, ["break", "through", "break", "through", "break"]);
+        
+        // All stops keep the URL as it was before via points existed
+        request.waypoints.retain(|wp| wp.kind == WaypointKind::Stop);
+        let url = OsrmBackend::new("https://osrm.example".to_string()).route_url(&request, GeometryFormat::GeoJson);
+        assert!(!url.contains("waypoints="));
+    }
+
+    #[test]
+    fn test_valhalla_trip_to_route() {
+        let response: ValhallaResponse = serde_json::from_value(serde_json::json!({
+            "trip": {
+                "legs": [{
+                    "shape": "_ajccB_{zpX_pR_pR",
+                    "maneuvers": [
+                        { "type": 1, "instruction": "Drive north.", "length": 1.2, "time": 90.0, "begin_shape_index": 0 },
+                        { "type": 15, "instruction": "Turn left onto Hauptstraße.", "length": 0.0, "time": 0.0, "begin_shape_index": 1 }
+                    ]
+                }],
+                "summary": { "length": 1.2, "time": 90.0 }
+            }
+        })).unwrap();
+        assert!(response.alternates.is_empty());
+
+        let route = response.trip.to_route().unwrap();
+        assert!((route.distance - 1200.0).abs() < 1e-9);
+        assert_eq!(route.coordinates(), vec![(52.5, 13.4), (52.51, 13.41)]);
+        assert_eq!(route.instructions[1].text, "Turn left onto Hauptstraße.");
+        assert_eq!(route.instructions[1].maneuver_icon, "turn-left");
+        assert_eq!((route.instructions[1].location.latitude, route.instructions[1].location.longitude), (52.51, 13.41));
+        assert_eq!(route.legs, vec![LegSummary { distance: 1200.0, duration: 90.0, first_instruction: 0 }]);
+
+        let request = RouteRequest {
+            profile: "cycling".to_string(),
+            exclude: vec!["ferry".to_string()],
+            depart_at: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            ..RouteRequest::default()
+        };
+        let body = ValhallaBackend::request_body(&request, Language::De);
+        assert_eq!(body["costing"], "bicycle");
+        assert_eq!(body["costing_options"]["bicycle"]["use_ferry"], 0.0);
+        assert_eq!(body["directions_options"]["language"], "de-DE");
+        assert_eq!(body["date_time"]["value"], "2023-11-14T22:13");
+    }
+
+    #[tokio::test]
+    async fn test_transit_routes_with_otp() {
+        let body = serde_json::json!({
+            "plan": {
+                "itineraries": [{
+                    "duration": 1500.0,
+                    "legs": [
+                        { "mode": "WALK", "distance": 400.0, "duration": 300.0, "from": { "name": "Origin", "lat": 52.5, "lon": 13.4 }, "to": { "name": "Hauptbahnhof", "lat": 52.52, "lon": 13.37 } },
+                        { "mode": "BUS", "distance": 5000.0, "duration": 900.0, "routeShortName": "M41", "from": { "name": "Hauptbahnhof", "lat": 52.52, "lon": 13.37 }, "to": { "lat": 52.48, "lon": 13.42 },
+                          "legGeometry": { "points": "_ajccB_{zpX" } }
+                    ]
+                }]
+            }
+        });
+        let request = RouteRequest {
+            waypoints: vec![
+                Waypoint { lat: 52.5, lng: 13.4, name: None, kind: WaypointKind::Stop },
+                Waypoint { lat: 52.48, lng: 13.42, name: None, kind: WaypointKind::Stop },
+            ],
+            profile: TRANSIT_PROFILE.to_string(),
+            ..RouteRequest::default()
+        };
+
+        // Without a transit router the profile is refused
+        let service = RoutingService::new();
+        assert!(!service.supports_transit());
+        assert!(matches!(service.calculate_routes(&request, GeometryFormat::GeoJson, false, Language::En, false).await, Err(RoutingError::InvalidRequest(_))));
+
+        let service = RoutingService::new()
+            .with_transit_backend(Box::new(OtpBackend::new("https://otp.example.org".to_string())))
+            .with_http_client(Box::new(CannedHttp {
+                status: reqwest::StatusCode::OK,
+                body: body.to_string(),
+                headers: Default::default(),
+            }));
+        assert!(service.supports_transit());
+        let route = service.calculate_route(&request, GeometryFormat::GeoJson, false, Language::En, false).await.unwrap();
+        let texts: Vec<&str> = route.instructions.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, vec!["Walk 400 m to Hauptbahnhof", "Take the bus M41 to 52.48000, 13.42000", "Arrive at your destination"]);
+        let modes: Vec<Option<&str>> = route.instructions.iter().map(|i| i.mode.as_deref()).collect();
+        assert_eq!(modes, vec![Some("walk"), Some("bus"), None]);
+        assert_eq!((route.distance, route.duration), (5400.0, 1500.0));
+        assert_eq!(route.coordinates().len(), 3);
+        
+        // The arrival includes the 300 s spent waiting for the bus
+        let sums: Vec<(f64, f64)> = route.instructions.iter().map(|i| (i.cumulative_distance, i.cumulative_duration)).collect();
+        assert_eq!(sums, vec![(0.0, 0.0), (400.0, 300.0), (5400.0, 1500.0)]);
+    }
+
+    #[test]
+    fn test_parse_legs() {
+        // The simple fixture's single leg, driven twice as a two-stop route
+        let mut json: serde_json::Value = serde_json::from_str(include_str!("../tests/fixtures/osrm_simple.json")).unwrap();
+        let leg = json["routes"][0]["legs"][0].clone();
+        json["routes"][0]["legs"] = serde_json::json!([leg.clone(), leg]);
+        let response: OSRMResponse = serde_json::from_value(json).unwrap();
+        
+        let backend = OsrmBackend::new(DEFAULT_OSRM_BASE.to_string());
+        let legs = backend.parse_legs(&response.routes[0].legs);
+        assert_eq!(legs, vec![
+            LegSummary { distance: 183.4, duration: 31.3, first_instruction: 0 },
+            LegSummary { distance: 183.4, duration: 31.3, first_instruction: 3 },
+        ]);
+        let instructions = backend.parse_instructions(&response.routes[0].legs, false, Language::En, false);
+        assert_eq!(instructions[3].text, "Head east on Unter den Linden for 60 m");
+        
+        // Running totals continue into the second leg and end at the route totals
+        let route = &response.routes[0];
+        let first_leg = &route.legs[0];
+        assert_eq!(instructions[0].cumulative_distance, 0.0);
+        assert!((instructions[3].cumulative_distance - first_leg.distance).abs() < 1e-6);
+        assert!((instructions[3].cumulative_duration - first_leg.duration).abs() < 1e-6);
+        let last = instructions.last().unwrap();
+        assert!((last.cumulative_distance - 2.0 * first_leg.distance).abs() < 1e-6);
+        assert!((last.cumulative_duration - 2.0 * first_leg.duration).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_osrm_error_response() {
+        // Error bodies carry a code and message but no routes
+        let response: OSRMResponse = serde_json::from_str(r#"{"code":"NoSegment","message":"Could not find a matching segment for coordinate 0"}"#).unwrap();
+        assert!(response.routes.is_empty());
+        assert_eq!(response.message.as_deref(), Some("Could not find a matching segment for coordinate 0"));
+        assert!(matches!(response.code_error(), Some(RoutingError::NoSegment)));
+        assert_eq!(RoutingError::NoSegment.to_string(), "No road near one of your points");
+
+        let response: OSRMResponse = serde_json::from_str(r#"{"code":"NoRoute","message":"Impossible route between points"}"#).unwrap();
+        assert!(matches!(response.code_error(), Some(RoutingError::NoRoute)));
+
+        // Unknown codes fall back to the HTTP status
+        let response: OSRMResponse = serde_json::from_str(r#"{"code":"TooBig"}"#).unwrap();
+        assert!(response.code_error().is_none());
+    }
+
+    // Instruction text for the first route of an OSRM response in tests/fixtures
+    fn fixture_instructions(json: &str) -> Vec<String> {
+        let response: OSRMResponse = serde_json::from_str(json).unwrap();
+        OsrmBackend::new(DEFAULT_OSRM_BASE.to_string())
+            .parse_instructions(&response.routes[0].legs, false, Language::En, false)
+            .into_iter()
+            .map(|instruction| instruction.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_raw_instructions() {
+        let legs: Vec<OSRMLeg> = serde_json::from_value(serde_json::json!([{
+            "distance": 100.0,
+            "duration": 20.0,
+            "steps": [
+                { "distance": 100.0, "duration": 20.0, "name": "Main Street", "maneuver": { "location": [13.4, 52.5], "type": "turn", "modifier": "left", "instruction": "Turn left onto Main Street" } },
+                { "distance": 0.0, "duration": 0.0, "name": "", "maneuver": { "location": [13.41, 52.51], "type": "arrive", "instruction": " " } }
+            ]
+        }])).unwrap();
+        let backend = OsrmBackend::new(DEFAULT_OSRM_BASE.to_string());
+        
+        let texts = |raw| backend.parse_instructions(&legs, false, Language::En, raw).into_iter().map(|i| i.text).collect::<Vec<_>>();
+        assert_eq!(texts(false), vec!["Turn left on Main Street for 100 m", "Arrive at your destination"]);
+        // Blank raw text falls back to the reconstructed instruction
+        assert_eq!(texts(true), vec!["Turn left onto Main Street", "Arrive at your destination"]);
+    }
+
+    #[test]
+    fn test_osrm_fixture_instructions() {
+        assert_eq!(fixture_instructions(include_str!("../tests/fixtures/osrm_simple.json")), vec![
+            "Head east on Unter den Linden for 60 m",
+            "Turn right on Friedrichstraße for 120 m",
+            "Arrive at your destination",
+        ]);
+        assert_eq!(fixture_instructions(include_str!("../tests/fixtures/osrm_roundabout.json")), vec![
+            "Head northeast on Drove Road for 170 m",
+            "Take the 3rd exit at the roundabout on Queens Drive (A4312) for 90 m",
+            "Continue on Queens Drive (A4312) for 410 m",
+            "Arrive at your destination",
+        ]);
+        assert_eq!(fixture_instructions(include_str!("../tests/fixtures/osrm_ramp_merge.json")), vec![
+            "Head northeast on Detmolder Straße for 130 m",
+            "Take the ramp right for 150 m",
+            "Merge left on Stadtring (A 100) for 610 m",
+            "Take the ramp right for 290 m",
+            "Turn left on Hauptstraße for 80 m",
+            "Arrive at your destination",
+        ]);
+    }
+
+    #[test]
+    fn test_maneuver_to_icon() {
+        let cases = [
+            ("depart", None, "depart"),
+            ("arrive", Some("left"), "arrive"),
+            ("roundabout", Some("right"), "roundabout"),
+            ("merge", Some("slight left"), "merge"),
+            ("fork", Some("slight right"), "fork-right"),
+            ("fork", None, "fork-left"),
+            ("turn", Some("left"), "turn-left"),
+            ("turn", Some("sharp right"), "turn-sharp-right"),
+            ("turn", Some("uturn"), "uturn"),
+            ("ramp", Some("slight left"), "turn-slight-left"),
+            ("continue", Some("straight"), "straight"),
+            ("continue", None, "straight"),
+            ("new name", Some("right"), "turn-right"),
+        ];
+        for (t, modifier, expected) in cases {
+            assert_eq!(maneuver_to_icon(t, modifier), expected, "{} {:?}", t, modifier);
+        }
+    }
+
+    #[test]
+    fn test_localized_instructions() {
+        let step: OSRMStep = serde_json::from_value(serde_json::json!({
+            "distance": 1000.0,
+            "duration": 60.0,
+            "name": "Hauptstraße",
+            "maneuver": { "location": [13.4, 52.5], "type": "turn", "modifier": "left" }
+        })).unwrap();
+        let backend = OsrmBackend::new(DEFAULT_OSRM_BASE.to_string());
+        
+        assert_eq!(backend.generate_instruction_text(&step, false, Language::En), "Turn left on Hauptstraße for 1.0 km");
+        assert_eq!(backend.generate_instruction_text(&step, false, Language::Es), "Gira a la izquierda por Hauptstraße durante 1.0 km");
+        assert_eq!(backend.generate_instruction_text(&step, false, Language::De), "Biege links ab auf Hauptstraße für 1.0 km");
+        
+        // Every translated key exists in English, so English can stand in for anything missing
+        for language in Language::ALL {
+            for (key, _) in language.phrases() {
+                assert!(EN_PHRASES.iter().any(|(k, _)| k == key), "{} has no English phrase", key);
+            }
+        }
+        assert_eq!(Language::De.phrase("no such key"), "no such key");
+        assert_eq!(Language::from_code("es"), Some(Language::Es));
+    }
+
+    #[test]
+    fn test_step_speed_limits() {
+        let leg: OSRMLeg = serde_json::from_value(serde_json::json!({
+            "distance": 300.0,
+            "duration": 30.0,
+            "steps": [
+                { "distance": 200.0, "duration": 20.0, "maneuver": { "location": [0.0, 0.0] } },
+                { "distance": 100.0, "duration": 10.0, "maneuver": { "location": [0.0, 0.0] } },
+                { "distance": 0.0, "duration": 0.0, "maneuver": { "location": [0.0, 0.0] } }
+            ],
+            "annotation": {
+                "distance": [50.0, 150.0, 100.0],
+                "maxspeed": [{ "speed": 30, "unit": "mph" }, { "speed": 50, "unit": "km/h" }, { "unknown": true }]
+            }
+        })).unwrap();
+        
+        let limits = leg.step_speed_limits();
+        assert_eq!(limits.len(), 3);
+        assert!((limits[0].unwrap() - 50.0 / 3.6).abs() < 1e-9);
+        assert_eq!(limits[1], None);
+        assert_eq!(limits[2], None);
+        
+        assert_eq!(format_speed_limit(limits[0].unwrap(), false), "50 km/h");
+        assert_eq!(format_speed_limit(13.4112, true), "30 mph");
+    }
+
+    // Answers every search with London and counts how often the "network" is hit
+    struct CountingHttp {
+        calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl HttpClient for CountingHttp {
+        fn get_with_headers<'a>(&'a self, _url: &'a str, _headers: &'a [(&'a str, &'a str)], _max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let body = r#"[{"lat":"51.5074","lon":"-0.1278","display_name":"London","type":"city","class":"place","importance":0.9}]"#;
+            async move { Ok(HttpResponse { status: reqwest::StatusCode::OK, body: body.to_string() }) }.boxed_local()
+        }
+
+        fn post_json<'a>(&'a self, url: &'a str, _body: &'a serde_json::Value, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.get(url, max_retries)
+        }
+    }
+
+    fn counting_service(capacity: usize, ttl: Duration) -> (RoutingService, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let service = RoutingService::with_endpoints(DEFAULT_OSRM_BASE.to_string(), DEFAULT_GEOCODER_BASE.to_string())
+            .with_geocode_interval(Duration::ZERO)
+            .with_http_client(Box::new(CountingHttp { calls: calls.clone() }))
+            .with_geocode_cache(capacity, ttl);
+        (service, calls)
+    }
+
+    #[tokio::test]
+    async fn test_geocode_cache_hit() {
+        let (service, calls) = counting_service(DEFAULT_GEOCODE_CACHE_CAPACITY, DEFAULT_GEOCODE_CACHE_TTL);
+        
+        let first = service.geocode("London", None, &[]).await.unwrap();
+        let second = service.geocode("  london ", None, &[]).await.unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(first[0].display_name, second[0].display_name);
+        
+        service.geocode("Paris", None, &[]).await.unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_geocode_cache_expiry_and_eviction() {
+        let (service, calls) = counting_service(DEFAULT_GEOCODE_CACHE_CAPACITY, Duration::ZERO);
+        service.geocode("London", None, &[]).await.unwrap();
+        service.geocode("London", None, &[]).await.unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        
+        // With room for one entry, "Paris" evicts "London"
+        let (service, calls) = counting_service(1, DEFAULT_GEOCODE_CACHE_TTL);
+        service.geocode("London", None, &[]).await.unwrap();
+        service.geocode("Paris", None, &[]).await.unwrap();
+        service.geocode("London", None, &[]).await.unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_geocode_rate_limit() {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let interval = Duration::from_millis(200);
+        let service = RoutingService::with_endpoints(DEFAULT_OSRM_BASE.to_string(), DEFAULT_GEOCODER_BASE.to_string())
+            .with_geocode_interval(interval)
+            .with_http_client(Box::new(CountingHttp { calls: calls.clone() }));
+        
+        let start = Instant::now();
+        service.geocode("London", None, &[]).await.unwrap();
+        assert!(start.elapsed() < interval);
+        
+        service.geocode("Paris", None, &[]).await.unwrap();
+        assert!(start.elapsed() >= interval);
+        
+        // Cached queries never touch the network, so they are not throttled
+        let cached = Instant::now();
+        service.geocode("London", None, &[]).await.unwrap();
+        assert!(cached.elapsed() < interval);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    // Finds nothing inside a viewbox and records every URL it is asked for
+    struct UnboundedOnlyHttp {
+        urls: std::sync::Arc<Mutex<Vec<String>>>,
+    }
+
+    impl HttpClient for UnboundedOnlyHttp {
+        fn get_with_headers<'a>(&'a self, url: &'a str, _headers: &'a [(&'a str, &'a str)], _max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.urls.lock().unwrap().push(url.to_string());
+            let body = if url.contains("bounded=1") {
+                "[]"
+            } else {
+                r#"[{"lat":"40.7128","lon":"-74.0060","display_name":"Main Street, New York"}]"#
+            };
+            async move { Ok(HttpResponse { status: reqwest::StatusCode::OK, body: body.to_string() }) }.boxed_local()
+        }
+
+        fn post_json<'a>(&'a self, url: &'a str, _body: &'a serde_json::Value, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.get(url, max_retries)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geocode_viewbox_falls_back_to_unbounded() {
+        let urls = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let service = RoutingService::with_endpoints(DEFAULT_OSRM_BASE.to_string(), DEFAULT_GEOCODER_BASE.to_string())
+            .with_geocode_interval(Duration::ZERO)
+            .with_http_client(Box::new(UnboundedOnlyHttp { urls: urls.clone() }));
+        let viewbox = (Location::new(51.4, -0.2), Location::new(51.6, 0.1));
+        
+        let results = service.geocode("Main Street", Some(viewbox), &[]).await.unwrap();
+        assert_eq!(results[0].display_name, "Main Street, New York");
+        
+        let urls = urls.lock().unwrap();
+        assert_eq!(urls.len(), 2);
+        assert!(urls[0].ends_with("&viewbox=-0.2,51.6,0.1,51.4&bounded=1"));
+        assert!(!urls[1].contains("viewbox"));
+    }
+
+    #[tokio::test]
+    async fn test_geocode_country_codes() {
+        let urls = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let service = RoutingService::with_endpoints(DEFAULT_OSRM_BASE.to_string(), DEFAULT_GEOCODER_BASE.to_string())
+            .with_geocode_interval(Duration::ZERO)
+            .with_http_client(Box::new(UnboundedOnlyHttp { urls: urls.clone() }));
+        
+        service.geocode("Main Street", None, &["GB", " ie"]).await.unwrap();
+        assert!(urls.lock().unwrap()[0].ends_with("/search?format=json&q=Main%20Street&countrycodes=gb,ie"));
+        
+        assert!(matches!(service.geocode("Main Street", None, &["gbr"]).await, Err(RoutingError::InvalidRequest(_))));
+        assert!(matches!(service.geocode("Main Street", None, &["g1"]).await, Err(RoutingError::InvalidRequest(_))));
+        assert_eq!(urls.lock().unwrap().len(), 1);
+    }
+
+    // Answers every search with the Springfield fixture, except ones for Atlantis
+    struct AtlantisMissingHttp;
+
+    impl HttpClient for AtlantisMissingHttp {
+        fn get_with_headers<'a>(&'a self, url: &'a str, _headers: &'a [(&'a str, &'a str)], _max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            let response = if url.contains("Atlantis") {
+                HttpResponse { status: reqwest::StatusCode::NOT_FOUND, body: String::new() }
+            } else {
+                HttpResponse { status: reqwest::StatusCode::OK, body: include_str!("../tests/fixtures/nominatim_springfield.json").to_string() }
+            };
+            async move { Ok(response) }.boxed_local()
+        }
+
+        fn post_json<'a>(&'a self, url: &'a str, _body: &'a serde_json::Value, max_retries: u32) -> LocalBoxFuture<'a, Result<HttpResponse, RoutingError>> {
+            self.get(url, max_retries)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geocode_batch() {
+        let interval = Duration::from_millis(100);
+        let service = RoutingService::with_endpoints(DEFAULT_OSRM_BASE.to_string(), DEFAULT_GEOCODER_BASE.to_string())
+            .with_geocode_interval(interval)
+            .with_http_client(Box::new(AtlantisMissingHttp));
+        let queries: Vec<String> = ["Springfield, IL", "Atlantis", "Springfield, MA"].iter().map(|q| q.to_string()).collect();
+
+        // One failure doesn't abort the batch, and requests stay spaced by the rate limit
+        let start = Instant::now();
+        let results = service.geocode_batch(&queries).await;
+        assert!(start.elapsed() >= interval * 2);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().len(), 4);
+        assert!(matches!(results[1], Err(RoutingError::Http(reqwest::StatusCode::NOT_FOUND))));
+        assert!(results[2].is_ok());
+
+        // Cancelled after the first query, the rest are never looked up
+        let checks = std::cell::Cell::new(0);
+        let results = service
+            .geocode_batch_until(&queries, || {
+                checks.set(checks.get() + 1);
+                checks.get() > 1
+            })
+            .await;
+        assert_eq!(results.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_geocode_ranks_by_importance() {
+        let service = |min_importance: f64| {
+            let mut service = RoutingService::with_endpoints(DEFAULT_OSRM_BASE.to_string(), DEFAULT_GEOCODER_BASE.to_string())
+                .with_geocode_interval(Duration::ZERO)
+                .with_http_client(Box::new(CannedHttp {
+                    status: reqwest::StatusCode::OK,
+                    body: include_str!("../tests/fixtures/nominatim_springfield.json").to_string(),
+                    headers: Default::default(),
+                }));
+            service.min_importance = min_importance;
+            service
+        };
+
+        let results = service(0.0).geocode("Springfield", None, &[]).await.unwrap();
+        let names: Vec<&str> = results.iter().map(|r| r.display_name.as_str()).collect();
+        assert_eq!(names, vec![
+            "Springfield, Sangamon County, Illinois, United States",
+            "Springfield, Hampden County, Massachusetts, United States",
+            "Springfield Road, Chelmsford, Essex, England, United Kingdom",
+            "Springfield, Cheshire West and Chester, England, United Kingdom",
+        ]);
+        assert_eq!((results[0].class.as_deref(), results[0].kind.as_deref()), (Some("boundary"), Some("administrative")));
+        assert_eq!(results[3].kind.as_deref(), Some("hamlet"));
+        assert_eq!(results[3].importance, 0.0); // no importance in the response
+
+        let results = service(0.5).geocode("Springfield", None, &[]).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.importance >= 0.5));
+    }
+
+    #[test]
+    fn test_decode_polyline() {
+        // Example from Google's polyline algorithm documentation
+        let points = decode_polyline("_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+        assert_eq!(points, vec![(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)]);
+
+        assert_eq!(decode_polyline("??"), vec![(0.0, 0.0)]);
+        assert!(decode_polyline("").is_empty());
+
+        // A dangling latitude without its longitude is dropped
+        assert_eq!(decode_polyline("_p~iF~ps|U_ulL"), vec![(38.5, -120.2)]);
+    }
+
+    #[test]
+    fn test_polyline_geometry_to_geojson() {
+        let geometry: OSRMGeometry = serde_json::from_str(r#""_p~iF~ps|U_ulLnnqC""#).unwrap();
+        let route = RouteResponse {
+            geometry: serde_json::to_string(&geometry.to_geojson()).unwrap(),
+            ..sample_route()
+        };
+        assert_eq!(route.coordinates(), vec![(38.5, -120.2), (40.7, -120.95)]);
+    }
+}
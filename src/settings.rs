@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::routing::Language;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+// Search result pins kept on the map unless the user picks another number
+pub const DEFAULT_MAX_SEARCH_MARKERS: usize = 5;
+
+// User preferences persisted between launches. Missing fields fall back to defaults
+// so settings files written by older versions keep loading.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppSettings {
+    pub dark_mode: bool,
+    pub use_miles: bool,
+    // Id of the selected base map, see TILE_LAYERS in main_map.rs
+    pub tile_layer: String,
+    // Move clicked route points onto the nearest road
+    pub snap_to_road: bool,
+    // Language of turn-by-turn instructions
+    pub language: Language,
+    // Two-letter country codes searches are limited to; empty searches everywhere
+    pub search_countries: Vec<String>,
+    // Newest search result pins left on the map; older ones are removed
+    pub max_search_markers: usize,
+    // Group nearby search result pins into numbered clusters
+    pub cluster_search_markers: bool,
+    // Show the routing server's own instruction text instead of ours, where it sends one
+    pub raw_instructions: bool,
+}
+
+impl Default for AppSettings {
+    fn default() -> Self {
+        Self {
+            dark_mode: false,
+            use_miles: true,
+            tile_layer: "osm".to_string(),
+            snap_to_road: false,
+            language: Language::En,
+            search_countries: Vec::new(),
+            max_search_markers: DEFAULT_MAX_SEARCH_MARKERS,
+            cluster_search_markers: false,
+            raw_instructions: false,
+        }
+    }
+}
+
+impl AppSettings {
+    pub fn load() -> Self {
+        Self::load_from(&config_file(SETTINGS_FILE))
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        self.save_to(&config_file(SETTINGS_FILE))
+    }
+
+    // Reads settings from a file, using defaults if it is missing or corrupt
+    pub fn load_from(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                eprintln!("⚠️ Ignoring corrupt settings file {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    eprintln!("⚠️ Could not read settings from {}: {}", path.display(), e);
+                }
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json)
+    }
+}
+
+// Resolves a file inside the application's XDG config directory
+pub fn config_file(name: &str) -> PathBuf {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(std::env::temp_dir);
+
+    base.join("map-rs").join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("map-rs-test-{}-{}", std::process::id(), name))
+            .join(SETTINGS_FILE)
+    }
+
+    #[test]
+    fn test_settings_round_trip() {
+        let path = temp_path("settings-round-trip");
+        let settings = AppSettings { dark_mode: true, use_miles: false, tile_layer: "satellite".to_string(), snap_to_road: true, language: Language::De, search_countries: vec!["de".to_string()], max_search_markers: 2, cluster_search_markers: true, raw_instructions: true };
+        settings.save_to(&path).unwrap();
+
+        assert_eq!(AppSettings::load_from(&path), settings);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_settings_fall_back_to_defaults() {
+        let path = temp_path("settings-missing");
+        assert_eq!(AppSettings::load_from(&path), AppSettings::default());
+
+        let path = temp_path("settings-corrupt");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "{ dark_mode: yes").unwrap();
+        assert_eq!(AppSettings::load_from(&path), AppSettings::default());
+
+        // Partial files keep the stored values and default the rest
+        fs::write(&path, r#"{"dark_mode": true}"#).unwrap();
+        assert_eq!(AppSettings::load_from(&path), AppSettings { dark_mode: true, ..AppSettings::default() });
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,348 @@
+use std::fmt;
+use crate::coords;
+use crate::routing::{Waypoint, WaypointKind, SUPPORTED_PROFILES, TRANSIT_PROFILE};
+
+// Links look like map-rs://route?wp=51.5074,-0.1278;51.4994,-0.1245&profile=driving, with
+// via=1,3 listing the indexes of any via points (older versions ignore it and stop there)
+pub const ROUTE_URL_PREFIX: &str = "map-rs://route";
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    MissingWaypoints,
+    TooFewWaypoints,
+    InvalidWaypoint(usize, String), // index of the offending waypoint and why
+    InvalidVia(String), // a via entry that doesn't name a waypoint
+    UnknownProfile(String),
+    InvalidGeoJson(String),
+    InvalidGeoUri(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingWaypoints => write!(f, "Route link has no wp parameter"),
+            ParseError::TooFewWaypoints => write!(f, "Route link needs at least 2 waypoints"),
+            ParseError::InvalidWaypoint(index, reason) => write!(f, "Waypoint {} in route link is invalid: {}", index + 1, reason),
+            ParseError::InvalidVia(index) => write!(f, "Via point '{}' in route link is not one of its waypoints", index),
+            ParseError::UnknownProfile(profile) => write!(f, "Unknown travel mode '{}' in route link", profile),
+            ParseError::InvalidGeoJson(reason) => write!(f, "Route file is not valid GeoJSON: {}", reason),
+            ParseError::InvalidGeoUri(reason) => write!(f, "Invalid map view link: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Encodes the waypoints, their kinds and the travel mode as a link; names are not kept
+pub fn encode_route_url(waypoints: &[Waypoint], profile: &str) -> String {
+    let points: Vec<String> = waypoints
+        .iter()
+        .map(|wp| format!("{:.6},{:.6}", wp.lat, wp.lng))
+        .collect();
+    let vias: Vec<String> = waypoints
+        .iter()
+        .enumerate()
+        .filter(|(_, wp)| wp.kind == WaypointKind::Via)
+        .map(|(index, _)| index.to_string())
+        .collect();
+    let mut url = format!("{}?wp={}&profile={}", ROUTE_URL_PREFIX, points.join(";"), urlencoding::encode(profile));
+    if !vias.is_empty() {
+        url.push_str(&format!("&via={}", vias.join(",")));
+    }
+    url
+}
+
+// Accepts a full link or just its query string. The profile defaults to driving.
+pub fn decode_route_url(s: &str) -> Result<(Vec<Waypoint>, String), ParseError> {
+    let query = s.trim().rsplit_once('?').map_or(s.trim(), |(_, query)| query);
+
+    let mut points = None;
+    let mut profile = "driving".to_string();
+    let mut vias = String::new();
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let value = urlencoding::decode(value).map_or_else(|_| value.to_string(), |v| v.into_owned());
+        match key {
+            "wp" => points = Some(value),
+            "profile" => profile = value,
+            "via" => vias = value,
+            _ => {} // Ignore parameters added by newer versions
+        }
+    }
+
+    let points = points.ok_or(ParseError::MissingWaypoints)?;
+    let mut waypoints = points
+        .split(';')
+        .filter(|point| !point.is_empty())
+        .enumerate()
+        .map(|(index, point)| {
+            let invalid = |reason: String| ParseError::InvalidWaypoint(index, reason);
+            let (lat, lng) = point
+                .split_once(',')
+                .ok_or_else(|| invalid(format!("expected lat,lng but got '{}'", point)))?;
+            let coordinate = |value: &str| {
+                value
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| invalid(format!("'{}' is not a number", value)))
+            };
+            let waypoint = Waypoint {
+                lat: coordinate(lat)?,
+                lng: coordinate(lng)?,
+                name: Some(format!("Point {}", index + 1)),
+                kind: WaypointKind::Stop,
+            };
+            waypoint.validate().map_err(invalid)?;
+            Ok(waypoint)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if waypoints.len() < 2 {
+        return Err(ParseError::TooFewWaypoints);
+    }
+    for index in vias.split(',').filter(|index| !index.is_empty()) {
+        let waypoint = index
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| waypoints.get_mut(index))
+            .ok_or_else(|| ParseError::InvalidVia(index.to_string()))?;
+        waypoint.kind = WaypointKind::Via;
+    }
+    if !SUPPORTED_PROFILES.contains(&profile.as_str()) && profile != TRANSIT_PROFILE {
+        return Err(ParseError::UnknownProfile(profile));
+    }
+
+    Ok((waypoints, profile))
+}
+
+// Highest zoom a view link may ask for, matching the deepest tile layer
+pub const MAX_VIEW_ZOOM: u8 = 19;
+
+// A map position and zoom, shared as geo:51.507400,-0.127800?z=13: an RFC 5870 geo URI
+// with the zoom parameter map apps understand
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapView {
+    pub lat: f64,
+    pub lng: f64,
+    pub zoom: Option<u8>,
+}
+
+pub fn encode_view_url(view: &MapView) -> String {
+    let uri = coords::to_geo_uri(view.lat, view.lng);
+    match view.zoom {
+        Some(zoom) => format!("{}?z={}", uri, zoom),
+        None => uri,
+    }
+}
+
+// Accepts any geo URI; an altitude, ;u= and ;crs= parameters and query parameters other
+// than z are ignored
+pub fn decode_view_url(s: &str) -> Result<MapView, ParseError> {
+    let s = s.trim();
+    let invalid = |reason: String| ParseError::InvalidGeoUri(reason);
+    let rest = s
+        .get(..4)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("geo:"))
+        .map(|_| &s[4..])
+        .ok_or_else(|| invalid(format!("expected geo:LAT,LNG but got '{}'", s)))?;
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+    let mut parts = path.split(';').next().unwrap_or_default().split(',');
+    let mut coordinate = || {
+        let value = parts.next().unwrap_or_default().trim();
+        value
+            .parse::<f64>()
+            .map_err(|_| invalid(format!("'{}' is not a number", value)))
+    };
+    let (lat, lng) = (coordinate()?, coordinate()?);
+    Waypoint { lat, lng, name: None, kind: WaypointKind::Stop }.validate().map_err(invalid)?;
+
+    let mut zoom = None;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        if key == "z" {
+            let level = value
+                .parse::<u8>()
+                .ok()
+                .filter(|level| *level <= MAX_VIEW_ZOOM)
+                .ok_or_else(|| invalid(format!("zoom '{}' is not between 0 and {}", value, MAX_VIEW_ZOOM)))?;
+            zoom = Some(level);
+        }
+    }
+
+    Ok(MapView { lat, lng, zoom })
+}
+
+// Reads a route saved as GeoJSON: Point features are the stops, in file order, and the
+// first LineString (or MultiLineString) is the geometry. A file with only a line uses its
+// endpoints as stops; one with only points gets a straight line through them. Points
+// carrying a "text" property are turn instructions from route_to_geojson, not stops.
+pub fn load_route_geojson(json: &str) -> Result<(Vec<Waypoint>, String), ParseError> {
+    let geojson: geojson::GeoJson = json.parse().map_err(|e: geojson::Error| ParseError::InvalidGeoJson(e.to_string()))?;
+    let features = match geojson {
+        geojson::GeoJson::FeatureCollection(collection) => collection.features,
+        geojson::GeoJson::Feature(feature) => vec![feature],
+        geojson::GeoJson::Geometry(geometry) => vec![geojson::Feature::from(geometry)],
+    };
+
+    let mut stops: Vec<(Vec<f64>, Option<String>)> = Vec::new();
+    let mut line: Option<Vec<Vec<f64>>> = None;
+    for feature in &features {
+        let Some(geometry) = &feature.geometry else {
+            continue;
+        };
+        match &geometry.value {
+            geojson::Value::Point(position) if !feature.contains_property("text") => {
+                let name = feature.property("name").and_then(|name| name.as_str()).map(str::to_string);
+                stops.push((position.clone(), name));
+            }
+            geojson::Value::LineString(positions) if line.is_none() => line = Some(positions.clone()),
+            geojson::Value::MultiLineString(parts) if line.is_none() => line = Some(parts.concat()),
+            _ => {}
+        }
+    }
+
+    if stops.len() < 2 {
+        let Some(positions) = line.as_ref().filter(|positions| positions.len() >= 2) else {
+            return Err(ParseError::TooFewWaypoints);
+        };
+        stops = vec![(positions[0].clone(), None), (positions[positions.len() - 1].clone(), None)];
+    }
+
+    let waypoints = stops
+        .into_iter()
+        .enumerate()
+        .map(|(index, (position, name))| {
+            let invalid = |reason: String| ParseError::InvalidWaypoint(index, reason);
+            let [lng, lat, ..] = position[..] else {
+                return Err(invalid("position needs a longitude and a latitude".to_string()));
+            };
+            let waypoint = Waypoint {
+                lat,
+                lng,
+                name: Some(name.unwrap_or_else(|| format!("Point {}", index + 1))),
+                kind: WaypointKind::Stop,
+            };
+            waypoint.validate().map_err(invalid)?;
+            Ok(waypoint)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let positions = line.unwrap_or_else(|| waypoints.iter().map(|wp| vec![wp.lng, wp.lat]).collect());
+    let geometry = geojson::Geometry::new(geojson::Value::LineString(positions));
+    let geometry = serde_json::to_string(&geometry).map_err(|e| ParseError::InvalidGeoJson(e.to_string()))?;
+
+    Ok((waypoints, geometry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waypoint(lat: f64, lng: f64) -> Waypoint {
+        Waypoint { lat, lng, name: None, kind: WaypointKind::Stop }
+    }
+
+    #[test]
+    fn test_route_url_round_trip() {
+        let waypoints = vec![waypoint(51.5074, -0.1278), waypoint(51.4994, -0.1245), waypoint(-33.868820, 151.209296)];
+        let url = encode_route_url(&waypoints, "cycling");
+        assert_eq!(url, "map-rs://route?wp=51.507400,-0.127800;51.499400,-0.124500;-33.868820,151.209296&profile=cycling");
+
+        let (decoded, profile) = decode_route_url(&url).unwrap();
+        assert_eq!(profile, "cycling");
+        let points: Vec<(f64, f64)> = decoded.iter().map(|wp| (wp.lat, wp.lng)).collect();
+        assert_eq!(points, vec![(51.5074, -0.1278), (51.4994, -0.1245), (-33.86882, 151.209296)]);
+
+        // Via points keep their kind; links without via parameters are all stops
+        assert!(decoded.iter().all(|wp| wp.kind == WaypointKind::Stop));
+        let mut waypoints = waypoints;
+        waypoints[1].kind = WaypointKind::Via;
+        let url = encode_route_url(&waypoints, "driving");
+        assert_eq!(url, "map-rs://route?wp=51.507400,-0.127800;51.499400,-0.124500;-33.868820,151.209296&profile=driving&via=1");
+        let (decoded, _) = decode_route_url(&url).unwrap();
+        let kinds: Vec<WaypointKind> = decoded.iter().map(|wp| wp.kind).collect();
+        assert_eq!(kinds, vec![WaypointKind::Stop, WaypointKind::Via, WaypointKind::Stop]);
+
+        // The bare query string works too, and the profile is optional
+        let (decoded, profile) = decode_route_url("?wp=1,2;3,4").unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(profile, "driving");
+    }
+
+    #[test]
+    fn test_route_url_errors() {
+        assert_eq!(decode_route_url("map-rs://route?profile=walking").unwrap_err(), ParseError::MissingWaypoints);
+        assert_eq!(decode_route_url("?wp=1,2").unwrap_err(), ParseError::TooFewWaypoints);
+        assert!(matches!(decode_route_url("?wp=1,2;95,0"), Err(ParseError::InvalidWaypoint(1, _))));
+        assert!(matches!(decode_route_url("?wp=1,2;abc"), Err(ParseError::InvalidWaypoint(1, _))));
+        assert_eq!(decode_route_url("?wp=1,2;3,4&via=2").unwrap_err(), ParseError::InvalidVia("2".to_string()));
+        assert_eq!(decode_route_url("?wp=1,2;3,4&via=x").unwrap_err(), ParseError::InvalidVia("x".to_string()));
+        assert_eq!(
+            decode_route_url("?wp=1,2;3,4&profile=flying").unwrap_err(),
+            ParseError::UnknownProfile("flying".to_string())
+        );
+    }
+
+    #[test]
+    fn test_view_url_round_trip() {
+        let view = MapView { lat: 51.5074, lng: -0.1278, zoom: Some(13) };
+        let url = encode_view_url(&view);
+        assert_eq!(url, "geo:51.507400,-0.127800?z=13");
+        assert_eq!(decode_view_url(&url), Ok(view));
+
+        let view = MapView { lat: -33.86882, lng: 151.209296, zoom: None };
+        assert_eq!(encode_view_url(&view), "geo:-33.868820,151.209296");
+        assert_eq!(decode_view_url(&encode_view_url(&view)), Ok(view));
+
+        // Altitude, uncertainty and other parameters are skipped
+        assert_eq!(
+            decode_view_url("GEO:48.2010,16.3695,183;u=40?q=Wien&z=7"),
+            Ok(MapView { lat: 48.201, lng: 16.3695, zoom: Some(7) })
+        );
+    }
+
+    #[test]
+    fn test_view_url_errors() {
+        assert!(matches!(decode_view_url("map-rs://route?wp=1,2;3,4"), Err(ParseError::InvalidGeoUri(_))));
+        assert!(matches!(decode_view_url("geo:51.5"), Err(ParseError::InvalidGeoUri(_))));
+        assert!(matches!(decode_view_url("geo:95,0"), Err(ParseError::InvalidGeoUri(_))));
+        assert!(matches!(decode_view_url("geo:51.5,0?z=25"), Err(ParseError::InvalidGeoUri(_))));
+        assert!(matches!(decode_view_url("geo:51.5,0?z=far"), Err(ParseError::InvalidGeoUri(_))));
+    }
+
+    #[test]
+    fn test_load_route_geojson() {
+        let json = r#"{
+            "type": "FeatureCollection",
+            "features": [
+                { "type": "Feature", "properties": {}, "geometry": { "type": "LineString", "coordinates": [[-0.1278, 51.5074], [-0.126, 51.503], [-0.1245, 51.4994]] } },
+                { "type": "Feature", "properties": { "name": "Start" }, "geometry": { "type": "Point", "coordinates": [-0.1278, 51.5074] } },
+                { "type": "Feature", "properties": { "text": "Turn left" }, "geometry": { "type": "Point", "coordinates": [-0.126, 51.503] } },
+                { "type": "Feature", "properties": {}, "geometry": { "type": "Point", "coordinates": [-0.1245, 51.4994] } }
+            ]
+        }"#;
+        let (waypoints, geometry) = load_route_geojson(json).unwrap();
+        let stops: Vec<(f64, f64, Option<&str>)> = waypoints.iter().map(|wp| (wp.lat, wp.lng, wp.name.as_deref())).collect();
+        assert_eq!(stops, vec![(51.5074, -0.1278, Some("Start")), (51.4994, -0.1245, Some("Point 2"))]);
+        let geometry: serde_json::Value = serde_json::from_str(&geometry).unwrap();
+        assert_eq!(geometry["coordinates"].as_array().unwrap().len(), 3);
+
+        // A bare line is routed between its endpoints
+        let (waypoints, _) = load_route_geojson(r#"{"type":"LineString","coordinates":[[13.4,52.5],[13.41,52.51],[13.45,52.52]]}"#).unwrap();
+        let stops: Vec<(f64, f64)> = waypoints.iter().map(|wp| (wp.lat, wp.lng)).collect();
+        assert_eq!(stops, vec![(52.5, 13.4), (52.52, 13.45)]);
+    }
+
+    #[test]
+    fn test_load_route_geojson_errors() {
+        assert!(matches!(load_route_geojson("not json"), Err(ParseError::InvalidGeoJson(_))));
+        assert_eq!(
+            load_route_geojson(r#"{"type":"Point","coordinates":[13.4,52.5]}"#).unwrap_err(),
+            ParseError::TooFewWaypoints
+        );
+        assert!(matches!(
+            load_route_geojson(r#"{"type":"LineString","coordinates":[[13.4,52.5],[200.0,52.5]]}"#),
+            Err(ParseError::InvalidWaypoint(1, _))
+        ));
+    }
+}
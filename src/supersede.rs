@@ -0,0 +1,100 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+// Hands out tickets for one kind of request (e.g. searches). Beginning a new
+// request supersedes every earlier ticket, so stale results can be dropped.
+#[derive(Clone, Default)]
+pub struct RequestGeneration {
+    latest: Arc<AtomicU64>,
+    changed: Arc<Notify>,
+}
+
+pub struct RequestTicket {
+    id: u64,
+    latest: Arc<AtomicU64>,
+    changed: Arc<Notify>,
+}
+
+impl RequestGeneration {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn begin(&self) -> RequestTicket {
+        let id = self.latest.fetch_add(1, Ordering::SeqCst) + 1;
+        self.changed.notify_waiters();
+        RequestTicket {
+            id,
+            latest: self.latest.clone(),
+            changed: self.changed.clone(),
+        }
+    }
+}
+
+impl RequestTicket {
+    pub fn is_current(&self) -> bool {
+        self.latest.load(Ordering::SeqCst) == self.id
+    }
+
+    // Resolves once a newer request has begun
+    pub async fn superseded(&self) {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            if !self.is_current() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    // Drives the future until it completes or is superseded. A superseded future is
+    // dropped at its current await point, aborting any request it was waiting on.
+    pub async fn run<F: Future>(&self, future: F) -> Option<F::Output> {
+        if !self.is_current() {
+            return None;
+        }
+
+        tokio::select! {
+            output = future => self.is_current().then_some(output),
+            _ = self.superseded() => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_latest_request_wins() {
+        let generation = RequestGeneration::new();
+        let first = generation.begin();
+        let second = generation.begin();
+
+        assert!(!first.is_current());
+        assert!(second.is_current());
+        assert_eq!(first.run(async { "stale" }).await, None);
+        assert_eq!(second.run(async { "fresh" }).await, Some("fresh"));
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_request_is_cancelled() {
+        let generation = RequestGeneration::new();
+        let first = generation.begin();
+
+        let slow = tokio::spawn(async move {
+            first.run(tokio::time::sleep(Duration::from_secs(30))).await
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        generation.begin();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), slow).await;
+        assert_eq!(result.expect("superseded request should stop early").unwrap(), None);
+    }
+}
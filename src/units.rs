@@ -0,0 +1,170 @@
+// Unit conversions used when displaying distances and speeds
+
+const METERS_PER_MILE: f64 = 1609.344;
+const METERS_PER_KM: f64 = 1000.0;
+const MPS_PER_KMH: f64 = 1.0 / 3.6;
+const MPS_PER_MPH: f64 = 0.44704;
+const METERS_PER_FOOT: f64 = 0.3048;
+const METERS_PER_YARD: f64 = 0.9144;
+
+// Imperial distances shorter than this read better in feet or yards than as "0.0 mi"
+pub const SHORT_DISTANCE_MILES: f64 = 0.1;
+
+// Unit for imperial distances under SHORT_DISTANCE_MILES
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShortImperialUnit {
+    #[default]
+    Feet,
+    Yards,
+}
+
+impl ShortImperialUnit {
+    pub fn from_meters(self, m: f64) -> f64 {
+        match self {
+            ShortImperialUnit::Feet => meters_to_feet(m),
+            ShortImperialUnit::Yards => meters_to_yards(m),
+        }
+    }
+
+    pub fn to_meters(self, value: f64) -> f64 {
+        match self {
+            ShortImperialUnit::Feet => value * METERS_PER_FOOT,
+            ShortImperialUnit::Yards => value * METERS_PER_YARD,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            ShortImperialUnit::Feet => "ft",
+            ShortImperialUnit::Yards => "yd",
+        }
+    }
+}
+
+// Web Mercator ground resolution: the equator's length spread over one 256 px tile at zoom 0
+const EQUATOR_LENGTH_M: f64 = 2.0 * std::f64::consts::PI * 6_378_137.0;
+const TILE_SIZE_PX: f64 = 256.0;
+
+pub fn meters_to_miles(m: f64) -> f64 {
+    m / METERS_PER_MILE
+}
+
+pub fn meters_to_km(m: f64) -> f64 {
+    m / METERS_PER_KM
+}
+
+pub fn meters_to_feet(m: f64) -> f64 {
+    m / METERS_PER_FOOT
+}
+
+pub fn meters_to_yards(m: f64) -> f64 {
+    m / METERS_PER_YARD
+}
+
+// Whether an imperial distance should be shown in feet or yards rather than miles
+pub fn is_short_imperial(m: f64) -> bool {
+    meters_to_miles(m) < SHORT_DISTANCE_MILES
+}
+
+pub fn mps_to_kmh(mps: f64) -> f64 {
+    mps / MPS_PER_KMH
+}
+
+pub fn mps_to_mph(mps: f64) -> f64 {
+    mps / MPS_PER_MPH
+}
+
+pub fn kmh_to_mps(kmh: f64) -> f64 {
+    kmh * MPS_PER_KMH
+}
+
+pub fn mph_to_mps(mph: f64) -> f64 {
+    mph * MPS_PER_MPH
+}
+
+// Meters covered by one screen pixel at the given latitude and (possibly fractional) zoom.
+// Mercator stretches the map by 1/cos(latitude), so a pixel covers less ground away from the equator.
+pub fn meters_per_pixel(latitude: f64, zoom: f64) -> f64 {
+    EQUATOR_LENGTH_M * latitude.to_radians().cos() / (TILE_SIZE_PX * zoom.exp2())
+}
+
+// The longest round length (1, 2 or 5 times a power of ten) that fits in `max_px`, in km or m
+// (mi or ft with `use_miles`). Returns its width in pixels and its label, e.g. (80.5, "200 m").
+pub fn scale_bar(meters_per_pixel: f64, max_px: f64, use_miles: bool) -> Option<(f64, String)> {
+    if !(meters_per_pixel.is_finite() && meters_per_pixel > 0.0 && max_px > 0.0) {
+        return None;
+    }
+    
+    let max_m = meters_per_pixel * max_px;
+    let (unit_m, unit) = match (use_miles, max_m) {
+        (true, m) if m >= METERS_PER_MILE => (METERS_PER_MILE, "mi"),
+        (true, _) => (METERS_PER_FOOT, "ft"),
+        (false, m) if m >= METERS_PER_KM => (METERS_PER_KM, "km"),
+        (false, _) => (1.0, "m"),
+    };
+    
+    let max_value = max_m / unit_m;
+    let magnitude = 10f64.powf(max_value.log10().floor());
+    let value = match max_value / magnitude {
+        d if d >= 5.0 => 5.0 * magnitude,
+        d if d >= 2.0 => 2.0 * magnitude,
+        _ => magnitude,
+    };
+    Some((value * unit_m / meters_per_pixel, format!("{} {}", value, unit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_conversion_factors() {
+        assert_close(meters_to_miles(1609.344), 1.0);
+        assert_close(meters_to_miles(1.0), 0.000621371192);
+        assert_close(meters_to_km(2500.0), 2.5);
+        assert_close(mps_to_kmh(10.0), 36.0);
+        assert_close(mps_to_mph(0.44704), 1.0);
+        assert_close(kmh_to_mps(mps_to_kmh(13.9)), 13.9);
+        assert_close(mph_to_mps(30.0), 13.4112);
+        assert_close(meters_to_feet(0.3048), 1.0);
+        assert_close(meters_to_yards(1609.344), 1760.0);
+        assert_close(ShortImperialUnit::Yards.from_meters(9.144), 10.0);
+    }
+
+    #[test]
+    fn test_short_imperial_boundary() {
+        assert!(is_short_imperial(0.05 * METERS_PER_MILE));
+        assert!(is_short_imperial(0.0999 * METERS_PER_MILE));
+        assert!(!is_short_imperial(0.1 * METERS_PER_MILE));
+        assert!(!is_short_imperial(2.0 * METERS_PER_MILE));
+    }
+
+    #[test]
+    fn test_meters_per_pixel() {
+        assert!((meters_per_pixel(0.0, 0.0) - 156_543.034).abs() < 0.001);
+        assert_close(meters_per_pixel(0.0, 1.0), meters_per_pixel(0.0, 0.0) / 2.0);
+        assert_close(meters_per_pixel(60.0, 10.0), meters_per_pixel(0.0, 10.0) / 2.0);
+        assert_close(meters_per_pixel(-60.0, 10.0), meters_per_pixel(60.0, 10.0));
+    }
+
+    #[test]
+    fn test_scale_bar() {
+        assert_eq!(scale_bar(1.0, 100.0, false), Some((100.0, "100 m".to_string())));
+        let (width, label) = scale_bar(3.0, 100.0, false).unwrap();
+        assert_eq!(label, "200 m");
+        assert_close(width, 200.0 / 3.0);
+        assert_eq!(scale_bar(60.0, 100.0, false), Some((83.33333333333333, "5 km".to_string())));
+        
+        let (width, label) = scale_bar(10.0, 100.0, true).unwrap();
+        assert_eq!(label, "2000 ft");
+        assert_close(width, 60.96);
+        assert_eq!(scale_bar(40.0, 100.0, true).map(|(_, label)| label), Some("2 mi".to_string()));
+        
+        assert_eq!(scale_bar(0.0, 100.0, false), None);
+        assert_eq!(scale_bar(f64::NAN, 100.0, true), None);
+    }
+}